@@ -0,0 +1,159 @@
+// Camera rays traced through the flat BVH, the SAH built BvhNode tree and
+// the median split it replaced, on the geometry of `final_scene` and on a
+// large triangle mesh.
+//
+//   cargo bench --bench bvh
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use shrimpray::material::Lambertian;
+use shrimpray::obj::*;
+use shrimpray::texture::ConstantTexture;
+use shrimpray::{rand_float, seed_rng, BvhNode, Camera, FlatBvh, Hittable, Ray, Vec3};
+
+const RAYS: usize = 10_000;
+
+fn grey() -> Arc<Lambertian> {
+    Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
+        0.5, 0.5, 0.5,
+    )))))
+}
+
+// The ground boxes, spheres and box of spheres from `final_scene` in one
+// list, leaving out the participating media
+fn final_scene_objects() -> Vec<Arc<dyn Hittable>> {
+    let mut list: Vec<Arc<dyn Hittable>> = Vec::new();
+    for i in 0..20 {
+        for j in 0..20 {
+            let p0 = Vec3::new(-1000.0 + i as f32 * 100.0, 0.0, -1000.0 + j as f32 * 100.0);
+            let p1 = p0 + Vec3::new(100.0, rand_float() * 100.0 + 1.0, 100.0);
+            list.push(Arc::new(BoxShape::new(p0, p1, grey())));
+        }
+    }
+    for (center, radius) in [
+        (Vec3::new(400.0, 400.0, 200.0), 50.0),
+        (Vec3::new(260.0, 150.0, 45.0), 50.0),
+        (Vec3::new(0.0, 150.0, 145.0), 50.0),
+        (Vec3::new(360.0, 150.0, 145.0), 70.0),
+        (Vec3::new(400.0, 200.0, 400.0), 100.0),
+        (Vec3::new(220.0, 280.0, 300.0), 80.0),
+    ]
+    .iter()
+    {
+        list.push(Arc::new(Sphere::new(*center, *radius, grey())));
+    }
+    for _ in 0..1000 {
+        let offset = Vec3::new(rand_float(), rand_float(), rand_float()) * 165.0;
+        list.push(Arc::new(Sphere::new(
+            Vec3::new(-100.0, 270.0, 395.0) + offset,
+            10.0,
+            grey(),
+        )));
+    }
+    list
+}
+
+// Rolling hills over a 256 by 256 grid, about 131 thousand triangles
+fn terrain_triangles() -> Vec<Arc<dyn Hittable>> {
+    let n = 256;
+    let mut positions = Vec::new();
+    for i in 0..=n {
+        for j in 0..=n {
+            let (x, z) = (i as f32 / n as f32, j as f32 / n as f32);
+            let y = 0.1 * (9.0 * x).sin() * (7.0 * z).cos() + 0.03 * (40.0 * x * z).sin();
+            positions.push(Vec3::new(x, y, z));
+        }
+    }
+    let mut indices = Vec::new();
+    for i in 0..n {
+        for j in 0..n {
+            let a = i * (n + 1) + j;
+            let b = a + n + 1;
+            indices.push([a, b, a + 1]);
+            indices.push([a + 1, b, b + 1]);
+        }
+    }
+    let mesh = Arc::new(MeshData {
+        positions,
+        normals: Vec::new(),
+        uvs: Vec::new(),
+        colors: Vec::new(),
+        indices,
+        motion: Vec::new(),
+    });
+    let mat = grey();
+    (0..mesh.indices.len())
+        .map(|face| {
+            Arc::new(Triangle::from_mesh(mesh.clone(), face, mat.clone())) as Arc<dyn Hittable>
+        })
+        .collect()
+}
+
+fn camera_rays(camera: &Camera) -> Vec<Ray> {
+    (0..RAYS)
+        .map(|_| camera.get_ray(rand_float(), rand_float()))
+        .collect()
+}
+
+fn trace(tree: &dyn Hittable, rays: &[Ray]) -> usize {
+    rays.iter()
+        .filter(|r| tree.hit(**r, 0.001, f32::MAX).is_some())
+        .count()
+}
+
+fn traversal(c: &mut Criterion) {
+    seed_rng(1);
+    let final_camera = Camera::new(
+        Vec3::new(478.0, 278.0, -600.0),
+        Vec3::new(278.0, 278.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        40.0,
+        1.0,
+        0.0,
+        10.0,
+        0.0,
+        1.0,
+    );
+    let terrain_camera = Camera::new(
+        Vec3::new(-0.3, 0.6, -0.3),
+        Vec3::new(0.6, 0.0, 0.6),
+        Vec3::new(0.0, 1.0, 0.0),
+        50.0,
+        1.0,
+        0.0,
+        1.0,
+        0.0,
+        1.0,
+    );
+    let cases = [
+        (
+            "final_scene",
+            final_scene_objects(),
+            camera_rays(&final_camera),
+        ),
+        ("terrain", terrain_triangles(), camera_rays(&terrain_camera)),
+    ];
+
+    let mut group = c.benchmark_group("bvh traversal");
+    group.sample_size(20);
+    for (name, list, rays) in cases.iter() {
+        let sah = BvhNode::new(&mut list.clone(), 0.0, 1.0);
+        let median = BvhNode::median_split(&mut list.clone(), 0.0, 1.0);
+        let flat = FlatBvh::new(list, 0.0, 1.0);
+        group.bench_with_input(BenchmarkId::new("flat", name), rays, |b, rays| {
+            b.iter(|| trace(&flat, rays))
+        });
+        group.bench_with_input(BenchmarkId::new("sah", name), rays, |b, rays| {
+            b.iter(|| trace(&sah, rays))
+        });
+        group.bench_with_input(BenchmarkId::new("median", name), rays, |b, rays| {
+            b.iter(|| trace(&median, rays))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, traversal);
+criterion_main!(benches);
@@ -4,6 +4,7 @@ use crate::hit::Hittable;
 use crate::util::*;
 use crate::vec3::{Ray, Vec3};
 
+#[derive(Clone, Copy)]
 pub struct AABB {
     _min: Vec3,
     _max: Vec3,
@@ -22,6 +23,24 @@ impl AABB {
     pub fn max(&self) -> Vec3 {
         self._max
     }
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max() - self.min();
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+    // Slab test with the reciprocal of the ray direction worked out once
+    // for a whole traversal
+    pub fn hit_inv(&self, origin: &Vec3, inv_dir: &Vec3, mut t_min: f32, mut t_max: f32) -> bool {
+        for a in 0..3 {
+            let t0 = (self.min()[a] - origin[a]) * inv_dir[a];
+            let t1 = (self.max()[a] - origin[a]) * inv_dir[a];
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+            if t_max <= t_min {
+                return false;
+            }
+        }
+        true
+    }
     pub fn hit(&self, r: &Ray, mut t_min: f32, mut t_max: f32) -> bool {
         for a in 0..3 {
             let inv_d = 1.0 / r.direction()[a];
@@ -60,8 +79,37 @@ pub struct BvhNode {
     pub bbox: AABB,
 }
 
+// Splits are chosen among this many bins of primitive centroids per axis
+const SAH_BINS: usize = 12;
+// Cost of visiting a node relative to intersecting one primitive
+const TRAVERSAL_COST: f32 = 0.125;
+pub const DEFAULT_LEAF_SIZE: usize = 4;
+
 impl BvhNode {
+    // Built with the surface area heuristic, which gives the same tree for
+    // the same list
     pub fn new(list: &mut [Arc<dyn Hittable>], time0: f32, time1: f32) -> Self {
+        Self::with_leaf_size(list, time0, time1, DEFAULT_LEAF_SIZE)
+    }
+    // Leaves hold up to `max_leaf_size` primitives when that is cheaper
+    // than splitting them further
+    pub fn with_leaf_size(
+        list: &mut [Arc<dyn Hittable>],
+        time0: f32,
+        time1: f32,
+        max_leaf_size: usize,
+    ) -> Self {
+        assert!(!list.is_empty(), "a BvhNode needs at least one object");
+        let boxes: Vec<AABB> = list
+            .iter()
+            .map(|obj| obj.bounding_box(time0, time1).expect("no bounding box"))
+            .collect();
+        let mut items: Vec<usize> = (0..list.len()).collect();
+        sah_node(list, &boxes, &mut items, max_leaf_size.max(1))
+    }
+    // The original builder, sorts along a random axis and splits at the
+    // median. Kept to compare the SAH builder against.
+    pub fn median_split(list: &mut [Arc<dyn Hittable>], time0: f32, time1: f32) -> Self {
         let axis = (3.0 * rand_float()) as u32;
         match axis {
             0 => {
@@ -90,8 +138,8 @@ impl BvhNode {
                 right = list[1].clone();
             }
             _ => {
-                left = Arc::new(BvhNode::new(&mut list[0..len / 2], time0, time1));
-                right = Arc::new(BvhNode::new(&mut list[len / 2..], time0, time1));
+                left = Arc::new(BvhNode::median_split(&mut list[0..len / 2], time0, time1));
+                right = Arc::new(BvhNode::median_split(&mut list[len / 2..], time0, time1));
             }
         }
 
@@ -106,6 +154,229 @@ impl BvhNode {
     }
 }
 
+fn bounds<I: Iterator<Item = AABB>>(mut boxes: I) -> AABB {
+    let first = boxes.next().unwrap();
+    boxes.fold(first, surrounding_bbox)
+}
+
+fn centroid(bbox: &AABB) -> Vec3 {
+    0.5 * (bbox.min() + bbox.max())
+}
+
+fn sah_node(
+    list: &[Arc<dyn Hittable>],
+    boxes: &[AABB],
+    items: &mut [usize],
+    max_leaf_size: usize,
+) -> BvhNode {
+    let bbox = bounds(items.iter().map(|i| boxes[*i]));
+    match sah_split(boxes, items, &bbox, max_leaf_size) {
+        Some((mid, _)) => {
+            let (l, r) = items.split_at_mut(mid);
+            BvhNode {
+                left: Some(sah_child(list, boxes, l, max_leaf_size)),
+                right: Some(sah_child(list, boxes, r, max_leaf_size)),
+                bbox,
+            }
+        }
+        None => {
+            let leaf: Arc<dyn Hittable> = match items {
+                [one] => list[*one].clone(),
+                _ => Arc::new(items.iter().map(|i| list[*i].clone()).collect::<Vec<_>>()),
+            };
+            BvhNode {
+                left: Some(leaf),
+                right: None,
+                bbox,
+            }
+        }
+    }
+}
+
+// Single primitives go in directly rather than in a node of their own
+fn sah_child(
+    list: &[Arc<dyn Hittable>],
+    boxes: &[AABB],
+    items: &mut [usize],
+    max_leaf_size: usize,
+) -> Arc<dyn Hittable> {
+    match items {
+        [one] => list[*one].clone(),
+        _ => Arc::new(sah_node(list, boxes, items, max_leaf_size)),
+    }
+}
+
+// Partitions `items` and returns where the right half starts and the axis
+// it was split along, or None if they are cheaper to keep in one leaf
+fn sah_split(
+    boxes: &[AABB],
+    items: &mut [usize],
+    bbox: &AABB,
+    max_leaf_size: usize,
+) -> Option<(usize, u32)> {
+    let n = items.len();
+    if n <= 1 {
+        return None;
+    }
+    let centroids = bounds(items.iter().map(|i| {
+        let c = centroid(&boxes[*i]);
+        AABB::new(c, c)
+    }));
+    let (cmin, extent) = (centroids.min(), centroids.max() - centroids.min());
+    let bin = |i: usize, axis: u32| {
+        let b = (centroid(&boxes[i])[axis] - cmin[axis]) / extent[axis] * SAH_BINS as f32;
+        (b as usize).min(SAH_BINS - 1)
+    };
+    // Relative to the parent, which a flat set of primitives can make zero
+    let parent_area = bbox.surface_area().max(f32::MIN_POSITIVE);
+
+    // Cost, axis and last bin on the left of the best split
+    let mut best: Option<(f32, u32, usize)> = None;
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        let mut counts = [0; SAH_BINS];
+        let mut bin_boxes: [Option<AABB>; SAH_BINS] = [None; SAH_BINS];
+        for i in items.iter() {
+            let b = bin(*i, axis);
+            counts[b] += 1;
+            bin_boxes[b] = Some(match bin_boxes[b] {
+                Some(bb) => surrounding_bbox(bb, boxes[*i]),
+                None => boxes[*i],
+            });
+        }
+        // Sweep from the right for the cost of everything past each bin
+        let mut right = [(0, 0.0); SAH_BINS];
+        let mut acc: (usize, Option<AABB>) = (0, None);
+        for b in (1..SAH_BINS).rev() {
+            acc = grow(acc, counts[b], bin_boxes[b]);
+            right[b - 1] = (acc.0, acc.1.map_or(0.0, |bb| bb.surface_area()));
+        }
+        let mut left: (usize, Option<AABB>) = (0, None);
+        for b in 0..SAH_BINS - 1 {
+            left = grow(left, counts[b], bin_boxes[b]);
+            let (right_count, right_area) = right[b];
+            if left.0 == 0 || right_count == 0 {
+                continue;
+            }
+            let left_area = left.1.map_or(0.0, |bb| bb.surface_area());
+            let cost = TRAVERSAL_COST
+                + (left_area * left.0 as f32 + right_area * right_count as f32) / parent_area;
+            if best.is_none_or(|(c, _, _)| cost < c) {
+                best = Some((cost, axis, b));
+            }
+        }
+    }
+
+    match best {
+        Some((cost, _, _)) if cost >= n as f32 && n <= max_leaf_size => None,
+        Some((_, axis, last)) => {
+            // Stable, so the same input always gives the same tree
+            let (mut l, r): (Vec<usize>, Vec<usize>) =
+                items.iter().partition(|i| bin(**i, axis) <= last);
+            let mid = l.len();
+            l.extend(r);
+            items.copy_from_slice(&l);
+            Some((mid, axis))
+        }
+        // Every centroid is in the same place, so no split helps
+        None if n <= max_leaf_size => None,
+        None => Some((n / 2, 0)),
+    }
+}
+
+// A SAH tree stored as one array of nodes in depth first order, with the
+// primitives reordered so that every leaf is a range of them. Cheaper to
+// walk than the pointer tree of BvhNode and visits the nearer child first.
+pub struct FlatBvh {
+    pub nodes: Vec<FlatNode>,
+    pub primitives: Vec<Arc<dyn Hittable>>,
+}
+
+pub struct FlatNode {
+    pub bbox: AABB,
+    // First primitive for a leaf, index of the second child otherwise. The
+    // first child always comes right after its parent.
+    pub offset: u32,
+    // Zero for an interior node
+    pub count: u32,
+    // Axis an interior node was split along
+    pub axis: u32,
+}
+
+// Past this depth nodes are split in half by count, which keeps the
+// traversal stack within FLAT_BVH_STACK
+const BALANCED_DEPTH: usize = 32;
+pub const FLAT_BVH_STACK: usize = 64;
+
+impl FlatBvh {
+    pub fn new(list: &[Arc<dyn Hittable>], time0: f32, time1: f32) -> Self {
+        Self::with_leaf_size(list, time0, time1, DEFAULT_LEAF_SIZE)
+    }
+    pub fn with_leaf_size(
+        list: &[Arc<dyn Hittable>],
+        time0: f32,
+        time1: f32,
+        max_leaf_size: usize,
+    ) -> Self {
+        assert!(!list.is_empty(), "a FlatBvh needs at least one object");
+        let boxes: Vec<AABB> = list
+            .iter()
+            .map(|obj| obj.bounding_box(time0, time1).expect("no bounding box"))
+            .collect();
+        let mut items: Vec<usize> = (0..list.len()).collect();
+        let mut nodes = Vec::with_capacity(2 * list.len());
+        flatten(&boxes, &mut items, 0, 0, max_leaf_size.max(1), &mut nodes);
+        Self {
+            nodes,
+            primitives: items.iter().map(|i| list[*i].clone()).collect(),
+        }
+    }
+}
+
+fn flatten(
+    boxes: &[AABB],
+    items: &mut [usize],
+    first: usize,
+    depth: usize,
+    max_leaf_size: usize,
+    nodes: &mut Vec<FlatNode>,
+) {
+    let bbox = bounds(items.iter().map(|i| boxes[*i]));
+    let split = if depth < BALANCED_DEPTH {
+        sah_split(boxes, items, &bbox, max_leaf_size)
+    } else if items.len() > 1 {
+        Some((items.len() / 2, 0))
+    } else {
+        None
+    };
+    let index = nodes.len();
+    nodes.push(FlatNode {
+        bbox,
+        offset: first as u32,
+        count: items.len() as u32,
+        axis: 0,
+    });
+    if let Some((mid, axis)) = split {
+        let (l, r) = items.split_at_mut(mid);
+        flatten(boxes, l, first, depth + 1, max_leaf_size, nodes);
+        let second = nodes.len() as u32;
+        flatten(boxes, r, first + mid, depth + 1, max_leaf_size, nodes);
+        nodes[index].offset = second;
+        nodes[index].count = 0;
+        nodes[index].axis = axis;
+    }
+}
+
+fn grow(acc: (usize, Option<AABB>), count: usize, bbox: Option<AABB>) -> (usize, Option<AABB>) {
+    let bbox = match (acc.1, bbox) {
+        (Some(a), Some(b)) => Some(surrounding_bbox(a, b)),
+        (a, b) => a.or(b),
+    };
+    (acc.0 + count, bbox)
+}
+
 pub fn box_compare_x(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>) -> std::cmp::Ordering {
     match (a.bounding_box(0.0, 0.0), b.bounding_box(0.0, 0.0)) {
         (Some(lbox), Some(rbox)) => lbox.min().x().partial_cmp(&rbox.min().x()).unwrap(),
@@ -125,3 +396,47 @@ pub fn box_compare_z(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>) -> std::cmp::
         (_, _) => panic!("Missing bounding box in a BvhNode contructor"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::obj::Sphere;
+    use crate::texture::ConstantTexture;
+
+    #[test]
+    fn trees_find_the_closest_hit() {
+        seed_rng(3);
+        let mat = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
+            0.5, 0.5, 0.5,
+        )))));
+        let random_point = || Vec3::new(rand_float(), rand_float(), rand_float()) * 10.0;
+        let list: Vec<Arc<dyn Hittable>> = (0..500)
+            .map(|_| {
+                Arc::new(Sphere::new(
+                    random_point(),
+                    0.1 + 0.3 * rand_float(),
+                    mat.clone(),
+                )) as Arc<dyn Hittable>
+            })
+            .collect();
+        let sah = BvhNode::new(&mut list.clone(), 0.0, 1.0);
+        let median = BvhNode::median_split(&mut list.clone(), 0.0, 1.0);
+        let wide = BvhNode::with_leaf_size(&mut list.clone(), 0.0, 1.0, 16);
+        let flat = FlatBvh::new(&list, 0.0, 1.0);
+        let flat_wide = FlatBvh::with_leaf_size(&list, 0.0, 1.0, 16);
+        let trees: [&dyn Hittable; 5] = [&sah, &median, &wide, &flat, &flat_wide];
+
+        for _ in 0..1000 {
+            let r = Ray::new(
+                random_point(),
+                random_point() - Vec3::new(5.0, 5.0, 5.0),
+                0.0,
+            );
+            let expected = list.hit(r, 0.001, f32::MAX).map(|hit| hit.t);
+            for tree in trees.iter() {
+                assert_eq!(tree.hit(r, 0.001, f32::MAX).map(|hit| hit.t), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use shrimpray::film::Film;
+use shrimpray::output::save_atomically;
+
+const MAGIC: &[u8] = b"shrimpray checkpoint\n";
+
+// Identifies the render a checkpoint belongs to. The random number state is
+// fully described by `seed` and the sample counts in the film, since each
+// sample draws from a stream picked by its pixel and sample index.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Header {
+    // Built-in scene name or scene file path, for error messages
+    pub scene: String,
+    // Hash of the scene file contents, empty for built-in scenes
+    pub scene_hash: String,
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub max_depth: u32,
+    pub seed: u32,
+    // Whether the film also holds albedo, normal and depth
+    pub aovs: bool,
+    // Older checkpoints were all rendered in RGB
+    #[serde(default)]
+    pub spectral: bool,
+    // Sensor curves file, its hash and its number of receptors, empty
+    // without a sensor
+    #[serde(default)]
+    pub sensor: String,
+    #[serde(default)]
+    pub sensor_hash: String,
+    #[serde(default)]
+    pub receptors: usize,
+    #[serde(default)]
+    pub polarized: bool,
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Corrupt(String),
+    SceneChanged(String),
+    Mismatch {
+        what: &'static str,
+        checkpoint: String,
+        current: String,
+    },
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "{}", e),
+            CheckpointError::Corrupt(message) => write!(f, "not a valid checkpoint: {}", message),
+            CheckpointError::SceneChanged(scene) => write!(
+                f,
+                "scene file {} has changed since the checkpoint was written",
+                scene
+            ),
+            CheckpointError::Mismatch {
+                what,
+                checkpoint,
+                current,
+            } => write!(
+                f,
+                "checkpoint {} is {} but this render has {}",
+                what, checkpoint, current
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+// 64-bit FNV-1a, stable across builds unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn file_hash(path: &Path) -> io::Result<String> {
+    Ok(format!("{:016x}", fnv1a(&std::fs::read(path)?)))
+}
+
+impl Header {
+    // Refuses to carry on from `self` if it was written for a different
+    // render than `current`
+    pub fn check(&self, current: &Header) -> Result<(), CheckpointError> {
+        let mismatch = |what, checkpoint: String, current: String| {
+            Err(CheckpointError::Mismatch {
+                what,
+                checkpoint,
+                current,
+            })
+        };
+        if self.scene_hash.is_empty() != current.scene_hash.is_empty()
+            || (self.scene_hash.is_empty() && self.scene != current.scene)
+        {
+            return mismatch(
+                "scene",
+                format!("`{}`", self.scene),
+                format!("`{}`", current.scene),
+            );
+        }
+        if self.scene_hash != current.scene_hash {
+            return Err(CheckpointError::SceneChanged(current.scene.clone()));
+        }
+        if (self.width, self.height) != (current.width, current.height) {
+            return mismatch(
+                "resolution",
+                format!("{}x{}", self.width, self.height),
+                format!("{}x{}", current.width, current.height),
+            );
+        }
+        if self.samples != current.samples {
+            return mismatch(
+                "samples per pixel",
+                self.samples.to_string(),
+                current.samples.to_string(),
+            );
+        }
+        if self.max_depth != current.max_depth {
+            return mismatch(
+                "depth",
+                self.max_depth.to_string(),
+                current.max_depth.to_string(),
+            );
+        }
+        if self.seed != current.seed {
+            return mismatch("seed", self.seed.to_string(), current.seed.to_string());
+        }
+        if self.spectral != current.spectral {
+            let mode = |spectral| if spectral { "spectral" } else { "RGB" }.to_string();
+            return mismatch("color mode", mode(self.spectral), mode(current.spectral));
+        }
+        if self.sensor_hash != current.sensor_hash {
+            let sensor = |h: &Header| {
+                if h.sensor.is_empty() {
+                    "none".to_string()
+                } else {
+                    format!("`{}` with hash {}", h.sensor, h.sensor_hash)
+                }
+            };
+            return mismatch("sensor", sensor(self), sensor(current));
+        }
+        if self.polarized != current.polarized {
+            let mode = |polarized| if polarized { "polarized" } else { "scalar" }.to_string();
+            return mismatch("light mode", mode(self.polarized), mode(current.polarized));
+        }
+        Ok(())
+    }
+}
+
+// The magic line, the length of the header, the header as TOML and then
+// the film buffers
+pub fn save(path: &Path, header: &Header, film: &Film) -> io::Result<()> {
+    let header = Header {
+        aovs: film.has_aovs(),
+        receptors: film.receptors(),
+        polarized: film.is_polarized(),
+        ..header.clone()
+    };
+    let toml = toml::to_string(&header).map_err(io::Error::other)?;
+    save_atomically(path, |w| {
+        w.write_all(MAGIC)?;
+        w.write_all(&(toml.len() as u32).to_le_bytes())?;
+        w.write_all(toml.as_bytes())?;
+        film.write(w)
+    })
+}
+
+pub fn load(path: &Path) -> Result<(Header, Film), CheckpointError> {
+    let mut r = BufReader::new(File::open(path)?);
+    let corrupt = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => CheckpointError::Corrupt("file is truncated".to_string()),
+        _ => CheckpointError::Io(e),
+    };
+
+    let mut magic = vec![0; MAGIC.len()];
+    r.read_exact(&mut magic).map_err(corrupt)?;
+    if magic != MAGIC {
+        return Err(CheckpointError::Corrupt("wrong file type".to_string()));
+    }
+    let mut len = [0; 4];
+    r.read_exact(&mut len).map_err(corrupt)?;
+    let mut toml = vec![0; u32::from_le_bytes(len) as usize];
+    r.read_exact(&mut toml).map_err(corrupt)?;
+    let header: Header = std::str::from_utf8(&toml)
+        .map_err(|e| e.to_string())
+        .and_then(|s| toml::from_str(s).map_err(|e| e.to_string()))
+        .map_err(CheckpointError::Corrupt)?;
+
+    let film = Film::read(
+        &mut r,
+        header.width,
+        header.height,
+        header.aovs,
+        header.receptors,
+        header.polarized,
+    )
+    .map_err(corrupt)?;
+    Ok((header, film))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shrimpray::Vec3;
+
+    fn header() -> Header {
+        Header {
+            scene: "cornell_mc".to_string(),
+            scene_hash: String::new(),
+            width: 3,
+            height: 2,
+            samples: 100,
+            max_depth: 50,
+            seed: 7,
+            aovs: true,
+            spectral: false,
+            sensor: String::new(),
+            sensor_hash: String::new(),
+            receptors: 0,
+            polarized: false,
+        }
+    }
+
+    #[test]
+    fn round_trip_and_mismatch() {
+        let mut film = Film::new(3, 2, true);
+        film.add_sample(2, 1, Vec3::new(1.0, 2.0, 3.0));
+        film.add_aovs(
+            2,
+            1,
+            Vec3::new(0.5, 0.5, 0.5),
+            Vec3::new(0.0, 1.0, 0.0),
+            4.0,
+        );
+        let path = std::env::temp_dir().join(format!("shrimpray-{}.ckpt", std::process::id()));
+        save(&path, &header(), &film).unwrap();
+
+        let (saved, loaded) = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved, header());
+        let image = loaded.image();
+        assert_eq!(image.spp, 0);
+        assert_eq!(image.pixels[5][2], 3.0);
+        assert_eq!(image.aovs.unwrap().depth[5], 4.0);
+
+        assert!(saved.check(&header()).is_ok());
+        let other_scene = Header {
+            scene: "final".to_string(),
+            ..header()
+        };
+        assert!(saved.check(&other_scene).is_err());
+        let other_size = Header {
+            width: 4,
+            ..header()
+        };
+        assert!(saved.check(&other_size).is_err());
+    }
+}
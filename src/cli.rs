@@ -0,0 +1,403 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, Error, ErrorKind};
+
+use shrimpray::output::{OutputFormat, Precision};
+use shrimpray::render::{Interval, TileOrder};
+use shrimpray::scene::*;
+
+pub enum SceneSource {
+    Builtin(&'static str, SceneFn),
+    File(PathBuf),
+}
+
+pub struct Settings {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    pub max_depth: u32,
+    pub scene: SceneSource,
+    pub output: Option<PathBuf>,
+    pub format: OutputFormat,
+    pub threads: Option<usize>,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    pub seed: u32,
+    pub spectral: bool,
+    // Receptor curves and where to write their false colors
+    pub sensor: Option<PathBuf>,
+    pub preview: Option<PathBuf>,
+    // Carry Stokes vectors, and where to write the degree and angle of
+    // polarization
+    pub polarized: bool,
+    pub dop: Option<PathBuf>,
+    pub aop: Option<PathBuf>,
+    // Samples per pixel in each pass when rendering progressively or with
+    // checkpoints, otherwise everything is rendered in one pass
+    pub pass_samples: u32,
+    // Set when rendering progressively
+    pub snapshot_every: Option<Interval>,
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_every: Interval,
+    pub resume: bool,
+}
+
+fn positive(v: String) -> Result<(), String> {
+    match v.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        Ok(_) => Err("must be greater than zero".to_string()),
+        Err(_) => Err(format!("`{}` is not a whole number", v)),
+    }
+}
+
+fn image_path(v: String) -> Result<(), String> {
+    match OutputFormat::from_path(Path::new(&v)) {
+        Some(_) => Ok(()),
+        None => Err("the file extension must be one of .png, .ppm, .hdr or .exr".to_string()),
+    }
+}
+
+fn interval(v: String) -> Result<(), String> {
+    match Interval::from_arg(&v) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "`{}` is neither a number of passes nor a number of seconds like `30s`",
+            v
+        )),
+    }
+}
+
+fn number<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> T {
+    // Validators have already run, so this only fails on a programming error
+    match matches.value_of(name).unwrap().parse() {
+        Ok(n) => n,
+        Err(_) => panic!("{} was not validated", name),
+    }
+}
+
+// What the command line asks for
+pub enum Command {
+    Render(Settings),
+    ListScenes,
+}
+
+pub fn parse_args() -> Settings {
+    match command_from(std::env::args_os()) {
+        Ok(Command::Render(settings)) => settings,
+        Ok(Command::ListScenes) => {
+            for (name, _) in SCENES.iter() {
+                println!("{}", name);
+            }
+            std::process::exit(0);
+        }
+        Err(e) => e.exit(),
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("shrimpray")
+        .about("A Monte Carlo path tracer")
+        .arg(
+            Arg::with_name("width")
+                .long("width")
+                .value_name("PIXELS")
+                .default_value("500")
+                .validator(positive)
+                .help("Image width"),
+        )
+        .arg(
+            Arg::with_name("height")
+                .long("height")
+                .value_name("PIXELS")
+                .default_value("500")
+                .validator(positive)
+                .help("Image height"),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .short("s")
+                .long("samples")
+                .value_name("N")
+                .default_value("1000")
+                .validator(positive)
+                .help("Samples per pixel"),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .short("d")
+                .long("depth")
+                .value_name("N")
+                .default_value("50")
+                .validator(positive)
+                .help("Maximum number of bounces per path"),
+        )
+        .arg(
+            Arg::with_name("scene")
+                .long("scene")
+                .value_name("NAME|FILE")
+                .default_value("cornell_mc")
+                .help("Built-in scene name or path to a scene file"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .validator(image_path)
+                .help("Output image (.png, .ppm, .hdr or .exr), binary PPM on stdout if not given"),
+        )
+        .arg(
+            Arg::with_name("half")
+                .long("half")
+                .help("Store OpenEXR color channels as half floats"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("j")
+                .long("threads")
+                .value_name("N")
+                .validator(positive)
+                .help("Number of render threads, defaults to one per core"),
+        )
+        .arg(
+            Arg::with_name("tile-size")
+                .long("tile-size")
+                .value_name("PIXELS")
+                .default_value("32")
+                .validator(positive)
+                .help("Width and height of the tiles rendered in parallel"),
+        )
+        .arg(
+            Arg::with_name("tile-order")
+                .long("tile-order")
+                .value_name("ORDER")
+                .default_value("hilbert")
+                .possible_values(&["scanline", "spiral", "hilbert"])
+                .help("Order in which tiles are rendered"),
+        )
+        .arg(
+            Arg::with_name("progressive")
+                .long("progressive")
+                .requires("output")
+                .help("Render in passes over the whole image, writing the output as it improves"),
+        )
+        .arg(
+            Arg::with_name("pass-samples")
+                .long("pass-samples")
+                .value_name("N")
+                .default_value("1")
+                .validator(positive)
+                .help("Samples per pixel in each progressive pass"),
+        )
+        .arg(
+            Arg::with_name("snapshot-every")
+                .long("snapshot-every")
+                .value_name("N|Ns")
+                .default_value("10")
+                .validator(interval)
+                .help("Write the output every N passes, or every N seconds with an `s` suffix"),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .value_name("FILE")
+                .help("Save the render state to a checkpoint file between passes"),
+        )
+        .arg(
+            Arg::with_name("checkpoint-every")
+                .long("checkpoint-every")
+                .value_name("N|Ns")
+                .default_value("60s")
+                .validator(interval)
+                .help("Save a checkpoint every N passes, or every N seconds with an `s` suffix"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .requires("checkpoint")
+                .help("Carry on from the checkpoint file, given the same scene and settings"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("N")
+                .default_value("0")
+                .validator(|v| match v.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("`{}` is not a whole number", v)),
+                })
+                .help("Seed for the random numbers, the same seed gives the same image"),
+        )
+        .arg(
+            Arg::with_name("spectral")
+                .long("spectral")
+                .help("Trace wavelengths from 300 to 720 nm instead of red, green and blue"),
+        )
+        .arg(
+            Arg::with_name("sensor")
+                .long("sensor")
+                .value_name("CSV")
+                .requires("output")
+                .help("Render for receptors with the spectral sensitivities in a CSV file, as layers of an .exr output"),
+        )
+        .arg(
+            Arg::with_name("preview")
+                .long("preview")
+                .value_name("FILE")
+                .requires("sensor")
+                .validator(image_path)
+                .help("Also write the receptors in false color to an image file"),
+        )
+        .arg(
+            Arg::with_name("polarized")
+                .long("polarized")
+                .help("Trace the polarization of the light, as layers of an .exr output"),
+        )
+        .arg(
+            Arg::with_name("dop")
+                .long("dop")
+                .value_name("FILE")
+                .validator(image_path)
+                .help("Also write the degree of polarization to an image file, implies --polarized"),
+        )
+        .arg(
+            Arg::with_name("aop")
+                .long("aop")
+                .value_name("FILE")
+                .validator(image_path)
+                .help("Also write the angle of polarization to an image file, implies --polarized"),
+        )
+        .arg(
+            Arg::with_name("list-scenes")
+                .long("list-scenes")
+                .help("List the built-in scenes and exit"),
+        )
+}
+
+// What a command line starting with the program name asks for, or why it
+// makes no sense
+pub fn command_from<I, T>(args: I) -> Result<Command, Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = app().get_matches_from_safe(args)?;
+
+    if matches.is_present("list-scenes") {
+        return Ok(Command::ListScenes);
+    }
+
+    let scene_arg = matches.value_of("scene").unwrap();
+    let scene = if let Some((name, f)) = find_scene(scene_arg) {
+        SceneSource::Builtin(name, f)
+    } else if Path::new(scene_arg).is_file() {
+        SceneSource::File(PathBuf::from(scene_arg))
+    } else {
+        return Err(Error::with_description(
+            &format!(
+                "unknown scene `{}`, expected a scene file or one of the names from --list-scenes",
+                scene_arg
+            ),
+            ErrorKind::InvalidValue,
+        ));
+    };
+
+    let output = matches.value_of("output").map(PathBuf::from);
+    let format = match output.as_ref().and_then(|p| OutputFormat::from_path(p)) {
+        Some(OutputFormat::Exr(_)) if matches.is_present("half") => {
+            OutputFormat::Exr(Precision::Half)
+        }
+        Some(format) => format,
+        None => OutputFormat::Ppm,
+    };
+    let exr = matches!(format, OutputFormat::Exr(_));
+    if matches.is_present("sensor") && !exr {
+        return Err(Error::with_description(
+            "--sensor needs an .exr output to hold a layer per receptor",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+    if matches.is_present("polarized")
+        && !exr
+        && !matches.is_present("dop")
+        && !matches.is_present("aop")
+    {
+        return Err(Error::with_description(
+            "--polarized needs an .exr output, or --dop or --aop, to keep the polarization",
+            ErrorKind::ArgumentConflict,
+        ));
+    }
+
+    Ok(Command::Render(Settings {
+        width: number(&matches, "width"),
+        height: number(&matches, "height"),
+        samples: number(&matches, "samples"),
+        max_depth: number(&matches, "depth"),
+        scene,
+        output,
+        format,
+        threads: matches
+            .value_of("threads")
+            .map(|_| number(&matches, "threads")),
+        tile_size: number(&matches, "tile-size"),
+        tile_order: TileOrder::from_name(matches.value_of("tile-order").unwrap()).unwrap(),
+        seed: number(&matches, "seed"),
+        spectral: matches.is_present("spectral") || matches.is_present("sensor"),
+        sensor: matches.value_of("sensor").map(PathBuf::from),
+        preview: matches.value_of("preview").map(PathBuf::from),
+        polarized: matches.is_present("polarized")
+            || matches.is_present("dop")
+            || matches.is_present("aop"),
+        dop: matches.value_of("dop").map(PathBuf::from),
+        aop: matches.value_of("aop").map(PathBuf::from),
+        pass_samples: number(&matches, "pass-samples"),
+        snapshot_every: if matches.is_present("progressive") {
+            Interval::from_arg(matches.value_of("snapshot-every").unwrap())
+        } else {
+            None
+        },
+        checkpoint: matches.value_of("checkpoint").map(PathBuf::from),
+        checkpoint_every: Interval::from_arg(matches.value_of("checkpoint-every").unwrap())
+            .unwrap(),
+        resume: matches.is_present("resume"),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(args: &[&str]) -> ErrorKind {
+        match command_from(std::iter::once("shrimpray").chain(args.iter().cloned())) {
+            Ok(_) => panic!("{:?} was accepted", args),
+            Err(e) => e.kind,
+        }
+    }
+
+    #[test]
+    fn bad_settings_are_refused() {
+        assert_eq!(error(&["--samples", "0"]), ErrorKind::ValueValidation);
+        assert_eq!(error(&["--scene", "nope"]), ErrorKind::InvalidValue);
+        assert_eq!(
+            error(&["--sensor", "eye.csv", "-o", "out.png"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            error(&["--polarized", "-o", "out.png"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert!(matches!(
+            command_from(vec!["shrimpray", "--polarized", "--dop", "dop.png"]),
+            Ok(Command::Render(_))
+        ));
+        assert!(matches!(
+            command_from(vec!["shrimpray", "--samples", "4"]),
+            Ok(Command::Render(_))
+        ));
+        assert!(matches!(
+            command_from(vec!["shrimpray", "--list-scenes"]),
+            Ok(Command::ListScenes)
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use std::io::{self, Read, Write};
+
+use crate::output::{Aovs, Image, Polarization};
+use crate::polarization::Stokes;
+use crate::vec3::Vec3;
+
+// Accumulates samples per pixel, in rows from top to bottom. Color, albedo,
+// normal, receptor responses and Stokes vectors are running sums, depth
+// keeps the nearest hit.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    color: Vec<Vec3>,
+    samples: Vec<u32>,
+    aovs: Option<Aovs>,
+    // The number of sensor receptors and their responses, that many values
+    // per pixel
+    receptors: usize,
+    responses: Vec<f32>,
+    // S1, S2 and S3 for each pixel when polarized, S0 being the color
+    stokes: Vec<Vec3>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, with_aovs: bool) -> Self {
+        let n = (width * height) as usize;
+        let aovs = if with_aovs {
+            Some(Aovs {
+                albedo: vec![Vec3::default(); n],
+                normal: vec![Vec3::default(); n],
+                depth: vec![f32::INFINITY; n],
+            })
+        } else {
+            None
+        };
+        Self {
+            width,
+            height,
+            color: vec![Vec3::default(); n],
+            samples: vec![0; n],
+            aovs,
+            receptors: 0,
+            responses: Vec::new(),
+            stokes: Vec::new(),
+        }
+    }
+
+    // Also keeps the responses of a sensor with `receptors` receptors
+    pub fn with_receptors(mut self, receptors: usize) -> Self {
+        self.receptors = receptors;
+        self.responses = vec![0.0; (self.width * self.height) as usize * receptors];
+        self
+    }
+
+    // Also keeps the polarization if `polarized`
+    pub fn with_polarization(mut self, polarized: bool) -> Self {
+        let n = if polarized { 3 } else { 0 };
+        self.stokes = vec![Vec3::default(); (self.width * self.height) as usize * n];
+        self
+    }
+
+    pub fn has_aovs(&self) -> bool {
+        self.aovs.is_some()
+    }
+
+    pub fn receptors(&self) -> usize {
+        self.receptors
+    }
+
+    pub fn is_polarized(&self) -> bool {
+        !self.stokes.is_empty()
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    pub fn add_sample(&mut self, x: u32, y: u32, color: Vec3) {
+        let i = self.index(x, y);
+        self.color[i] += color;
+        self.samples[i] += 1;
+    }
+
+    pub fn add_aovs(&mut self, x: u32, y: u32, albedo: Vec3, normal: Vec3, depth: f32) {
+        let i = self.index(x, y);
+        if let Some(aovs) = self.aovs.as_mut() {
+            aovs.albedo[i] += albedo;
+            aovs.normal[i] += normal;
+            aovs.depth[i] = aovs.depth[i].min(depth);
+        }
+    }
+
+    pub fn add_responses(&mut self, x: u32, y: u32, responses: &[f32]) {
+        let i = self.index(x, y) * self.receptors;
+        for (sum, r) in self.responses[i..i + self.receptors]
+            .iter_mut()
+            .zip(responses)
+        {
+            *sum += r;
+        }
+    }
+
+    // The color goes in with `add_sample`, this adds the rest
+    pub fn add_stokes(&mut self, x: u32, y: u32, stokes: &Stokes) {
+        let i = self.index(x, y) * 3;
+        self.stokes[i] += stokes.s1;
+        self.stokes[i + 1] += stokes.s2;
+        self.stokes[i + 2] += stokes.s3;
+    }
+
+    // Adds everything in `other` to this film with its top left corner at (x0, y0)
+    pub fn merge(&mut self, x0: u32, y0: u32, other: &Film) {
+        for y in 0..other.height {
+            for x in 0..other.width {
+                let i = self.index(x0 + x, y0 + y);
+                let j = other.index(x, y);
+                self.color[i] += other.color[j];
+                self.samples[i] += other.samples[j];
+                if let (Some(aovs), Some(other_aovs)) = (self.aovs.as_mut(), other.aovs.as_ref()) {
+                    aovs.albedo[i] += other_aovs.albedo[j];
+                    aovs.normal[i] += other_aovs.normal[j];
+                    aovs.depth[i] = aovs.depth[i].min(other_aovs.depth[j]);
+                }
+                let n = self.receptors;
+                for k in 0..n.min(other.receptors) {
+                    self.responses[i * n + k] += other.responses[j * n + k];
+                }
+                if self.is_polarized() && other.is_polarized() {
+                    for k in 0..3 {
+                        self.stokes[i * 3 + k] += other.stokes[j * 3 + k];
+                    }
+                }
+            }
+        }
+    }
+
+    // Fewest samples any pixel has received, all pixels get the same number
+    // unless the render was stopped halfway through a pass
+    pub fn spp(&self) -> u32 {
+        self.samples.iter().cloned().min().unwrap_or(0)
+    }
+
+    // The mean of everything accumulated so far
+    pub fn image(&self) -> Image {
+        let aovs = self.aovs.as_ref().map(|aovs| Aovs {
+            albedo: mean(&aovs.albedo, &self.samples),
+            normal: mean(&aovs.normal, &self.samples),
+            depth: aovs.depth.clone(),
+        });
+        let pixels = mean(&self.color, &self.samples);
+        let polarization = if self.is_polarized() {
+            let samples: Vec<u32> = self.samples.iter().flat_map(|n| vec![*n; 3]).collect();
+            let stokes: Vec<Stokes> = mean(&self.stokes, &samples)
+                .chunks(3)
+                .zip(pixels.iter())
+                .map(|(s, s0)| Stokes {
+                    s0: *s0,
+                    s1: s[0],
+                    s2: s[1],
+                    s3: s[2],
+                })
+                .collect();
+            Some(Polarization {
+                s1: stokes.iter().map(|s| s.s1).collect(),
+                s2: stokes.iter().map(|s| s.s2).collect(),
+                s3: stokes.iter().map(|s| s.s3).collect(),
+                dop: stokes.iter().map(|s| s.degree()).collect(),
+                aop: stokes.iter().map(|s| s.angle()).collect(),
+            })
+        } else {
+            None
+        };
+        Image {
+            width: self.width,
+            height: self.height,
+            pixels,
+            aovs,
+            receptors: None,
+            polarization,
+            spp: self.spp(),
+        }
+    }
+
+    // The mean responses, for `Sensor::receptors` to turn into layers
+    pub fn responses(&self) -> Option<Vec<f32>> {
+        if self.receptors == 0 {
+            return None;
+        }
+        let n = self.receptors;
+        let means = self
+            .responses
+            .chunks(n)
+            .zip(self.samples.iter())
+            .flat_map(|(sums, samples)| {
+                let samples = (*samples).max(1) as f32;
+                sums.iter().map(move |r| r / samples)
+            })
+            .collect();
+        Some(means)
+    }
+
+    // Raw little endian dump of the running sums and sample counts, read
+    // back by `read` for a film of the same size and buffers
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_vec3s(w, &self.color)?;
+        for n in self.samples.iter() {
+            w.write_all(&n.to_le_bytes())?;
+        }
+        if let Some(aovs) = &self.aovs {
+            write_vec3s(w, &aovs.albedo)?;
+            write_vec3s(w, &aovs.normal)?;
+            for d in aovs.depth.iter() {
+                w.write_all(&d.to_le_bytes())?;
+            }
+        }
+        for r in self.responses.iter() {
+            w.write_all(&r.to_le_bytes())?;
+        }
+        write_vec3s(w, &self.stokes)
+    }
+
+    pub fn read<R: Read>(
+        r: &mut R,
+        width: u32,
+        height: u32,
+        with_aovs: bool,
+        receptors: usize,
+        polarized: bool,
+    ) -> io::Result<Film> {
+        let mut film = Film::new(width, height, with_aovs)
+            .with_receptors(receptors)
+            .with_polarization(polarized);
+        read_vec3s(r, &mut film.color)?;
+        for n in film.samples.iter_mut() {
+            *n = u32::from_le_bytes(read_word(r)?);
+        }
+        if let Some(aovs) = film.aovs.as_mut() {
+            read_vec3s(r, &mut aovs.albedo)?;
+            read_vec3s(r, &mut aovs.normal)?;
+            for d in aovs.depth.iter_mut() {
+                *d = f32::from_le_bytes(read_word(r)?);
+            }
+        }
+        for v in film.responses.iter_mut() {
+            *v = f32::from_le_bytes(read_word(r)?);
+        }
+        read_vec3s(r, &mut film.stokes)?;
+        Ok(film)
+    }
+}
+
+fn write_vec3s<W: Write>(w: &mut W, values: &[Vec3]) -> io::Result<()> {
+    for v in values.iter() {
+        for c in 0..3 {
+            w.write_all(&v[c].to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_word<R: Read>(r: &mut R) -> io::Result<[u8; 4]> {
+    let mut word = [0; 4];
+    r.read_exact(&mut word)?;
+    Ok(word)
+}
+
+fn read_vec3s<R: Read>(r: &mut R, values: &mut [Vec3]) -> io::Result<()> {
+    for v in values.iter_mut() {
+        for c in 0..3 {
+            v[c] = f32::from_le_bytes(read_word(r)?);
+        }
+    }
+    Ok(())
+}
+
+fn mean(sums: &[Vec3], samples: &[u32]) -> Vec<Vec3> {
+    sums.iter()
+        .zip(samples.iter())
+        .map(|(c, n)| if *n > 0 { *c / *n as f32 } else { *c })
+        .collect()
+}
@@ -0,0 +1,544 @@
+// glTF 2.0 scenes, as .gltf with embedded or external buffers or as .glb.
+//
+// Node transforms are baked into the vertices, so every primitive becomes
+// a triangle mesh in world space. Metallic-roughness materials become
+// principled ones, with each factor multiplied into its texture, except
+// that emissive materials are lights. The first perspective camera becomes
+// the scene camera.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use base64::Engine;
+use gltf::camera::Projection;
+use gltf::mesh::Mode;
+
+use crate::bvh::FlatBvh;
+use crate::camera::Camera;
+use crate::hit::Hittable;
+use crate::material::*;
+use crate::obj::{MeshData, TriangleMesh};
+use crate::objfile::MeshError;
+use crate::scene::Scene;
+use crate::texture::*;
+use crate::vec3::{cross, dot, Vec3};
+
+// Column major, as glTF stores them
+type Mat4 = [[f32; 4]; 4];
+
+const IDENTITY: Mat4 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for (c, column) in m.iter_mut().enumerate() {
+        for (r, x) in column.iter_mut().enumerate() {
+            *x = (0..4).map(|k| a[k][r] * b[c][k]).sum();
+        }
+    }
+    m
+}
+
+fn column(m: &Mat4, c: usize) -> Vec3 {
+    Vec3::new(m[c][0], m[c][1], m[c][2])
+}
+
+fn transform_point(m: &Mat4, p: [f32; 3]) -> Vec3 {
+    p[0] * column(m, 0) + p[1] * column(m, 1) + p[2] * column(m, 2) + column(m, 3)
+}
+
+// Normals go through the inverse transpose, which is the cofactor matrix
+// up to a scale that normalizing takes care of
+fn transform_normal(m: &Mat4, n: [f32; 3]) -> Vec3 {
+    let (a, b, c) = (column(m, 0), column(m, 1), column(m, 2));
+    let n = n[0] * cross(b, c) + n[1] * cross(c, a) + n[2] * cross(a, b);
+    (n * dot(a, cross(b, c)).signum()).unit()
+}
+
+// URIs escape bytes as %XX, and names outside ASCII as their UTF-8 bytes
+fn percent_decode(uri: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(uri.len());
+    let mut rest = uri.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+struct Importer<'a> {
+    path: &'a Path,
+    document: &'a gltf::Document,
+    buffers: Vec<Vec<u8>>,
+    textures: HashMap<usize, Arc<dyn Texture>>,
+    materials: HashMap<Option<usize>, (Arc<dyn Material>, bool)>,
+    world: Vec<Arc<dyn Hittable>>,
+    lights: Vec<Arc<dyn Hittable>>,
+    camera: Option<(Mat4, f32)>,
+    // Bounds of everything, to frame a default camera
+    min: Vec3,
+    max: Vec3,
+}
+
+impl<'a> Importer<'a> {
+    fn new(path: &'a Path, document: &'a gltf::Document) -> Self {
+        Self {
+            path,
+            document,
+            buffers: Vec::new(),
+            textures: HashMap::new(),
+            materials: HashMap::new(),
+            world: Vec::new(),
+            lights: Vec::new(),
+            camera: None,
+            min: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, MeshError> {
+        Err(MeshError::Invalid(self.path.to_path_buf(), message))
+    }
+
+    // Data URIs are decoded, anything else is a file next to the glTF
+    fn read_uri(&self, uri: &str) -> Result<Vec<u8>, MeshError> {
+        if let Some(rest) = uri.strip_prefix("data:") {
+            let data = match rest.find(";base64,") {
+                Some(at) => &rest[at + ";base64,".len()..],
+                None => return self.error("only base64 data URIs are supported".to_string()),
+            };
+            return match base64::engine::general_purpose::STANDARD.decode(data) {
+                Ok(bytes) => Ok(bytes),
+                Err(e) => self.error(format!("bad data URI: {}", e)),
+            };
+        }
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let file = match percent_decode(uri) {
+            Some(name) => dir.join(name),
+            None => return self.error(format!("bad escape in URI {}", uri)),
+        };
+        std::fs::read(&file).map_err(|e| MeshError::Io(file, e))
+    }
+
+    fn texture(&mut self, image: gltf::Image) -> Result<Arc<dyn Texture>, MeshError> {
+        if let Some(tex) = self.textures.get(&image.index()) {
+            return Ok(tex.clone());
+        }
+        let bytes = match image.source() {
+            gltf::image::Source::Uri { uri, .. } => self.read_uri(uri)?,
+            gltf::image::Source::View { view, .. } => {
+                let buffer = &self.buffers[view.buffer().index()];
+                match buffer.get(view.offset()..view.offset() + view.length()) {
+                    Some(bytes) => bytes.to_vec(),
+                    None => return self.error(format!("image {} is out of bounds", image.index())),
+                }
+            }
+        };
+        let img = match image::load_from_memory(&bytes) {
+            Ok(img) => img.to_rgb(),
+            Err(e) => return self.error(format!("could not load image {}: {}", image.index(), e)),
+        };
+        let (nx, ny) = img.dimensions();
+        let tex: Arc<dyn Texture> =
+            Arc::new(ImageTexture::new(img.into_raw(), nx as i32, ny as i32));
+        self.textures.insert(image.index(), tex.clone());
+        Ok(tex)
+    }
+
+    // The texture in `info` times `factor`, or the factor alone. Parameters
+    // packed into one channel of an image take just that channel.
+    fn factored(
+        &mut self,
+        info: Option<gltf::texture::Info>,
+        channel: Option<u32>,
+        factor: Vec3,
+    ) -> Result<Arc<dyn Texture>, MeshError> {
+        let info = match info {
+            Some(info) => info,
+            None => return Ok(Arc::new(ConstantTexture::new(factor))),
+        };
+        let mut tex = self.texture(info.texture().source())?;
+        if let Some(channel) = channel {
+            tex = Arc::new(ChannelTexture::new(tex, channel));
+        }
+        Ok(Arc::new(ScaledTexture::new(tex, factor)))
+    }
+
+    fn principled(&mut self, mat: &gltf::Material) -> Result<Principled, MeshError> {
+        let splat = |x: f32| Vec3::new(x, x, x);
+        let pbr = mat.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let base_color = self.factored(pbr.base_color_texture(), None, Vec3::new(r, g, b))?;
+        let mut principled = Principled::new(base_color);
+        // Roughness is in green and metallic in blue
+        let metallic_roughness = pbr.metallic_roughness_texture();
+        principled.metallic = self.factored(
+            metallic_roughness.clone(),
+            Some(2),
+            splat(pbr.metallic_factor()),
+        )?;
+        principled.roughness =
+            self.factored(metallic_roughness, Some(1), splat(pbr.roughness_factor()))?;
+        if let Some(t) = mat.transmission() {
+            principled.transmission = self.factored(
+                t.transmission_texture(),
+                Some(0),
+                splat(t.transmission_factor()),
+            )?;
+        }
+        if let Some(ior) = mat.ior() {
+            // Our specular is 1 at 8 percent reflected head on
+            let f0 = ((ior - 1.0) / (ior + 1.0)).powi(2);
+            principled.specular = Arc::new(ConstantTexture::new(splat(f0 / 0.08)));
+            principled.ior = Arc::new(ConstantTexture::new(splat(ior)));
+        }
+        Ok(principled)
+    }
+
+    // The material and whether it emits light
+    fn material(&mut self, mat: gltf::Material) -> Result<(Arc<dyn Material>, bool), MeshError> {
+        if let Some(m) = self.materials.get(&mat.index()) {
+            return Ok(m.clone());
+        }
+        let [er, eg, eb] = mat.emissive_factor();
+        let emission = Vec3::new(er, eg, eb) * mat.emissive_strength().unwrap_or(1.0);
+
+        let m: (Arc<dyn Material>, bool) = if mat.index().is_none() {
+            // Primitives without a material, the glTF default would be a
+            // rough white metal which is rarely what was meant
+            let grey = Vec3::new(0.8, 0.8, 0.8);
+            (
+                Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(grey)))),
+                false,
+            )
+        } else if emission.x().max(emission.y()).max(emission.z()) > 0.0 {
+            let light = self.factored(mat.emissive_texture(), None, emission)?;
+            (Arc::new(DiffuseLight::new(light)), true)
+        } else {
+            (Arc::new(self.principled(&mat)?), false)
+        };
+        self.materials.insert(mat.index(), m.clone());
+        Ok(m)
+    }
+
+    fn primitive(&mut self, prim: gltf::Primitive, world: &Mat4) -> Result<(), MeshError> {
+        let buffers = &self.buffers;
+        let reader = prim.reader(|b| Some(&buffers[b.index()]));
+        let positions: Vec<[f32; 3]> = match reader.read_positions() {
+            Some(p) => p.collect(),
+            None => return Ok(()),
+        };
+        let normals: Vec<[f32; 3]> = reader.read_normals().map_or(Vec::new(), |n| n.collect());
+        // glTF puts v = 0 at the top of the image
+        let uvs: Vec<(f32, f32)> = reader.read_tex_coords(0).map_or(Vec::new(), |t| {
+            t.into_f32().map(|[u, v]| (u, 1.0 - v)).collect()
+        });
+        let corners: Vec<u32> = match reader.read_indices() {
+            Some(i) => i.into_u32().collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        let mut indices: Vec<[u32; 3]> = match prim.mode() {
+            Mode::Triangles => corners
+                .chunks_exact(3)
+                .map(|c| [c[0], c[1], c[2]])
+                .collect(),
+            Mode::TriangleStrip => (2..corners.len())
+                .map(|i| match i % 2 {
+                    0 => [corners[i - 2], corners[i - 1], corners[i]],
+                    _ => [corners[i - 1], corners[i - 2], corners[i]],
+                })
+                .collect(),
+            Mode::TriangleFan => (2..corners.len())
+                .map(|i| [corners[0], corners[i - 1], corners[i]])
+                .collect(),
+            // Points and lines have no area to hit
+            _ => return Ok(()),
+        };
+        if let Some(bad) = indices
+            .iter()
+            .flatten()
+            .find(|i| **i as usize >= positions.len())
+        {
+            return self.error(format!(
+                "index {} is out of range, there are {} vertices",
+                bad,
+                positions.len()
+            ));
+        }
+        // A mirroring transform turns the faces inside out
+        let (a, b, c) = (column(world, 0), column(world, 1), column(world, 2));
+        if dot(a, cross(b, c)) < 0.0 {
+            indices.iter_mut().for_each(|tri| tri.swap(1, 2));
+        }
+        indices.retain(|[a, b, c]| a != b && b != c && a != c);
+        if indices.is_empty() {
+            return Ok(());
+        }
+
+        let positions: Vec<Vec3> = positions
+            .iter()
+            .map(|p| transform_point(world, *p))
+            .collect();
+        for p in positions.iter() {
+            for k in 0..3 {
+                self.min[k] = self.min[k].min(p[k]);
+                self.max[k] = self.max[k].max(p[k]);
+            }
+        }
+        let mesh = MeshData {
+            positions,
+            normals: normals
+                .iter()
+                .map(|n| transform_normal(world, *n))
+                .collect(),
+            uvs,
+            colors: Vec::new(),
+            indices,
+            motion: Vec::new(),
+        };
+        let (material, emits) = self.material(prim.material())?;
+        let mesh: Arc<dyn Hittable> = Arc::new(TriangleMesh::new(Arc::new(mesh), material));
+        if emits {
+            self.lights.push(mesh.clone());
+        }
+        self.world.push(mesh);
+        Ok(())
+    }
+
+    fn node(&mut self, node: gltf::Node, parent: &Mat4) -> Result<(), MeshError> {
+        let world = mul(parent, &node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            for prim in mesh.primitives() {
+                self.primitive(prim, &world)?;
+            }
+        }
+        if let (None, Some(camera)) = (self.camera, node.camera()) {
+            if let Projection::Perspective(p) = camera.projection() {
+                self.camera = Some((world, p.yfov()));
+            }
+        }
+        for child in node.children() {
+            self.node(child, &world)?;
+        }
+        Ok(())
+    }
+
+    // Looks down the camera's -z with its y up, or if there is no camera
+    // down -z at the whole scene
+    fn camera(&self, aspect: f32) -> Camera {
+        let (lookfrom, lookat, vup, vfov) = match &self.camera {
+            Some((m, yfov)) => {
+                let origin = column(m, 3);
+                let forward = -1.0 * column(m, 2).unit();
+                (
+                    origin,
+                    origin + forward,
+                    column(m, 1).unit(),
+                    yfov.to_degrees(),
+                )
+            }
+            None => {
+                let center = 0.5 * (self.min + self.max);
+                let radius = 0.5 * (self.max - self.min).mag();
+                let vfov: f32 = 40.0;
+                let distance = radius / (vfov.to_radians() / 2.0).sin();
+                let lookfrom = center + Vec3::new(0.0, 0.0, distance);
+                (lookfrom, center, Vec3::new(0.0, 1.0, 0.0), vfov)
+            }
+        };
+        Camera::new(lookfrom, lookat, vup, vfov, aspect, 0.0, 1.0, 0.0, 1.0)
+    }
+}
+
+// `path` is only used to find external buffers and images and for errors
+pub fn parse_gltf(data: &[u8], path: &Path, aspect: f32) -> Result<Scene, MeshError> {
+    let invalid = |message: String| MeshError::Invalid(path.to_path_buf(), message);
+    let gltf::Gltf { document, mut blob } =
+        gltf::Gltf::from_slice(data).map_err(|e| invalid(e.to_string()))?;
+    let mut importer = Importer::new(path, &document);
+    for buffer in document.buffers() {
+        let mut bytes = match buffer.source() {
+            gltf::buffer::Source::Bin => match blob.take() {
+                Some(blob) => blob,
+                None => return Err(invalid("missing the binary chunk".to_string())),
+            },
+            gltf::buffer::Source::Uri(uri) => importer.read_uri(uri)?,
+        };
+        if bytes.len() < buffer.length() {
+            return Err(invalid(format!("buffer {} is too short", buffer.index())));
+        }
+        // Accessors are validated against the declared length
+        bytes.truncate(buffer.length());
+        importer.buffers.push(bytes);
+    }
+
+    let scene = match importer.document.default_scene() {
+        Some(scene) => scene,
+        None => match importer.document.scenes().next() {
+            Some(scene) => scene,
+            None => return Err(invalid("no scenes".to_string())),
+        },
+    };
+    for node in scene.nodes() {
+        importer.node(node, &IDENTITY)?;
+    }
+    if importer.world.is_empty() {
+        return Err(invalid("no triangles".to_string()));
+    }
+
+    let camera = importer.camera(aspect);
+    let world: Vec<Arc<dyn Hittable>> = vec![Arc::new(FlatBvh::new(&importer.world, 0.0, 1.0))];
+    Ok(Scene {
+        camera,
+        world,
+        lights: importer.lights,
+    })
+}
+
+pub fn load_gltf(path: &Path, aspect: f32) -> Result<Scene, MeshError> {
+    match std::fs::read(path) {
+        Ok(data) => parse_gltf(&data, path, aspect),
+        Err(e) => Err(MeshError::Io(path.to_path_buf(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::Ray;
+
+    // A unit triangle in a child node moved by its parent, an emissive
+    // material and a camera at z = 5 rotated to look down -x
+    fn sample() -> String {
+        let mut buffer = Vec::new();
+        for x in [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0].iter() {
+            buffer.extend_from_slice(&x.to_le_bytes());
+        }
+        let uri = base64::engine::general_purpose::STANDARD.encode(&buffer);
+        format!(
+            r#"{{
+  "asset": {{ "version": "2.0" }},
+  "scene": 0,
+  "scenes": [{{ "nodes": [0, 2] }}],
+  "nodes": [
+    {{ "translation": [10, 0, 0], "children": [1] }},
+    {{ "mesh": 0, "scale": [2, 2, 2] }},
+    {{ "camera": 0, "translation": [0, 0, 5], "rotation": [0, 0.7071068, 0, 0.7071068] }}
+  ],
+  "cameras": [{{ "type": "perspective", "perspective": {{ "yfov": 0.8, "znear": 0.1 }} }}],
+  "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "material": 0 }}] }}],
+  "materials": [{{ "emissiveFactor": [4, 4, 4] }}],
+  "accessors": [{{
+    "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+    "min": [0, 0, 0], "max": [1, 1, 0]
+  }}],
+  "bufferViews": [{{ "buffer": 0, "byteLength": 36 }}],
+  "buffers": [{{ "byteLength": 36, "uri": "data:application/octet-stream;base64,{}" }}]
+}}"#,
+            uri
+        )
+    }
+
+    // Two pixels, each texture and factor picked so that every product
+    // differs from the texture and the factor alone
+    #[test]
+    fn textures_take_their_factors() {
+        let pixels = vec![255, 51, 204, 0, 102, 255];
+        let image = image::RgbImage::from_raw(2, 1, pixels).unwrap();
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(image)
+            .write_to(&mut png, image::ImageOutputFormat::PNG)
+            .unwrap();
+        let uri = base64::engine::general_purpose::STANDARD.encode(&png);
+        let json = format!(
+            r#"{{
+  "asset": {{ "version": "2.0" }},
+  "extensionsUsed": ["KHR_materials_transmission"],
+  "materials": [{{
+    "pbrMetallicRoughness": {{
+      "baseColorFactor": [0.5, 1, 0.25, 1],
+      "baseColorTexture": {{ "index": 0 }},
+      "metallicFactor": 0.5,
+      "roughnessFactor": 0.8,
+      "metallicRoughnessTexture": {{ "index": 0 }}
+    }},
+    "extensions": {{
+      "KHR_materials_transmission": {{
+        "transmissionFactor": 0.5,
+        "transmissionTexture": {{ "index": 0 }}
+      }}
+    }}
+  }}],
+  "textures": [{{ "source": 0 }}],
+  "images": [{{ "uri": "data:image/png;base64,{}" }}]
+}}"#,
+            uri
+        );
+        let gltf::Gltf { document, .. } = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+        let mut importer = Importer::new(Path::new("textured.gltf"), &document);
+        let mat = importer
+            .principled(&document.materials().next().unwrap())
+            .unwrap();
+
+        let close = |a: Vec3, b: Vec3| (a - b).mag() < 1e-3;
+        let splat = |x: f32| Vec3::new(x, x, x);
+        let p = Vec3::default();
+        assert!(close(
+            mat.base_color.value(0.25, 0.5, &p),
+            Vec3::new(0.5, 0.2, 0.2)
+        ));
+        assert!(close(
+            mat.base_color.value(0.75, 0.5, &p),
+            Vec3::new(0.0, 0.4, 0.25)
+        ));
+        assert!(close(mat.metallic.value(0.25, 0.5, &p), splat(0.4)));
+        assert!(close(mat.roughness.value(0.25, 0.5, &p), splat(0.16)));
+        assert!(close(mat.roughness.value(0.75, 0.5, &p), splat(0.32)));
+        assert!(close(mat.transmission.value(0.25, 0.5, &p), splat(0.5)));
+        assert!(close(mat.transmission.value(0.75, 0.5, &p), splat(0.0)));
+    }
+
+    #[test]
+    fn uris_are_percent_decoded() {
+        assert_eq!(
+            percent_decode("my%20mesh%23%C3%A9t%C3%A9.bin").as_deref(),
+            Some("my mesh#été.bin")
+        );
+        assert_eq!(percent_decode("plain.bin").as_deref(), Some("plain.bin"));
+        assert_eq!(percent_decode("half%2"), None);
+        assert_eq!(percent_decode("not%zzhex"), None);
+        assert_eq!(percent_decode("bad%FFutf8"), None);
+    }
+
+    #[test]
+    fn nodes_materials_and_camera() {
+        let scene = parse_gltf(sample().as_bytes(), Path::new("sample.gltf"), 1.0).unwrap();
+        assert_eq!(scene.lights.len(), 1);
+
+        // The triangle is scaled by 2 and then moved to x = 10
+        let r = Ray::new(Vec3::new(10.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = scene.world[0].hit(r, 0.001, f32::MAX).unwrap();
+        assert!((hit.p - Vec3::new(10.5, 0.5, 0.0)).mag() < 1e-5);
+        let r = Ray::new(Vec3::new(11.2, 0.6, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(scene.world[0].hit(r, 0.001, f32::MAX).is_some());
+
+        // Turned 90 degrees about y, so the camera looks down -x
+        let r = scene.camera.get_ray(0.5, 0.5);
+        assert!((r.origin() - Vec3::new(0.0, 0.0, 5.0)).mag() < 1e-5);
+        assert!((r.direction().unit() - Vec3::new(-1.0, 0.0, 0.0)).mag() < 1e-3);
+    }
+}
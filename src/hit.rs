@@ -11,14 +11,24 @@ use crate::vec3::*;
 pub struct HitRecord {
     pub t: f32,
     pub p: Vec3,
+    // Shading normal, interpolated on meshes with vertex normals
     pub normal: Vec3,
+    // Normal of the actual surface, for light sampling
+    pub geometric_normal: Vec3,
     pub u: f32,
     pub v: f32,
+    // Direction of increasing u where the surface knows it, otherwise
+    // around the y axis. Orients anisotropic materials.
+    pub tangent: Vec3,
+    // The face of a mesh that was hit and the barycentric coordinates of its
+    // second and third corners, for blending what is stored per vertex
+    pub face: usize,
+    pub barycentric: (f32, f32),
     pub material: Arc<dyn Material>,
 }
 
 impl HitRecord {
-    fn new(
+    pub fn new(
         t: f32,
         p: Vec3,
         normal: Vec3,
@@ -30,8 +40,12 @@ impl HitRecord {
             t: t,
             p: p,
             normal: normal,
+            geometric_normal: normal,
             u: u,
             v: v,
+            tangent: cross(Vec3::new(0.0, 1.0, 0.0), normal),
+            face: 0,
+            barycentric: (0.0, 0.0),
             material: material,
         }
     }
@@ -100,6 +114,10 @@ impl Hittable for Sphere {
         Some(bbox)
     }
     fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        // From inside, every direction leads to the sphere
+        if (self.center - *o).mag_sqrd() < self.radius * self.radius {
+            return 1.0 / (4.0 * std::f32::consts::PI);
+        }
         if let Some(_) = self.hit(Ray::new(*o, *v, 0.0), 0.001, std::f32::MAX) {
             let cos_theta_max =
                 (1.0 - self.radius * self.radius / (self.center - *o).mag_sqrd()).sqrt();
@@ -111,6 +129,9 @@ impl Hittable for Sphere {
     fn random(&self, o: &Vec3) -> Vec3 {
         let direction = self.center - *o;
         let dist_sqrd = direction.mag_sqrd();
+        if dist_sqrd < self.radius * self.radius {
+            return random_unit_vector();
+        }
         let mut uvw = Onb::new();
         uvw.build_from_w(&direction);
         uvw.local_vector(&random_to_sphere(self.radius, dist_sqrd))
@@ -161,7 +182,7 @@ impl Hittable for Vec<Arc<dyn Hittable>> {
         sum
     }
     fn random(&self, o: &Vec3) -> Vec3 {
-        self.choose(&mut rand::thread_rng()).unwrap().random(o)
+        with_rng(|rng| self.choose(rng)).unwrap().random(o)
     }
 }
 
@@ -241,6 +262,12 @@ impl Hittable for BvhNode {
                     }
                 }
             }
+            // A leaf
+            (Some(left), None) => {
+                if self.bbox.hit(&r, t_min, t_max) {
+                    return left.hit(r, t_min, t_max);
+                }
+            }
             (_, _) => (),
         }
         None
@@ -250,6 +277,52 @@ impl Hittable for BvhNode {
     }
 }
 
+impl Hittable for FlatBvh {
+    fn hit(&self, r: Ray, t_min: f32, mut t_max: f32) -> Option<HitRecord> {
+        let origin = r.origin();
+        let d = r.direction();
+        let inv_dir = Vec3::new(1.0 / d.x(), 1.0 / d.y(), 1.0 / d.z());
+        let mut stack = [0; FLAT_BVH_STACK];
+        let mut top = 0;
+        let mut index = 0;
+        let mut closest = None;
+        loop {
+            let node = &self.nodes[index];
+            if node.bbox.hit_inv(&origin, &inv_dir, t_min, t_max) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    for obj in self.primitives[first..first + node.count as usize].iter() {
+                        // Each hit narrows the range left to search
+                        if let Some(hit) = obj.hit(r, t_min, t_max) {
+                            t_max = hit.t;
+                            closest = Some(hit);
+                        }
+                    }
+                } else {
+                    // Nearer child first, the other one waits on the stack
+                    let (near, far) = if inv_dir[node.axis] < 0.0 {
+                        (node.offset as usize, index + 1)
+                    } else {
+                        (index + 1, node.offset as usize)
+                    };
+                    stack[top] = far;
+                    top += 1;
+                    index = near;
+                    continue;
+                }
+            }
+            if top == 0 {
+                return closest;
+            }
+            top -= 1;
+            index = stack[top];
+        }
+    }
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        Some(self.nodes[0].bbox)
+    }
+}
+
 impl Hittable for XYRect {
     fn hit(&self, r: Ray, t0: f32, t1: f32) -> Option<HitRecord> {
         let t = (self.k - r.origin().z()) / r.direction().z();
@@ -273,6 +346,23 @@ impl Hittable for XYRect {
             Vec3::new(self.x1, self.y1, self.k + 0.0001),
         ))
     }
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        if let Some(hit) = self.hit(Ray::new(*o, *v, 0.0), 0.001, f32::MAX) {
+            let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+            let dist_sqrd = hit.t * hit.t * v.mag().powi(2);
+            let cosine = (dot(*v, hit.normal) / v.mag()).abs();
+            return dist_sqrd / (cosine * area);
+        }
+        0.0
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        let random_point = Vec3::new(
+            self.x0 + rand_float() * (self.x1 - self.x0),
+            self.y0 + rand_float() * (self.y1 - self.y0),
+            self.k,
+        );
+        random_point - *o
+    }
 }
 
 impl Hittable for XZRect {
@@ -340,12 +430,30 @@ impl Hittable for YZRect {
             Vec3::new(self.k + 0.0001, self.y1, self.z1),
         ))
     }
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        if let Some(hit) = self.hit(Ray::new(*o, *v, 0.0), 0.001, f32::MAX) {
+            let area = (self.y1 - self.y0) * (self.z1 - self.z0);
+            let dist_sqrd = hit.t * hit.t * v.mag().powi(2);
+            let cosine = (dot(*v, hit.normal) / v.mag()).abs();
+            return dist_sqrd / (cosine * area);
+        }
+        0.0
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        let random_point = Vec3::new(
+            self.k,
+            self.y0 + rand_float() * (self.y1 - self.y0),
+            self.z0 + rand_float() * (self.z1 - self.z0),
+        );
+        random_point - *o
+    }
 }
 
 impl Hittable for FlipNormals {
     fn hit(&self, r: Ray, t0: f32, t1: f32) -> Option<HitRecord> {
         if let Some(mut hit) = self.obj_ref.hit(r, t0, t1) {
             hit.normal *= -1.0;
+            hit.geometric_normal *= -1.0;
             return Some(hit);
         }
         None
@@ -353,6 +461,12 @@ impl Hittable for FlipNormals {
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
         self.obj_ref.bounding_box(t0, t1)
     }
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        self.obj_ref.pdf_value(o, v)
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        self.obj_ref.random(o)
+    }
 }
 
 impl Hittable for BoxShape {
@@ -383,6 +497,12 @@ impl Hittable for Translate {
         }
         None
     }
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        self.obj_ref.pdf_value(&(*o - self.offset), v)
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        self.obj_ref.random(&(*o - self.offset))
+    }
 }
 
 impl Hittable for RotateY {
@@ -397,14 +517,17 @@ impl Hittable for RotateY {
         direction[2] = self.sin_theta * r.direction()[0] + self.cos_theta * r.direction()[2];
         let rotate_r = Ray::new(origin, direction, r.time());
         if let Some(mut hit) = self.obj_ref.hit(rotate_r, t_min, t_max) {
-            let mut p = hit.p;
-            let mut normal = hit.normal;
-            p[0] = self.cos_theta * hit.p[0] + self.sin_theta * hit.p[2];
-            p[2] = -self.sin_theta * hit.p[0] + self.cos_theta * hit.p[2];
-            normal[0] = self.cos_theta * hit.normal[0] + self.sin_theta * hit.normal[2];
-            normal[2] = -self.sin_theta * hit.normal[0] + self.cos_theta * hit.normal[2];
-            hit.p = p;
-            hit.normal = normal;
+            let rotate = |v: Vec3| {
+                Vec3::new(
+                    self.cos_theta * v[0] + self.sin_theta * v[2],
+                    v[1],
+                    -self.sin_theta * v[0] + self.cos_theta * v[2],
+                )
+            };
+            hit.p = rotate(hit.p);
+            hit.normal = rotate(hit.normal);
+            hit.geometric_normal = rotate(hit.geometric_normal);
+            hit.tangent = rotate(hit.tangent);
             return Some(hit);
         }
         None
@@ -444,6 +567,147 @@ impl Hittable for RotateY {
     }
 }
 
+// Hits, bounds and light sampling for an object seen through `transform`,
+// shared by the transformed objects
+//
+// The ray direction is not normalized in object space, so distances along
+// it are the same in both spaces
+fn hit_through(
+    obj: &dyn Hittable,
+    transform: &Transform,
+    r: Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<HitRecord> {
+    let inv = transform.inverse();
+    let local = Ray::new(inv.point(r.origin()), inv.vector(r.direction()), r.time());
+    let mut hit = obj.hit(local, t_min, t_max)?;
+    hit.p = transform.point(hit.p);
+    hit.normal = transform.normal(hit.normal).unit();
+    hit.geometric_normal = transform.normal(hit.geometric_normal).unit();
+    hit.tangent = transform.vector(hit.tangent);
+    Some(hit)
+}
+
+// The eight corners of a box
+fn corners(bbox: &AABB) -> impl Iterator<Item = Vec3> + '_ {
+    (0..8).map(move |i| {
+        let pick = |bit, a: u32| {
+            if i & bit == 0 {
+                bbox.min()[a]
+            } else {
+                bbox.max()[a]
+            }
+        };
+        Vec3::new(pick(1, 0), pick(2, 1), pick(4, 2))
+    })
+}
+
+fn box_through(bbox: &AABB, transform: &Transform) -> AABB {
+    let corners = corners(bbox).map(|p| transform.point(p));
+    let (mut min, mut max) = (
+        Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+        Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+    );
+    for p in corners {
+        for a in 0..3 {
+            min[a] = min[a].min(p[a]);
+            max[a] = max[a].max(p[a]);
+        }
+    }
+    AABB::new(min, max)
+}
+
+// The object gives a density over its own directions. Mapping a unit
+// direction w through the linear part A of the inverse stretches solid
+// angle by |det A| / |A w|^3.
+fn pdf_through(obj: &dyn Hittable, transform: &Transform, o: &Vec3, v: &Vec3) -> f32 {
+    let inv = transform.inverse();
+    let local_v = inv.vector(*v);
+    let pdf = obj.pdf_value(&inv.point(*o), &local_v);
+    let stretch = local_v.mag() / v.mag();
+    pdf * inv.determinant().abs() / (stretch * stretch * stretch)
+}
+
+fn random_through(obj: &dyn Hittable, transform: &Transform, o: &Vec3) -> Vec3 {
+    let inv = transform.inverse();
+    transform.vector(obj.random(&inv.point(*o)))
+}
+
+impl Hittable for Transformed {
+    fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        hit_through(&*self.obj_ref, &self.transform, r, t_min, t_max)
+    }
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        let bbox = self.obj_ref.bounding_box(t0, t1)?;
+        Some(box_through(&bbox, &self.transform))
+    }
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        pdf_through(&*self.obj_ref, &self.transform, o, v)
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        random_through(&*self.obj_ref, &self.transform, o)
+    }
+}
+
+impl Hittable for Keyframed {
+    fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        hit_through(&*self.obj_ref, &self.at(r.time()), r, t_min, t_max)
+    }
+    // The boxes at times close enough together that the object turns by
+    // at most MAX_TURN between them. A point at distance d from the center
+    // of rotation strays from the line between two of them by no more than
+    // d (1 - cos(MAX_TURN / 2)), which pads the result.
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        let bbox = self.obj_ref.bounding_box(t0, t1)?;
+        let mut swept: Option<AABB> = None;
+        let mut reach: f32 = 0.0;
+        for time in self.sample_times(t0, t1) {
+            let transform = self.at(time);
+            let moved = box_through(&bbox, &transform);
+            let center = transform.point(Vec3::new(0.0, 0.0, 0.0));
+            for p in corners(&moved) {
+                reach = reach.max((p - center).mag());
+            }
+            swept = Some(match swept {
+                Some(b) => surrounding_bbox(b, moved),
+                None => moved,
+            });
+        }
+        let swept = swept?;
+        let pad = reach * (1.0 - (MAX_TURN / 2.0).cos());
+        let pad = Vec3::new(pad, pad, pad);
+        Some(AABB::new(swept.min() - pad, swept.max() + pad))
+    }
+    // The sampling interface has no time, so lights are sampled where they
+    // are halfway between the first and last keys
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        pdf_through(&*self.obj_ref, &self.halfway(), o, v)
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        random_through(&*self.obj_ref, &self.halfway(), o)
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut hit = self.placed.hit(r, t_min, t_max)?;
+        if let Some(material) = &self.material {
+            hit.material = material.clone();
+        }
+        Some(hit)
+    }
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.placed.bounding_box(t0, t1)
+    }
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        self.placed.pdf_value(o, v)
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        self.placed.random(o)
+    }
+}
+
 impl Hittable for ConstantMedium {
     fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         if let Some(mut hit1) = self
@@ -485,3 +749,297 @@ impl Hittable for ConstantMedium {
         self.boundary.bounding_box(t0, t1)
     }
 }
+
+impl Hittable for Triangle {
+    // Möller–Trumbore, solves for the distance and the barycentric
+    // coordinates of p1 and p2 at once
+    fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let (p0, p1, p2) = self.vertices_at(r.time());
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let pvec = cross(r.direction(), e2);
+        let det = dot(e1, pvec);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let tvec = r.origin() - p0;
+        let b1 = dot(tvec, pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = cross(tvec, e1);
+        let b2 = dot(r.direction(), qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = dot(e2, qvec) * inv_det;
+        if t < t_min || t > t_max {
+            return None;
+        }
+
+        let b0 = 1.0 - b1 - b2;
+        let [i0, i1, i2] = self.indices();
+        let mesh = &self.mesh;
+        let (u, v, tangent) = if mesh.uvs.is_empty() {
+            (b1, b2, None)
+        } else {
+            let (uv0, uv1, uv2) = (mesh.uvs[i0], mesh.uvs[i1], mesh.uvs[i2]);
+            // dp/du from how the UVs change along the edges
+            let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+            let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+            let det = du1 * dv2 - du2 * dv1;
+            (
+                b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0,
+                b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1,
+                if det.abs() > 1e-12 {
+                    Some((dv2 * e1 - dv1 * e2) / det)
+                } else {
+                    None
+                },
+            )
+        };
+        let geometric_normal = cross(e1, e2).unit();
+        let mut hit = HitRecord::new(
+            t,
+            r.point_at_parameter(t),
+            geometric_normal,
+            u,
+            v,
+            self.material.clone(),
+        );
+        hit.face = self.face;
+        hit.barycentric = (b1, b2);
+        if !mesh.normals.is_empty() {
+            let n = b0 * mesh.normals[i0] + b1 * mesh.normals[i1] + b2 * mesh.normals[i2];
+            hit.normal = n.unit();
+        }
+        if let Some(tangent) = tangent {
+            hit.tangent = tangent;
+        }
+        Some(hit)
+    }
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        // Vertices move in straight lines between keys, so the corners at
+        // the ends of the interval and at the keys inside it bound them
+        let mut times = vec![t0, t1];
+        times.extend(
+            self.mesh
+                .motion
+                .iter()
+                .map(|(t, _)| *t)
+                .filter(|t| *t > t0 && *t < t1),
+        );
+        let (mut min, mut max) = (
+            Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        );
+        for time in times {
+            let (p0, p1, p2) = self.vertices_at(time);
+            for p in [p0, p1, p2].iter() {
+                for a in 0..3 {
+                    min[a] = min[a].min(p[a]);
+                    max[a] = max[a].max(p[a]);
+                }
+            }
+        }
+        // Padded so that triangles in an axis plane still have some volume
+        let pad = Vec3::new(0.0001, 0.0001, 0.0001);
+        Some(AABB::new(min - pad, max + pad))
+    }
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        if let Some(hit) = self.hit(Ray::new(*o, *v, 0.0), 0.001, f32::MAX) {
+            let dist_sqrd = hit.t * hit.t * v.mag_sqrd();
+            let cosine = (dot(*v, hit.geometric_normal) / v.mag()).abs();
+            return dist_sqrd / (cosine * self.area());
+        }
+        0.0
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        // Uniform over the area, the square root keeps points from bunching
+        // up at p0
+        let (p0, p1, p2) = self.vertices_at(0.0);
+        let s = rand_float().sqrt();
+        let b1 = s * (1.0 - rand_float());
+        let b2 = s - b1;
+        let random_point = p0 + b1 * (p1 - p0) + b2 * (p2 - p0);
+        random_point - *o
+    }
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, r: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.bvh.hit(r, t_min, t_max)
+    }
+    // The BVH covers every motion key, which holds at any time
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.bvh.bounding_box(t0, t1)
+    }
+    // Triangles are picked in proportion to their area, so the density is
+    // the same as for one triangle covering the whole mesh. A direction can
+    // pass through several of them and each one adds its share.
+    fn pdf_value(&self, o: &Vec3, v: &Vec3) -> f32 {
+        let r = Ray::new(*o, *v, 0.0);
+        let mut t_min = 0.001;
+        let mut pdf = 0.0;
+        while let Some(hit) = self.bvh.hit(r, t_min, f32::MAX) {
+            let dist_sqrd = hit.t * hit.t * v.mag_sqrd();
+            let cosine = (dot(*v, hit.geometric_normal) / v.mag()).abs();
+            pdf += dist_sqrd / (cosine * self.area());
+            t_min = hit.t * (1.0 + 1e-5) + 1e-5;
+        }
+        pdf
+    }
+    fn random(&self, o: &Vec3) -> Vec3 {
+        let target = rand_float() * self.area();
+        let i = self.area_cdf.partition_point(|a| *a < target);
+        self.triangles[i.min(self.triangles.len() - 1)].random(o)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::texture::ConstantTexture;
+
+    #[test]
+    fn triangle_mesh_matches_rect() {
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            Vec3::new(0.5, 0.5, 0.5),
+        ))));
+        let mesh = MeshData {
+            positions: vec![
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::new(2.0, 0.0, 1.0),
+                Vec3::new(2.0, 1.0, 1.0),
+                Vec3::new(0.0, 1.0, 1.0),
+            ],
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices: vec![[0, 1, 2], [0, 2, 3]],
+            motion: Vec::new(),
+        };
+        let mesh = TriangleMesh::new(Arc::new(mesh), mat.clone());
+        let rect = XYRect::new(0.0, 2.0, 0.0, 1.0, 1.0, mat);
+
+        let o = Vec3::new(0.3, 0.2, -1.0);
+        let v = Vec3::new(1.5, 0.6, 1.0) - o;
+        let hit = mesh.hit(Ray::new(o, v, 0.0), 0.001, f32::MAX).unwrap();
+        assert!((hit.p - Vec3::new(1.5, 0.6, 1.0)).mag() < 1e-5);
+        assert!((hit.geometric_normal - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-6);
+        assert!((mesh.pdf_value(&o, &v) - rect.pdf_value(&o, &v)).abs() < 1e-4);
+        assert_eq!(mesh.pdf_value(&o, &Vec3::new(0.0, 0.0, -1.0)), 0.0);
+
+        for _ in 0..100 {
+            let p = o + mesh.random(&o);
+            assert!((p.z() - 1.0).abs() < 1e-5);
+            assert!(p.x() >= -1e-5 && p.x() <= 2.0 + 1e-5 && p.y() >= -1e-5 && p.y() <= 1.0 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn deforming_triangle_follows_its_keys() {
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            Vec3::new(0.5, 0.5, 0.5),
+        ))));
+        let rest = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ];
+        let lifted: Vec<Vec3> = rest.iter().map(|p| *p + Vec3::new(0.0, 0.0, 2.0)).collect();
+        let mesh = MeshData {
+            positions: rest.clone(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices: vec![[0, 1, 2]],
+            motion: vec![(0.0, rest), (1.0, lifted)],
+        };
+        let tri = Triangle::from_mesh(Arc::new(mesh), 0, mat);
+
+        let o = Vec3::new(0.2, 0.2, -1.0);
+        let dir = Vec3::new(0.0, 0.0, 1.0);
+        for (time, z) in [(0.0, 0.0), (0.25, 0.5), (1.0, 2.0), (3.0, 2.0)].iter() {
+            let hit = tri.hit(Ray::new(o, dir, *time), 0.001, f32::MAX).unwrap();
+            assert!((hit.p.z() - z).abs() < 1e-5);
+        }
+        let bbox = tri.bounding_box(0.0, 0.5).unwrap();
+        assert!(bbox.min().z() < 0.0 && (bbox.max().z() - 1.0).abs() < 1e-3);
+    }
+
+    // Keys past the usual shutter still move the whole mesh and its bounds
+    #[test]
+    fn deforming_mesh_is_bounded_past_time_one() {
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            Vec3::new(0.5, 0.5, 0.5),
+        ))));
+        let rest = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.0),
+        ];
+        let lifted: Vec<Vec3> = rest.iter().map(|p| *p + Vec3::new(0.0, 0.0, 4.0)).collect();
+        let mesh = MeshData {
+            positions: rest.clone(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices: vec![[0, 1, 2], [1, 3, 2]],
+            motion: vec![(0.0, rest), (2.0, lifted)],
+        };
+        let mesh = TriangleMesh::new(Arc::new(mesh), mat);
+
+        let o = Vec3::new(0.2, 0.2, -1.0);
+        let dir = Vec3::new(0.0, 0.0, 1.0);
+        for (time, z) in [(1.0, 2.0), (1.5, 3.0), (2.0, 4.0), (5.0, 4.0)].iter() {
+            let hit = mesh.hit(Ray::new(o, dir, *time), 0.001, f32::MAX).unwrap();
+            assert!((hit.p.z() - z).abs() < 1e-5);
+        }
+        let bbox = mesh.bounding_box(1.5, 3.0).unwrap();
+        assert!(bbox.max().z() >= 4.0);
+    }
+
+    // One texture and material serve the whole mesh, blending the corner
+    // colors of whichever face is hit, whatever its texture coordinates
+    #[test]
+    fn vertex_colors_blend_across_each_face() {
+        let mesh = MeshData {
+            positions: vec![
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::new(2.0, 0.0, 1.0),
+                Vec3::new(2.0, 1.0, 1.0),
+                Vec3::new(0.0, 1.0, 1.0),
+            ],
+            normals: Vec::new(),
+            uvs: vec![(1.0, 1.0), (0.0, 1.0), (0.0, 0.0), (1.0, 0.0)],
+            colors: vec![
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ],
+            indices: vec![[0, 1, 2], [0, 2, 3]],
+            motion: Vec::new(),
+        };
+        let mesh = TriangleMesh::with_vertex_colors(Arc::new(mesh));
+        let materials: Vec<_> = mesh.triangles.iter().map(|t| &t.material).collect();
+        assert!(Arc::ptr_eq(materials[0], materials[1]));
+
+        for (x, y, color) in [
+            (1.5, 0.5, Vec3::new(0.25, 0.25, 0.5)),
+            (0.5, 0.75, Vec3::new(0.75, 0.5, 0.75)),
+        ]
+        .iter()
+        {
+            let r = Ray::new(Vec3::new(*x, *y, 0.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+            let hit = mesh.hit(r, 0.001, f32::MAX).unwrap();
+            let albedo = hit.material.scatter(r, &hit).unwrap().attenuation;
+            assert!((albedo - *color).mag() < 1e-5, "{:?}", albedo);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! A Monte Carlo path tracer.
+//!
+//! Scenes are lists of [`Hittable`] objects with a [`Material`] each, seen
+//! through a [`Camera`]. A [`Renderer`] traces them into a film and
+//! [`render`] does the whole thing in one call:
+//!
+//! ```
+//! use shrimpray::{render, scene, Renderer};
+//!
+//! let scene = scene::cornell_box(1.0);
+//! let image = render(&scene, &Renderer::new(8, 8, 1));
+//! assert_eq!(image.pixels.len(), 64);
+//! ```
+
+pub mod bvh;
+pub mod camera;
+pub mod film;
+pub mod gltffile;
+pub mod hit;
+pub mod material;
+pub mod microfacet;
+pub mod obj;
+pub mod objfile;
+pub mod output;
+pub mod pdf;
+pub(crate) mod perlin;
+pub mod plyfile;
+pub mod polarization;
+pub mod render;
+pub mod scene;
+pub mod scenefile;
+pub mod sensor;
+pub(crate) mod spectrum;
+pub mod stlfile;
+pub mod texture;
+pub mod transf;
+pub(crate) mod util;
+pub mod vec3;
+
+pub use bvh::{BvhNode, FlatBvh, AABB};
+pub use camera::Camera;
+pub use film::Film;
+pub use hit::{HitRecord, Hittable};
+pub use material::{Material, ScatterRecord};
+pub use output::Image;
+pub use pdf::Pdf;
+pub use render::{render, Renderer};
+pub use scene::Scene;
+pub use texture::Texture;
+pub use util::{rand_float, seed_rng};
+pub use vec3::{Ray, Vec3};
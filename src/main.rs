@@ -1,118 +1,168 @@
+use std::io::{BufWriter, Write};
 use std::sync::Arc;
 
-use rayon::prelude::*;
+use shrimpray::output::{self, OutputFormat};
+use shrimpray::render::{Renderer, Schedule};
+use shrimpray::sensor::Sensor;
+use shrimpray::{scenefile, Film, Image};
 
-mod vec3;
-use vec3::*;
+mod checkpoint;
+mod cli;
+use cli::*;
 
-mod camera;
-
-mod obj;
-use obj::*;
-
-mod hit;
-use hit::*;
-
-mod bvh;
-
-mod material;
-use material::*;
-
-mod texture;
-use texture::*;
-
-mod util;
-use util::*;
-
-mod perlin;
-
-mod pdf;
-use pdf::*;
-
-mod transf;
-
-mod scene;
-use scene::*;
-
-fn color(r: Ray, world: &Vec<Arc<dyn Hittable>>, lights: &Arc<dyn Hittable>, depth: u32) -> Vec3 {
-    if depth == 0 {
-        return Vec3::new(0.0, 0.0, 0.0);
-    }
-    if let Some(hit) = world.hit(r, 0.001, std::f32::MAX) {
-        let emitted = hit.material.emitted(&r, &hit, hit.u, hit.v, &hit.p);
-        if let Some(s_rec) = hit.material.scatter(r, &hit) {
-            if s_rec.is_specular {
-                return s_rec.attenuation * color(s_rec.specular_ray, world, lights, depth - 1);
-            } else {
-                let plight = HittablePdf::new(lights.clone(), hit.p);
-                let p = MixturePdf::new(Box::new(plight), s_rec.pdf.unwrap());
-
-                let scattered = Ray::new(hit.p, p.generate(), r.time());
-                let pdf_val = p.value(&scattered.direction());
+fn exit_with_error(message: String) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
 
-                return emitted
-                    + s_rec.attenuation
-                        * hit.material.scattering_pdf(&r, &hit, &scattered)
-                        * color(scattered, world, lights, depth - 1)
-                        / pdf_val;
-            }
-        } else {
-            return emitted;
+// Writes the receptors in false color and the polarization if asked to
+fn save_extras(settings: &Settings, image: &Image) -> std::io::Result<()> {
+    for (path, extra) in [
+        (&settings.preview, image.preview()),
+        (&settings.dop, image.dop()),
+        (&settings.aop, image.aop()),
+    ]
+    .iter()
+    {
+        if let (Some(path), Some(extra)) = (path, extra) {
+            // clap has checked the extension
+            let format = OutputFormat::from_path(path).unwrap();
+            output::save_image(path, format, extra)?;
         }
     }
-    Vec3::new(0.0, 0.0, 0.0)
+    Ok(())
 }
 
 fn main() {
-    let (nx, ny, ns) = (500, 500, 1000);
-    println!("P3");
-    println!("{} {}", nx, ny);
-    println!("255");
-
-    let (cam, world) = cornell_mc(ny as f32 / nx as f32);
-
-    let light_shape: Arc<dyn Hittable> = Arc::new(XZRect::new(
-        213.0,
-        343.0,
-        227.0,
-        332.0,
-        554.0,
-        Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
-            Vec3::new(1.0, 1.0, 1.0),
-        )))),
-    ));
-
-    let glass = Arc::new(Dielectric::new(1.5));
-    let glass_sphere: Arc<dyn Hittable> =
-        Arc::new(Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0, glass));
-
-    let lights: Arc<dyn Hittable> = Arc::new(vec![light_shape, glass_sphere]);
-
-    for j in (0..ny).rev() {
-        for i in 0..nx {
-            let mut col: Vec3 = (0..ns)
-                .into_par_iter()
-                .map(|_| {
-                    let u: f32 = (i as f32 + rand_float()) / nx as f32;
-                    let v: f32 = (j as f32 + rand_float()) / ny as f32;
-                    let r = cam.get_ray(u, v);
-                    de_nan(&color(r, &world, &lights, 50))
-                })
-                .sum();
-            col /= ns as f32;
-            col = Vec3::new(col[0].sqrt(), col[1].sqrt(), col[2].sqrt());
+    let settings = cli::parse_args();
+    if let Some(threads) = settings.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("could not start the render threads");
+    }
 
-            let ir: u32 = (256.0 * num::clamp(col[0], 0.0, 0.999)) as u32;
-            let ig: u32 = (256.0 * num::clamp(col[1], 0.0, 0.999)) as u32;
-            let ib: u32 = (256.0 * num::clamp(col[2], 0.0, 0.999)) as u32;
-            println!("{} {} {}", ir, ig, ib)
+    // Scenes with random placement or Perlin noise come out the same for
+    // the same seed, which a resumed render relies on
+    shrimpray::seed_rng(settings.seed as u64);
+    let aspect = settings.width as f32 / settings.height as f32;
+    let (scene, scene_name, scene_hash) = match &settings.scene {
+        SceneSource::Builtin(name, f) => (f(aspect), name.to_string(), String::new()),
+        SceneSource::File(path) => {
+            let fail = |e: &dyn std::fmt::Display| -> ! {
+                exit_with_error(format!("{}: {}", path.display(), e))
+            };
+            let scene = scenefile::load_scene(path, aspect).unwrap_or_else(|e| fail(&e));
+            let hash = checkpoint::file_hash(path).unwrap_or_else(|e| fail(&e));
+            (scene, path.display().to_string(), hash)
+        }
+    };
+    let (sensor, sensor_hash) = match &settings.sensor {
+        Some(path) => {
+            let fail = |e: &dyn std::fmt::Display| -> ! { exit_with_error(e.to_string()) };
+            let sensor = Sensor::load(path).unwrap_or_else(|e| fail(&e));
+            let hash = checkpoint::file_hash(path).unwrap_or_else(|e| fail(&e));
+            (Some(Arc::new(sensor)), hash)
+        }
+        None => (None, String::new()),
+    };
+    let receptors = sensor.as_ref().map_or(0, |s| s.names.len());
+    let header = checkpoint::Header {
+        scene: scene_name,
+        scene_hash,
+        width: settings.width,
+        height: settings.height,
+        samples: settings.samples,
+        max_depth: settings.max_depth,
+        seed: settings.seed,
+        aovs: matches!(settings.format, OutputFormat::Exr(_)),
+        spectral: settings.spectral,
+        sensor: settings
+            .sensor
+            .as_ref()
+            .map_or(String::new(), |p| p.display().to_string()),
+        sensor_hash,
+        receptors,
+        polarized: settings.polarized,
+    };
+    let mut film = if settings.resume {
+        // clap makes --resume require --checkpoint
+        let path = settings.checkpoint.as_ref().unwrap();
+        match checkpoint::load(path).and_then(|(saved, film)| saved.check(&header).map(|_| film)) {
+            Ok(film) => {
+                eprintln!("resuming from {} spp", film.spp());
+                film
+            }
+            Err(e) => exit_with_error(format!("{}: {}", path.display(), e)),
+        }
+    } else {
+        Film::new(settings.width, settings.height, header.aovs)
+            .with_receptors(receptors)
+            .with_polarization(settings.polarized)
+    };
+
+    let in_passes = settings.snapshot_every.is_some() || settings.checkpoint.is_some();
+    let renderer = Renderer {
+        width: settings.width,
+        height: settings.height,
+        samples: settings.samples,
+        pass_samples: if in_passes {
+            settings.pass_samples
+        } else {
+            settings.samples
+        },
+        max_depth: settings.max_depth,
+        tile_size: settings.tile_size,
+        tile_order: settings.tile_order,
+        // A resumed film keeps the buffers it was started with
+        aovs: film.has_aovs(),
+        seed: settings.seed as u64,
+        spectral: settings.spectral,
+        sensor,
+        polarized: settings.polarized,
+    };
+
+    let mut snapshots = settings.snapshot_every.map(Schedule::new);
+    let mut checkpoints = Schedule::new(settings.checkpoint_every);
+    renderer.render(&mut film, &scene, |film| {
+        if let Some(path) = &settings.checkpoint {
+            if checkpoints.due() {
+                if let Err(e) = checkpoint::save(path, &header, film) {
+                    eprintln!("warning: could not save a checkpoint: {}", e);
+                }
+            }
+        }
+        // Snapshots only happen with an output file, which clap enforces
+        if let (Some(schedule), Some(path)) = (snapshots.as_mut(), &settings.output) {
+            if schedule.due() {
+                let image = renderer.image(film);
+                match output::save_image(path, settings.format, &image)
+                    .and_then(|_| save_extras(&settings, &image))
+                {
+                    Ok(()) => eprintln!("{} spp written to {}", image.spp, path.display()),
+                    Err(e) => eprintln!("warning: could not write a snapshot: {}", e),
+                }
+            }
+        }
+    });
+    let image = renderer.image(&film);
+
+    let written = match &settings.output {
+        Some(path) => output::save_image(path, settings.format, &image),
+        None => {
+            let stdout = std::io::stdout();
+            let mut w = BufWriter::new(stdout.lock());
+            output::write_image(&mut w, settings.format, &image).and_then(|_| w.flush())
         }
+    };
+    if let Err(e) = written.and_then(|_| save_extras(&settings, &image)) {
+        exit_with_error(format!("could not write the image: {}", e));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::util::*;
+    use shrimpray::rand_float;
 
     #[test]
     fn mc() {
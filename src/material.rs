@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
 use crate::hit::HitRecord;
+use crate::microfacet::*;
 use crate::pdf::*;
-use crate::texture::Texture;
+use crate::polarization::Mueller;
+use crate::texture::{ConstantTexture, Texture};
 use crate::util::*;
 use crate::vec3::*;
 
@@ -36,9 +38,31 @@ pub trait Material: Sync + Send {
     fn scattering_pdf(&self, _ray_in: &Ray, _hit: &HitRecord, _scattered: &Ray) -> f32 {
         0.0
     }
+    // The BSDF times the cosine towards `scattered`, what light coming back
+    // along it is weighed by. Materials whose color does not change with
+    // the direction leave it as the attenuation times `scattering_pdf`.
+    fn scattering(
+        &self,
+        ray_in: &Ray,
+        hit: &HitRecord,
+        scattered: &Ray,
+        attenuation: Vec3,
+    ) -> Vec3 {
+        attenuation * self.scattering_pdf(ray_in, hit, scattered)
+    }
     fn emitted(&self, _r_in: &Ray, _hit: &HitRecord, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
+    // Sends each wavelength its own way, which `scatter` expects the ray
+    // to have been narrowed down to one for
+    fn dispersive(&self) -> bool {
+        false
+    }
+    // How sending `ray_in` on as `scattered` polarizes light, for the
+    // polarized mode. Without one light comes out unpolarized.
+    fn mueller(&self, _ray_in: &Ray, _hit: &HitRecord, _scattered: &Ray) -> Option<Mueller> {
+        None
+    }
 }
 
 // Diffuse
@@ -54,7 +78,7 @@ impl Lambertian {
 
 impl Material for Lambertian {
     fn scatter(&self, _ray_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
-        let alb = self.albedo.value(hit.u, hit.v, &hit.p);
+        let alb = self.albedo.value_at(hit);
         let pdf = Box::new(CosinePdf::new(&hit.normal));
 
         Some(ScatterRecord::new(Ray::default(), false, alb, Some(pdf)))
@@ -98,15 +122,194 @@ impl Material for Metal {
 
         Some(ScatterRecord::new(scattered, true, attenuation, None))
     }
+    // A complex index of refraction that reflects the albedo head on, with
+    // an edge tint of the albedo too (Gulbrandsen 2014)
+    fn mueller(&self, ray_in: &Ray, _hit: &HitRecord, scattered: &Ray) -> Option<Mueller> {
+        let d = ray_in.direction().unit();
+        // Fuzz tilts the bit of surface that reflects
+        let h = scattered.direction().unit() - d;
+        if h.mag() < 1e-6 {
+            return None;
+        }
+        let mut eta = Vec3::default();
+        let mut k = Vec3::default();
+        for c in 0..3 {
+            let r = self.albedo[c].clamp(0.0, 0.99);
+            let g = r;
+            let n = g * (1.0 - r) / (1.0 + r) + (1.0 - g) * (1.0 + r.sqrt()) / (1.0 - r.sqrt());
+            eta[c] = n;
+            k[c] = ((r * (n + 1.0).powi(2) - (n - 1.0).powi(2)) / (1.0 - r))
+                .max(0.0)
+                .sqrt();
+        }
+        Some(Mueller::fresnel(-dot(d, h.unit()), eta, k, true))
+    }
+}
+
+// Complex indices of refraction (eta, k) of some metals at red, green and
+// blue wavelengths
+pub const GOLD: (Vec3, Vec3) = (
+    Vec3 {
+        e0: 0.143,
+        e1: 0.374,
+        e2: 1.442,
+    },
+    Vec3 {
+        e0: 3.983,
+        e1: 2.386,
+        e2: 1.603,
+    },
+);
+pub const SILVER: (Vec3, Vec3) = (
+    Vec3 {
+        e0: 0.155,
+        e1: 0.117,
+        e2: 0.138,
+    },
+    Vec3 {
+        e0: 4.828,
+        e1: 3.122,
+        e2: 2.147,
+    },
+);
+pub const COPPER: (Vec3, Vec3) = (
+    Vec3 {
+        e0: 0.200,
+        e1: 0.924,
+        e2: 1.102,
+    },
+    Vec3 {
+        e0: 3.912,
+        e1: 2.452,
+        e2: 2.142,
+    },
+);
+pub const ALUMINIUM: (Vec3, Vec3) = (
+    Vec3 {
+        e0: 1.657,
+        e1: 0.880,
+        e2: 0.521,
+    },
+    Vec3 {
+        e0: 9.224,
+        e1: 6.270,
+        e2: 4.837,
+    },
+);
+
+// A rough metal, a GGX distribution of mirror microfacets with the
+// Fresnel reflectance of a conductor. Unlike `Metal` it keeps energy in
+// check and is sampled through a pdf, so it takes part in light sampling.
+pub struct Conductor {
+    eta: Vec3,
+    k: Vec3,
+    ggx: Ggx,
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, roughness: f32) -> Self {
+        Self::anisotropic(eta, k, roughness, roughness)
+    }
+    // Rougher along the surface tangent or across it, for brushed metal
+    pub fn anisotropic(eta: Vec3, k: Vec3, roughness_u: f32, roughness_v: f32) -> Self {
+        Self {
+            eta,
+            k,
+            ggx: Ggx::new(roughness_u, roughness_v),
+        }
+    }
+    fn fresnel(&self, cosine: f32) -> Vec3 {
+        Vec3::new(
+            fresnel_conductor(cosine, self.eta.x(), self.k.x()),
+            fresnel_conductor(cosine, self.eta.y(), self.k.y()),
+            fresnel_conductor(cosine, self.eta.z(), self.k.z()),
+        )
+    }
+    // The shading frame on the side the ray came from, metals are opaque
+    fn frame(&self, ray_in: &Ray, hit: &HitRecord) -> Onb {
+        let mut normal = hit.normal;
+        if dot(ray_in.direction(), normal) > 0.0 {
+            normal = -1.0 * normal;
+        }
+        let mut uvw = Onb::new();
+        uvw.build_from_w_u(&normal, &hit.tangent);
+        uvw
+    }
+}
+
+impl Material for Conductor {
+    // The attenuation is the color seen head on, the actual reflectance
+    // comes from `scattering`
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let uvw = self.frame(&ray_in, hit);
+        let pdf = Box::new(GgxPdf::new(uvw, &(-1.0 * ray_in.direction()), self.ggx));
+        Some(ScatterRecord::new(
+            Ray::default(),
+            false,
+            self.fresnel(1.0),
+            Some(pdf),
+        ))
+    }
+    fn scattering(&self, ray_in: &Ray, hit: &HitRecord, scattered: &Ray, _: Vec3) -> Vec3 {
+        let uvw = self.frame(ray_in, hit);
+        let wo = uvw.coordinates(&(-1.0 * ray_in.direction()).unit());
+        let wi = uvw.coordinates(&scattered.direction().unit());
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let h = (wo + wi).unit();
+        let f = self.ggx.d(&h) * self.ggx.g(&wo, &wi) / (4.0 * wo.z());
+        f * self.fresnel(dot(wo, h))
+    }
+}
+
+// Index of refraction over wavelength
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Ior {
+    Constant(f32),
+    // n = a + b / λ², with λ in micrometers
+    Cauchy(f32, f32),
+    // n² = 1 + Σ b λ² / (λ² - c), with λ in micrometers
+    Sellmeier([f32; 3], [f32; 3]),
+}
+
+impl Ior {
+    pub const BK7: Ior = Ior::Sellmeier(
+        [1.039_612, 0.231_792_34, 1.010_469_5],
+        [0.006_000_699, 0.020_017_914, 103.560_65],
+    );
+    pub const FUSED_SILICA: Ior = Ior::Sellmeier(
+        [0.696_166_3, 0.407_942_6, 0.897_479_4],
+        [0.004_679_148, 0.013_512_063, 97.934],
+    );
+    pub const DIAMOND: Ior = Ior::Sellmeier([4.3356, 0.3306, 0.0], [0.011_236, 0.030_625, 0.0]);
+
+    // At `wavelength` in nanometers, or at the yellow sodium line glasses
+    // are usually given for
+    pub fn at(&self, wavelength: Option<f32>) -> f32 {
+        let lambda = wavelength.unwrap_or(587.6) / 1000.0;
+        let l2 = lambda * lambda;
+        match self {
+            Ior::Constant(n) => *n,
+            Ior::Cauchy(a, b) => a + b / l2,
+            Ior::Sellmeier(b, c) => {
+                let sum: f32 = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
 }
 
 pub struct Dielectric {
-    ref_idx: f32,
+    ior: Ior,
 }
 
 impl Dielectric {
     pub fn new(ri: f32) -> Self {
-        Self { ref_idx: ri }
+        Self::with_ior(Ior::Constant(ri))
+    }
+    pub fn with_ior(ior: Ior) -> Self {
+        Self { ior }
     }
 }
 
@@ -115,14 +318,15 @@ impl Material for Dielectric {
         let outward_normal: Vec3;
         let ni_over_nt: f32;
         let cosine: f32;
+        let ref_idx = self.ior.at(ray_in.wavelength);
 
         if dot(ray_in.direction(), hit.normal) > 0.0 {
             outward_normal = -1.0 * hit.normal;
-            ni_over_nt = self.ref_idx;
-            cosine = self.ref_idx * dot(ray_in.direction(), hit.normal) / ray_in.direction().mag();
+            ni_over_nt = ref_idx;
+            cosine = ref_idx * dot(ray_in.direction(), hit.normal) / ray_in.direction().mag();
         } else {
             outward_normal = hit.normal;
-            ni_over_nt = 1.0 / self.ref_idx;
+            ni_over_nt = 1.0 / ref_idx;
             cosine = -1.0 * dot(ray_in.direction(), hit.normal) / ray_in.direction().mag();
         }
 
@@ -130,7 +334,7 @@ impl Material for Dielectric {
         let attenuation = Vec3::new(1.0, 1.0, 1.0);
 
         if let Some(refracted) = refract(ray_in.direction(), outward_normal, ni_over_nt) {
-            let refract_prob = 1.0 - schlick(cosine, self.ref_idx);
+            let refract_prob = 1.0 - schlick(cosine, ref_idx);
             if rand_float() < refract_prob {
                 let scattering = Ray::new(hit.p, refracted, ray_in.time());
                 return Some(ScatterRecord::new(scattering, true, attenuation, None));
@@ -139,6 +343,294 @@ impl Material for Dielectric {
         let scattering = Ray::new(hit.p, reflected, ray_in.time());
         Some(ScatterRecord::new(scattering, true, attenuation, None))
     }
+    fn dispersive(&self) -> bool {
+        !matches!(self.ior, Ior::Constant(_))
+    }
+    fn mueller(&self, ray_in: &Ray, hit: &HitRecord, scattered: &Ray) -> Option<Mueller> {
+        let d = ray_in.direction().unit();
+        let cos_d = dot(d, hit.normal);
+        let ref_idx = self.ior.at(ray_in.wavelength);
+        let eta = if cos_d < 0.0 { ref_idx } else { 1.0 / ref_idx };
+        let reflected = (dot(scattered.direction(), hit.normal) > 0.0) == (cos_d < 0.0);
+        Some(Mueller::fresnel(
+            cos_d.abs(),
+            Vec3::new(eta, eta, eta),
+            Vec3::default(),
+            reflected,
+        ))
+    }
+}
+
+// Frosted glass, a GGX distribution of microfacets that each reflect and
+// refract like a smooth dielectric. Tinted glass
+// absorbs light on its way through, following Beer-Lambert.
+pub struct RoughDielectric {
+    ref_idx: f32,
+    ggx: Ggx,
+    // Per unit of distance travelled inside
+    absorption: Vec3,
+}
+
+impl RoughDielectric {
+    pub fn new(ref_idx: f32, roughness: f32) -> Self {
+        Self {
+            ref_idx,
+            ggx: Ggx::new(roughness, roughness),
+            absorption: Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+    // Lets through `tint` of the light that goes `distance` through it
+    pub fn tinted(ref_idx: f32, roughness: f32, tint: Vec3, distance: f32) -> Self {
+        let absorb = |t: f32| -t.max(1e-6).ln() / distance;
+        Self {
+            absorption: Vec3::new(absorb(tint.x()), absorb(tint.y()), absorb(tint.z())),
+            ..Self::new(ref_idx, roughness)
+        }
+    }
+    fn frame(&self, hit: &HitRecord) -> Onb {
+        let mut uvw = Onb::new();
+        uvw.build_from_w_u(&hit.normal, &hit.tangent);
+        uvw
+    }
+}
+
+impl Material for RoughDielectric {
+    // A ray leaving the glass has come from where it went in, so the
+    // attenuation is what it lost on the way
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let mut attenuation = Vec3::new(1.0, 1.0, 1.0);
+        if dot(ray_in.direction(), hit.geometric_normal) > 0.0 {
+            let distance = hit.t * ray_in.direction().mag();
+            for a in 0..3 {
+                attenuation[a] = (-self.absorption[a] * distance).exp();
+            }
+        }
+        let pdf = Box::new(RoughDielectricPdf::new(
+            self.frame(hit),
+            &(-1.0 * ray_in.direction()),
+            self.ggx,
+            self.ref_idx,
+        ));
+        Some(ScatterRecord::new(
+            Ray::default(),
+            false,
+            attenuation,
+            Some(pdf),
+        ))
+    }
+    fn scattering(
+        &self,
+        ray_in: &Ray,
+        hit: &HitRecord,
+        scattered: &Ray,
+        attenuation: Vec3,
+    ) -> Vec3 {
+        let uvw = self.frame(hit);
+        let wo = uvw.coordinates(&(-1.0 * ray_in.direction()).unit());
+        let wi = uvw.coordinates(&scattered.direction().unit());
+        rough_dielectric(&self.ggx, self.ref_idx, &wo, &wi) * attenuation
+    }
+}
+
+// One material for most surfaces, after Disney's principled BSDF (Burley
+// 2012 and 2015): a diffuse base with sheen, a GGX specular layer that
+// turns into a conductor as it gets metallic, rough glass for transmission
+// and a clearcoat on top. Every parameter is a texture, with numbers taken
+// as the average of its channels, and most run from 0 to 1.
+pub struct Principled {
+    pub base_color: Arc<dyn Texture>,
+    pub metallic: Arc<dyn Texture>,
+    pub roughness: Arc<dyn Texture>,
+    // Reflectance head on, with 1 at 8 percent
+    pub specular: Arc<dyn Texture>,
+    // How much the specular layer takes the base color
+    pub specular_tint: Arc<dyn Texture>,
+    // Soft grazing highlights, for cloth
+    pub sheen: Arc<dyn Texture>,
+    pub clearcoat: Arc<dyn Texture>,
+    pub transmission: Arc<dyn Texture>,
+    pub ior: Arc<dyn Texture>,
+}
+
+// The parameters looked up at one hit
+struct Surface {
+    base_color: Vec3,
+    metallic: f32,
+    roughness: f32,
+    specular: f32,
+    specular_tint: f32,
+    sheen: f32,
+    clearcoat: f32,
+    transmission: f32,
+    ior: f32,
+}
+
+impl Surface {
+    // The base color with its brightness taken out
+    fn hue(&self) -> Vec3 {
+        let c = self.base_color;
+        let luminance = 0.3 * c.x() + 0.6 * c.y() + 0.1 * c.z();
+        if luminance > 0.0 {
+            c / luminance
+        } else {
+            Vec3::new(1.0, 1.0, 1.0)
+        }
+    }
+    fn diffuse_weight(&self) -> f32 {
+        (1.0 - self.metallic) * (1.0 - self.transmission)
+    }
+    fn specular_weight(&self) -> f32 {
+        1.0 - (1.0 - self.metallic) * self.transmission
+    }
+    fn glass_weight(&self) -> f32 {
+        (1.0 - self.metallic) * self.transmission
+    }
+    // Disney's clearcoat is a quarter as strong as the rest
+    fn clearcoat_weight(&self) -> f32 {
+        0.25 * self.clearcoat
+    }
+    fn ggx(&self) -> Ggx {
+        Ggx::new(self.roughness, self.roughness)
+    }
+    // Glass takes the base color a little going in and again going out
+    fn glass_tint(&self) -> Vec3 {
+        let c = self.base_color;
+        Vec3::new(c.x().sqrt(), c.y().sqrt(), c.z().sqrt())
+    }
+}
+
+// Schlick's approximation of how much more is reflected away from head on
+fn schlick_weight(cosine: f32) -> f32 {
+    (1.0 - cosine).clamp(0.0, 1.0).powi(5)
+}
+
+fn lerp(a: Vec3, b: Vec3, f: f32) -> Vec3 {
+    (1.0 - f) * a + f * b
+}
+
+impl Principled {
+    // Rough white plastic in the given color
+    pub fn new(base_color: Arc<dyn Texture>) -> Self {
+        let constant = |x: f32| Arc::new(ConstantTexture::new(Vec3::new(x, x, x)));
+        Self {
+            base_color,
+            metallic: constant(0.0),
+            roughness: constant(0.5),
+            specular: constant(0.5),
+            specular_tint: constant(0.0),
+            sheen: constant(0.0),
+            clearcoat: constant(0.0),
+            transmission: constant(0.0),
+            ior: constant(1.5),
+        }
+    }
+    fn surface(&self, hit: &HitRecord) -> Surface {
+        let scalar = |t: &Arc<dyn Texture>| {
+            let v = t.value_at(hit);
+            (v.x() + v.y() + v.z()) / 3.0
+        };
+        let fraction = |t: &Arc<dyn Texture>| scalar(t).clamp(0.0, 1.0);
+        Surface {
+            base_color: self.base_color.value_at(hit),
+            metallic: fraction(&self.metallic),
+            roughness: fraction(&self.roughness),
+            specular: scalar(&self.specular).max(0.0),
+            specular_tint: fraction(&self.specular_tint),
+            sheen: scalar(&self.sheen).max(0.0),
+            clearcoat: scalar(&self.clearcoat).max(0.0),
+            transmission: fraction(&self.transmission),
+            ior: scalar(&self.ior).max(1.0 + 1e-3),
+        }
+    }
+    // The normal points out of the surface so glass knows which way is in
+    fn frame(&self, hit: &HitRecord) -> Onb {
+        let mut uvw = Onb::new();
+        uvw.build_from_w_u(&hit.normal, &hit.tangent);
+        uvw
+    }
+    fn clearcoat_ggx() -> Ggx {
+        Ggx::new(0.25, 0.25)
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
+        let s = self.surface(hit);
+        let uvw = self.frame(hit);
+        let wo = -1.0 * ray_in.direction();
+        let glass = Box::new(RoughDielectricPdf::new(uvw.clone(), &wo, s.ggx(), s.ior));
+        let pdf: Box<dyn Pdf> = if dot(wo, hit.normal) <= 0.0 {
+            // From inside only the glass is left to go through
+            glass
+        } else {
+            let mut lobes: Vec<(f32, Box<dyn Pdf>)> = vec![
+                (s.diffuse_weight(), Box::new(CosinePdf::new(&hit.normal))),
+                (
+                    s.specular_weight(),
+                    Box::new(GgxPdf::new(uvw.clone(), &wo, s.ggx())),
+                ),
+                (s.glass_weight(), glass),
+                (
+                    s.clearcoat_weight(),
+                    Box::new(GgxPdf::new(uvw, &wo, Self::clearcoat_ggx())),
+                ),
+            ];
+            lobes.retain(|(w, _)| *w > 0.0);
+            Box::new(MixturePdf::weighted(lobes))
+        };
+        Some(ScatterRecord::new(
+            Ray::default(),
+            false,
+            s.base_color,
+            Some(pdf),
+        ))
+    }
+    fn scattering(&self, ray_in: &Ray, hit: &HitRecord, scattered: &Ray, _: Vec3) -> Vec3 {
+        let s = self.surface(hit);
+        let uvw = self.frame(hit);
+        let wo = uvw.coordinates(&(-1.0 * ray_in.direction()).unit());
+        let wi = uvw.coordinates(&scattered.direction().unit());
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let glass = rough_dielectric(&s.ggx(), s.ior, &wo, &wi);
+        let through = if wo.z() * wi.z() < 0.0 {
+            s.glass_tint()
+        } else {
+            white
+        };
+        if wo.z() <= 0.0 {
+            return glass * through;
+        }
+        let mut f = s.glass_weight() * glass * through;
+        if wi.z() <= 0.0 {
+            return f;
+        }
+
+        let h = (wo + wi).unit();
+        let cos_d = dot(wi, h);
+        let hue = s.hue();
+
+        // Diffuse, brighter towards grazing angles the rougher it gets,
+        // with the sheen halfway to the base color
+        let fd90 = 0.5 + 2.0 * s.roughness * cos_d * cos_d;
+        let fd = (1.0 + (fd90 - 1.0) * schlick_weight(wo.z()))
+            * (1.0 + (fd90 - 1.0) * schlick_weight(wi.z()));
+        let sheen = s.sheen * schlick_weight(cos_d) * lerp(white, hue, 0.5);
+        f += s.diffuse_weight() * wi.z() * (fd / std::f32::consts::PI * s.base_color + sheen);
+
+        // Specular, reflecting the base color once metallic
+        let dielectric = 0.08 * s.specular * lerp(white, hue, s.specular_tint);
+        let f0 = lerp(dielectric, s.base_color, s.metallic);
+        let fresnel = f0 + schlick_weight(dot(wo, h)) * (white - f0);
+        let ggx = s.ggx();
+        let spec = ggx.d(&h) * ggx.g(&wo, &wi) / (4.0 * wo.z());
+        f += s.specular_weight() * spec * fresnel;
+
+        // A clear varnish with the reflectance of index 1.5
+        let coat = Self::clearcoat_ggx();
+        let coat_fresnel = 0.04 + 0.96 * schlick_weight(dot(wo, h));
+        let clear = coat.d(&h) * coat.g(&wo, &wi) / (4.0 * wo.z());
+        f + s.clearcoat_weight() * clear * coat_fresnel * white
+    }
 }
 
 pub struct DiffuseLight {
@@ -175,8 +667,115 @@ impl Isotropic {
 
 impl Material for Isotropic {
     fn scatter(&self, _ray_in: Ray, hit: &HitRecord) -> Option<ScatterRecord> {
-        let scattered = Ray::new(hit.p, random_in_unit_sphere(), hit.t);
-        let attenuation = self.albedo.value(hit.u, hit.v, &hit.p);
-        Some(ScatterRecord::new(scattered, false, attenuation, None))
+        let attenuation = self.albedo.value_at(hit);
+        let pdf = Box::new(SpherePdf);
+        Some(ScatterRecord::new(
+            Ray::default(),
+            false,
+            attenuation,
+            Some(pdf),
+        ))
+    }
+    fn scattering_pdf(&self, _: &Ray, _: &HitRecord, _: &Ray) -> f32 {
+        1.0 / (4.0 * std::f32::consts::PI)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glass_bends_blue_more_than_red() {
+        assert!((Ior::BK7.at(None) - 1.5168).abs() < 1e-3);
+        assert!((Ior::DIAMOND.at(None) - 2.417).abs() < 2e-3);
+        for ior in [
+            Ior::BK7,
+            Ior::FUSED_SILICA,
+            Ior::DIAMOND,
+            Ior::Cauchy(1.5, 0.004),
+        ]
+        .iter()
+        {
+            assert!(ior.at(Some(450.0)) > ior.at(Some(650.0)));
+            assert!(Dielectric::with_ior(*ior).dispersive());
+        }
+        assert!(!Dielectric::new(1.5).dispersive());
+    }
+
+    // The light scattered towards -dir off a surface facing +z, estimated
+    // from samples, f cos / pdf, and by integrating f cos over the sphere
+    fn sampled_and_integrated(mat: Arc<dyn Material>, dir: &Vec3) -> (f32, f32) {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let hit = HitRecord::new(1.0, Vec3::new(0.0, 0.0, 0.0), normal, 0.0, 0.0, mat.clone());
+        let ray_in = Ray::new(-1.0 * *dir, *dir, 0.0);
+        let s_rec = mat.scatter(ray_in, &hit).unwrap();
+        let pdf = s_rec.pdf.unwrap();
+        let n = 100_000;
+        let mut sampled = 0.0;
+        for _ in 0..n {
+            let v = pdf.generate();
+            if v.mag_sqrd() > 0.0 {
+                let f = mat.scattering(&ray_in, &hit, &Ray::new(hit.p, v, 0.0), s_rec.attenuation);
+                sampled += f.x() / pdf.value(&v);
+            }
+        }
+        sampled /= n as f32;
+
+        let steps = 600;
+        let mut integral = 0.0;
+        for i in 0..steps {
+            for j in 0..steps {
+                let cos_theta = -1.0 + 2.0 * (i as f32 + 0.5) / steps as f32;
+                let phi = 2.0 * std::f32::consts::PI * (j as f32 + 0.5) / steps as f32;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let v = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                let f = mat.scattering(&ray_in, &hit, &Ray::new(hit.p, v, 0.0), s_rec.attenuation);
+                integral += f.x() * 4.0 * std::f32::consts::PI / (steps * steps) as f32;
+            }
+        }
+        (sampled, integral)
+    }
+
+    // From outside and from inside
+    #[test]
+    fn rough_glass_samples_match_its_bsdf() {
+        seed_rng(9);
+        let glass: Arc<dyn Material> = Arc::new(RoughDielectric::new(1.5, 0.5));
+        for dir in [Vec3::new(0.5, 0.2, -0.8), Vec3::new(-0.3, 0.1, 0.9)].iter() {
+            let (sampled, integral) = sampled_and_integrated(glass.clone(), dir);
+            assert!(
+                (sampled - integral).abs() < 0.02 * integral,
+                "sampled {} integrated {}",
+                sampled,
+                integral
+            );
+        }
+    }
+
+    // Every lobe at once, and glass seen from inside
+    #[test]
+    fn principled_samples_match_its_bsdf() {
+        seed_rng(10);
+        let constant = |x: f32| Arc::new(ConstantTexture::new(Vec3::new(x, x, x)));
+        let mut mat = Principled::new(Arc::new(ConstantTexture::new(Vec3::new(0.8, 0.5, 0.3))));
+        mat.metallic = constant(0.3);
+        mat.roughness = constant(0.4);
+        mat.sheen = constant(0.5);
+        mat.clearcoat = constant(1.0);
+        mat.transmission = constant(0.5);
+        let mat: Arc<dyn Material> = Arc::new(mat);
+        for dir in [Vec3::new(0.5, 0.2, -0.8), Vec3::new(-0.3, 0.1, 0.9)].iter() {
+            let (sampled, integral) = sampled_and_integrated(mat.clone(), dir);
+            assert!(
+                (sampled - integral).abs() < 0.02 * integral,
+                "sampled {} integrated {}",
+                sampled,
+                integral
+            );
+            if dir.z() < 0.0 {
+                assert!(integral < 1.0, "scatters {}", integral);
+            }
+        }
     }
 }
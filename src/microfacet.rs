@@ -0,0 +1,229 @@
+// GGX (Trowbridge-Reitz) microfacets with Smith masking-shadowing.
+//
+// Directions are in a shading frame with the normal along z and the
+// surface tangent along x, and point away from the surface. Microfacet
+// normals always face +z, and are seen from below through dielectrics.
+
+use crate::util::*;
+use crate::vec3::*;
+
+#[derive(Clone, Copy)]
+pub struct Ggx {
+    pub alpha_x: f32,
+    pub alpha_y: f32,
+}
+
+impl Ggx {
+    // Roughness along the tangent and across it, squared to get alpha so
+    // that it looks about as rough as it says. Nearly smooth surfaces are
+    // kept just rough enough for the numbers to hold up.
+    pub fn new(roughness_x: f32, roughness_y: f32) -> Self {
+        Self {
+            alpha_x: (roughness_x * roughness_x).max(1e-3),
+            alpha_y: (roughness_y * roughness_y).max(1e-3),
+        }
+    }
+    // Density of microfacet normals over the projected area
+    pub fn d(&self, h: &Vec3) -> f32 {
+        if h.z() <= 0.0 {
+            return 0.0;
+        }
+        let x = h.x() / self.alpha_x;
+        let y = h.y() / self.alpha_y;
+        let s = x * x + y * y + h.z() * h.z();
+        1.0 / (std::f32::consts::PI * self.alpha_x * self.alpha_y * s * s)
+    }
+    fn lambda(&self, w: &Vec3) -> f32 {
+        let x = self.alpha_x * w.x();
+        let y = self.alpha_y * w.y();
+        let tan2 = (x * x + y * y) / (w.z() * w.z());
+        0.5 * ((1.0 + tan2).sqrt() - 1.0)
+    }
+    // Fraction of the microfacets facing w that are not hidden by others
+    pub fn g1(&self, w: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+    // Masking and shadowing together, correlated by height
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+    // Density of the normals visible from wo
+    pub fn visible_pdf(&self, wo: &Vec3, h: &Vec3) -> f32 {
+        let cosine = dot(*wo, *h);
+        if cosine * wo.z() <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * (cosine / wo.z()).abs() * self.d(h)
+    }
+    // A normal visible from wo, picked in proportion to `visible_pdf`. The
+    // view is stretched so the microfacets become a hemisphere, a point is
+    // picked on its projected disk and the result stretched back.
+    pub fn sample_visible(&self, wo: &Vec3) -> Vec3 {
+        let side = wo.z().signum();
+        let v = side * Vec3::new(self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()).unit();
+        let len_sqrd = v.x() * v.x() + v.y() * v.y();
+        let t1 = if len_sqrd > 0.0 {
+            Vec3::new(-v.y(), v.x(), 0.0) / len_sqrd.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = cross(v, t1);
+
+        let r = rand_float().sqrt();
+        let phi = 2.0 * std::f32::consts::PI * rand_float();
+        let p1 = r * phi.cos();
+        // Squeezed onto the part of the disk that faces wo
+        let s = 0.5 * (1.0 + v.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+        Vec3::new(self.alpha_x * n.x(), self.alpha_y * n.y(), n.z().max(1e-6)).unit()
+    }
+}
+
+// Reflectance of a conductor with complex index of refraction eta + ik,
+// for unpolarized light at an angle with cosine `cos_i`
+pub fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rs + rp)
+}
+
+// Reflectance of a dielectric with relative index of refraction eta, the
+// one inside over the one outside. A negative `cos_i` comes from inside.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let (cos_i, eta) = if cos_i < 0.0 {
+        (-cos_i, 1.0 / eta)
+    } else {
+        (cos_i, eta)
+    };
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+// wo bent through a microfacet with normal h into a dielectric with
+// relative index of refraction eta, or out of it if wo is below
+pub fn refract_through(wo: &Vec3, h: &Vec3, eta: f32) -> Option<Vec3> {
+    let mut cos_i = dot(*wo, *h);
+    let (mut n, mut eta) = (*h, eta);
+    if cos_i < 0.0 {
+        eta = 1.0 / eta;
+        cos_i = -cos_i;
+        n = -1.0 * n;
+    }
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-1.0 * *wo / eta + (cos_i / eta - cos_t) * n)
+}
+
+// The microfacet normal that takes wo to wi through a dielectric, by
+// reflection when both are on the same side and refraction otherwise,
+// with the ratio of indices of refraction across it (1 for reflection).
+// None if either direction is on the back of that microfacet.
+pub fn dielectric_half(wo: &Vec3, wi: &Vec3, eta: f32) -> Option<(Vec3, f32)> {
+    let (cos_o, cos_i) = (wo.z(), wi.z());
+    if cos_o == 0.0 || cos_i == 0.0 {
+        return None;
+    }
+    let etap = if cos_o * cos_i > 0.0 {
+        1.0
+    } else if cos_o > 0.0 {
+        eta
+    } else {
+        1.0 / eta
+    };
+    let h = etap * *wi + *wo;
+    if h.mag_sqrd() == 0.0 {
+        return None;
+    }
+    let h = h.z().signum() * h.unit();
+    if dot(h, *wi) * cos_i <= 0.0 || dot(h, *wo) * cos_o <= 0.0 {
+        return None;
+    }
+    Some((h, etap))
+}
+
+// The BSDF of a rough dielectric times the cosine towards wi, reflecting
+// and refracting through microfacets (Walter et al. 2007)
+pub fn rough_dielectric(ggx: &Ggx, eta: f32, wo: &Vec3, wi: &Vec3) -> f32 {
+    let (h, etap) = match dielectric_half(wo, wi, eta) {
+        Some(half) => half,
+        None => return 0.0,
+    };
+    let reflectance = fresnel_dielectric(dot(*wo, h), eta);
+    let dg = ggx.d(&h) * ggx.g(wo, wi);
+    if wo.z() * wi.z() > 0.0 {
+        reflectance * dg / (4.0 * wo.z().abs())
+    } else {
+        // Radiance is squeezed into a narrower cone going into the denser
+        // side, by etap squared
+        let denom = dot(*wi, h) + dot(*wo, h) / etap;
+        (1.0 - reflectance) * dg * (dot(*wi, h) * dot(*wo, h)).abs()
+            / (denom * denom * wo.z().abs() * etap * etap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visible_normals_follow_their_density() {
+        seed_rng(3);
+        let ggx = Ggx::new(0.5, 0.8);
+        let wo = Vec3::new(0.4, -0.3, 0.6).unit();
+        // Sampled reflections average to what integrating the density says,
+        // E[f] = ∫ f p, for an f that picks out one quadrant
+        let n = 200_000;
+        let quadrant = |h: &Vec3| h.x() > 0.0 && h.y() > 0.0;
+        let hits = (0..n)
+            .filter(|_| quadrant(&ggx.sample_visible(&wo)))
+            .count();
+        let sampled = hits as f32 / n as f32;
+
+        let steps = 400;
+        let mut integral = 0.0;
+        let mut total = 0.0;
+        for i in 0..steps {
+            for j in 0..steps {
+                // Midpoints over the hemisphere in cos theta and phi
+                let cos_theta = (i as f32 + 0.5) / steps as f32;
+                let phi = 2.0 * std::f32::consts::PI * (j as f32 + 0.5) / steps as f32;
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let h = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                let weight =
+                    ggx.visible_pdf(&wo, &h) * 2.0 * std::f32::consts::PI / (steps * steps) as f32;
+                total += weight;
+                if quadrant(&h) {
+                    integral += weight;
+                }
+            }
+        }
+        assert!((total - 1.0).abs() < 0.01, "density sums to {}", total);
+        assert!((sampled - integral).abs() < 0.01);
+
+        // Gold reflects more red than blue and everything at grazing angles
+        let red = fresnel_conductor(1.0, 0.143, 3.983);
+        let blue = fresnel_conductor(1.0, 1.442, 1.603);
+        assert!(red > 0.9 && blue < 0.5);
+        assert!(fresnel_conductor(1e-3, 1.442, 1.603) > 0.99);
+    }
+}
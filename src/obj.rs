@@ -1,10 +1,12 @@
 use std::sync::Arc;
 
+use crate::bvh::FlatBvh;
 use crate::hit::*;
 use crate::material::*;
 use crate::texture::*;
 use crate::transf::*;
-use crate::vec3::Vec3;
+use crate::util::key_span;
+use crate::vec3::{cross, Vec3};
 
 pub struct Sphere {
     pub center: Vec3,
@@ -210,3 +212,157 @@ impl ConstantMedium {
         }
     }
 }
+
+// Vertex buffers shared by the triangles of a mesh. `normals`, `uvs` and
+// `colors` are either empty or indexed like `positions`.
+//
+// `motion` holds (time, positions) keys sorted by time for deformation
+// blur, with vertices moving in a straight line from one key to the next.
+// Lights are sampled in their shape at time 0 and the vertex normals are
+// not animated.
+pub struct MeshData {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub colors: Vec<Vec3>,
+    pub indices: Vec<[u32; 3]>,
+    pub motion: Vec<(f32, Vec<Vec3>)>,
+}
+
+pub struct Triangle {
+    pub mesh: Arc<MeshData>,
+    pub face: usize,
+    pub material: Arc<dyn Material>,
+}
+
+impl Triangle {
+    // A triangle on its own, with UVs from the barycentric coordinates
+    pub fn new(p0: Vec3, p1: Vec3, p2: Vec3, material: Arc<dyn Material>) -> Self {
+        let mesh = MeshData {
+            positions: vec![p0, p1, p2],
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices: vec![[0, 1, 2]],
+            motion: Vec::new(),
+        };
+        Self::from_mesh(Arc::new(mesh), 0, material)
+    }
+    pub fn from_mesh(mesh: Arc<MeshData>, face: usize, material: Arc<dyn Material>) -> Self {
+        Self {
+            mesh,
+            face,
+            material,
+        }
+    }
+    pub fn indices(&self) -> [usize; 3] {
+        let [a, b, c] = self.mesh.indices[self.face];
+        [a as usize, b as usize, c as usize]
+    }
+    pub fn vertices(&self) -> (Vec3, Vec3, Vec3) {
+        let [a, b, c] = self.indices();
+        let p = &self.mesh.positions;
+        (p[a], p[b], p[c])
+    }
+    // The corners at `time`, the same as `vertices` without motion keys
+    pub fn vertices_at(&self, time: f32) -> (Vec3, Vec3, Vec3) {
+        let motion = &self.mesh.motion;
+        if motion.is_empty() {
+            return self.vertices();
+        }
+        let [a, b, c] = self.indices();
+        let (k0, k1, f) = key_span(motion, time);
+        let (p0, p1) = (&motion[k0].1, &motion[k1].1);
+        let at = |i: usize| (1.0 - f) * p0[i] + f * p1[i];
+        (at(a), at(b), at(c))
+    }
+    pub fn area(&self) -> f32 {
+        let (p0, p1, p2) = self.vertices_at(0.0);
+        0.5 * cross(p1 - p0, p2 - p0).mag()
+    }
+}
+
+pub struct TriangleMesh {
+    pub triangles: Vec<Arc<Triangle>>,
+    pub bvh: FlatBvh,
+    // Running total of the triangle areas, for picking one in proportion
+    // to its area when the mesh is sampled as a light
+    pub area_cdf: Vec<f32>,
+}
+
+impl TriangleMesh {
+    pub fn new(mesh: Arc<MeshData>, material: Arc<dyn Material>) -> Self {
+        let materials = vec![material; mesh.indices.len()];
+        Self::with_materials(mesh, materials)
+    }
+    // Lambertian with the colors of the mesh blended across each face
+    pub fn with_vertex_colors(mesh: Arc<MeshData>) -> Self {
+        assert_eq!(mesh.colors.len(), mesh.positions.len());
+        let albedo = Arc::new(VertexColorTexture::new(mesh.clone()));
+        Self::new(mesh, Arc::new(Lambertian::new(albedo)))
+    }
+    // One material per face
+    pub fn with_materials(mesh: Arc<MeshData>, materials: Vec<Arc<dyn Material>>) -> Self {
+        assert!(
+            !mesh.indices.is_empty(),
+            "a triangle mesh needs at least one face"
+        );
+        assert_eq!(materials.len(), mesh.indices.len());
+        let triangles: Vec<Arc<Triangle>> = materials
+            .into_iter()
+            .enumerate()
+            .map(|(face, material)| Arc::new(Triangle::from_mesh(mesh.clone(), face, material)))
+            .collect();
+
+        let mut total = 0.0;
+        let area_cdf = triangles
+            .iter()
+            .map(|tri| {
+                total += tri.area();
+                total
+            })
+            .collect();
+
+        let list: Vec<Arc<dyn Hittable>> = triangles
+            .iter()
+            .map(|tri| tri.clone() as Arc<dyn Hittable>)
+            .collect();
+        // Vertices hold still outside their keys, so a BVH over all of them
+        // bounds the mesh at any time
+        let (time0, time1) = mesh
+            .motion
+            .iter()
+            .fold((0.0f32, 1.0f32), |(t0, t1), (time, _)| {
+                (t0.min(*time), t1.max(*time))
+            });
+        let bvh = FlatBvh::new(&list, time0, time1);
+        Self {
+            triangles,
+            bvh,
+            area_cdf,
+        }
+    }
+    // The same faces and materials with the vertices moving through
+    // `motion`, (time, positions) keys in any order
+    pub fn with_motion(&self, mut motion: Vec<(f32, Vec<Vec3>)>) -> Self {
+        let mesh = &self.triangles[0].mesh;
+        assert!(
+            motion.iter().all(|(_, p)| p.len() == mesh.positions.len()),
+            "every motion key needs a position for each vertex"
+        );
+        motion.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let moving = MeshData {
+            positions: mesh.positions.clone(),
+            normals: mesh.normals.clone(),
+            uvs: mesh.uvs.clone(),
+            colors: mesh.colors.clone(),
+            indices: mesh.indices.clone(),
+            motion,
+        };
+        let materials = self.triangles.iter().map(|t| t.material.clone()).collect();
+        Self::with_materials(Arc::new(moving), materials)
+    }
+    pub fn area(&self) -> f32 {
+        *self.area_cdf.last().unwrap()
+    }
+}
@@ -0,0 +1,461 @@
+// Wavefront OBJ meshes and their MTL materials.
+//
+// OBJ: `v`, `vt`, `vn`, `f` with any number of corners (fan triangulated)
+// and negative indices, `g`/`o` to start a group, `usemtl` and `mtllib`.
+// MTL: `newmtl`, `Kd`, `Ks`, `Ns`, `Ni`, `Ke`, `d`, `Tr`, `illum` and
+// `map_Kd`. Everything else, like smoothing groups, is ignored.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::material::*;
+use crate::obj::{MeshData, TriangleMesh};
+use crate::texture::*;
+use crate::vec3::Vec3;
+
+#[derive(Debug)]
+pub enum MeshError {
+    Io(PathBuf, std::io::Error),
+    // Problems in binary data, which has no lines to point at
+    Invalid(PathBuf, String),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            MeshError::Invalid(path, message) => write!(f, "{}: {}", path.display(), message),
+            MeshError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+// The faces of one `g` or `o` statement
+pub struct ObjGroup {
+    pub name: String,
+    pub mesh: TriangleMesh,
+}
+
+// Reads through a file a line at a time, keeping track of where it is for
+// error messages
+struct Lines<'a> {
+    path: &'a Path,
+    line: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn error<T>(&self, message: String) -> Result<T, MeshError> {
+        Err(MeshError::Parse {
+            path: self.path.to_path_buf(),
+            line: self.line,
+            message,
+        })
+    }
+
+    fn float(&self, token: Option<&str>, what: &str) -> Result<f32, MeshError> {
+        match token {
+            Some(t) => match t.parse() {
+                Ok(x) => Ok(x),
+                Err(_) => self.error(format!("expected a number for {}, found `{}`", what, t)),
+            },
+            None => self.error(format!("missing {}", what)),
+        }
+    }
+
+    fn vec3<'t, I: Iterator<Item = &'t str>>(&self, tokens: &mut I) -> Result<Vec3, MeshError> {
+        Ok(Vec3::new(
+            self.float(tokens.next(), "x")?,
+            self.float(tokens.next(), "y")?,
+            self.float(tokens.next(), "z")?,
+        ))
+    }
+
+    fn rest<'t, I: Iterator<Item = &'t str>>(
+        &self,
+        tokens: I,
+        what: &str,
+    ) -> Result<String, MeshError> {
+        let rest = tokens.collect::<Vec<_>>().join(" ");
+        if rest.is_empty() {
+            return self.error(format!("missing {}", what));
+        }
+        Ok(rest)
+    }
+}
+
+fn read(path: &Path) -> Result<String, MeshError> {
+    std::fs::read_to_string(path).map_err(|e| MeshError::Io(path.to_path_buf(), e))
+}
+
+// Lines with comments stripped, numbered from one
+fn statements(src: &str) -> impl Iterator<Item = (usize, &str)> {
+    src.lines().enumerate().map(|(i, line)| {
+        let line = match line.find('#') {
+            Some(end) => &line[..end],
+            None => line,
+        };
+        (i + 1, line.trim())
+    })
+}
+
+struct MtlDesc {
+    kd: Vec3,
+    ks: Vec3,
+    ke: Vec3,
+    ns: Option<f32>,
+    ni: f32,
+    opacity: f32,
+    illum: u32,
+    map_kd: Option<Arc<dyn Texture>>,
+}
+
+impl Default for MtlDesc {
+    fn default() -> Self {
+        Self {
+            kd: Vec3::new(0.8, 0.8, 0.8),
+            ks: Vec3::default(),
+            ke: Vec3::default(),
+            ns: None,
+            ni: 1.5,
+            opacity: 1.0,
+            illum: 2,
+            map_kd: None,
+        }
+    }
+}
+
+fn max_component(v: Vec3) -> f32 {
+    v.x().max(v.y()).max(v.z())
+}
+
+// MTL describes a Phong style material, pick whichever of ours is closest:
+// emissive is a light, transparent is glass, mostly specular is a metal
+// and anything else is diffuse.
+fn to_material(desc: MtlDesc) -> Arc<dyn Material> {
+    if max_component(desc.ke) > 0.0 {
+        return Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(desc.ke))));
+    }
+    // illum 4, 6, 7 and 9 are the refraction and glass models
+    if desc.opacity < 1.0 || [4, 6, 7, 9].contains(&desc.illum) {
+        return Arc::new(Dielectric::new(desc.ni));
+    }
+    if desc.map_kd.is_none() && max_component(desc.ks) > max_component(desc.kd) {
+        // Blinn-Phong exponent to a roughness, a mirror if not given
+        let fuzz = desc.ns.map_or(0.0, |ns| (2.0 / (ns + 2.0)).sqrt());
+        return Arc::new(Metal::new(desc.ks, fuzz));
+    }
+    match desc.map_kd {
+        Some(tex) => Arc::new(Lambertian::new(tex)),
+        None => Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(desc.kd)))),
+    }
+}
+
+// Texture paths are relative to `path`
+pub fn parse_mtl(src: &str, path: &Path) -> Result<HashMap<String, Arc<dyn Material>>, MeshError> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut lines = Lines { path, line: 0 };
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlDesc)> = None;
+
+    for (line, statement) in statements(src) {
+        lines.line = line;
+        let mut tokens = statement.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(k) => k,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            if let Some((name, desc)) = current.take() {
+                materials.insert(name, to_material(desc));
+            }
+            current = Some((lines.rest(tokens, "material name")?, MtlDesc::default()));
+            continue;
+        }
+
+        let desc = match current.as_mut() {
+            Some((_, desc)) => desc,
+            None if ["Kd", "Ks", "Ke", "Ns", "Ni", "d", "Tr", "illum", "map_Kd"]
+                .contains(&keyword) =>
+            {
+                return lines.error(format!("`{}` before any `newmtl`", keyword))
+            }
+            None => continue,
+        };
+        match keyword {
+            "Kd" => desc.kd = lines.vec3(&mut tokens)?,
+            "Ks" => desc.ks = lines.vec3(&mut tokens)?,
+            "Ke" => desc.ke = lines.vec3(&mut tokens)?,
+            "Ns" => desc.ns = Some(lines.float(tokens.next(), "Ns")?),
+            "Ni" => desc.ni = lines.float(tokens.next(), "Ni")?,
+            "d" => desc.opacity = lines.float(tokens.next(), "d")?,
+            "Tr" => desc.opacity = 1.0 - lines.float(tokens.next(), "Tr")?,
+            "illum" => {
+                desc.illum = match tokens.next().map(|t| t.parse()) {
+                    Some(Ok(n)) => n,
+                    _ => return lines.error("expected an illumination model number".to_string()),
+                }
+            }
+            "map_Kd" => {
+                // Options like `-s 1 1 1` come before the file name, which
+                // is all we use
+                let file = match tokens.last() {
+                    Some(file) => file,
+                    None => return lines.error("missing texture file".to_string()),
+                };
+                match ImageTexture::open(&dir.join(file)) {
+                    Ok(tex) => desc.map_kd = Some(Arc::new(tex)),
+                    Err(e) => return lines.error(format!("could not load `{}`: {}", file, e)),
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Some((name, desc)) = current {
+        materials.insert(name, to_material(desc));
+    }
+    Ok(materials)
+}
+
+pub fn load_mtl(path: &Path) -> Result<HashMap<String, Arc<dyn Material>>, MeshError> {
+    parse_mtl(&read(path)?, path)
+}
+
+// A corner of a face as indices into the position, texcoord and normal lists
+type Corner = (usize, Option<usize>, Option<usize>);
+
+struct GroupBuilder {
+    name: String,
+    corners: Vec<[Corner; 3]>,
+    materials: Vec<Arc<dyn Material>>,
+}
+
+impl GroupBuilder {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            corners: Vec::new(),
+            materials: Vec::new(),
+        }
+    }
+
+    // Vertices used by the group with their own index buffer. Normals are
+    // only kept if every corner has one, since interpolating towards a
+    // missing normal would be meaningless.
+    fn build(self, positions: &[Vec3], uvs: &[(f32, f32)], normals: &[Vec3]) -> ObjGroup {
+        let all_normals = self.corners.iter().flatten().all(|c| c.2.is_some());
+        let any_uvs = self.corners.iter().flatten().any(|c| c.1.is_some());
+        let mut mesh = MeshData {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            colors: Vec::new(),
+            indices: Vec::new(),
+            motion: Vec::new(),
+        };
+        let mut vertices: HashMap<Corner, u32> = HashMap::new();
+        for face in self.corners.iter() {
+            let mut tri = [0; 3];
+            for (k, corner) in face.iter().enumerate() {
+                let key = (
+                    corner.0,
+                    if any_uvs { corner.1 } else { None },
+                    if all_normals { corner.2 } else { None },
+                );
+                tri[k] = *vertices.entry(key).or_insert_with(|| {
+                    mesh.positions.push(positions[corner.0]);
+                    if any_uvs {
+                        mesh.uvs.push(corner.1.map_or((0.0, 0.0), |t| uvs[t]));
+                    }
+                    if all_normals {
+                        mesh.normals.push(normals[corner.2.unwrap()]);
+                    }
+                    mesh.positions.len() as u32 - 1
+                });
+            }
+            mesh.indices.push(tri);
+        }
+        ObjGroup {
+            name: self.name,
+            mesh: TriangleMesh::with_materials(Arc::new(mesh), self.materials),
+        }
+    }
+}
+
+// Resolves a one based or negative relative index against `len` items
+fn resolve(lines: &Lines, index: &str, len: usize, what: &str) -> Result<usize, MeshError> {
+    let i: i64 = match index.parse() {
+        Ok(i) => i,
+        Err(_) => return lines.error(format!("expected a {} index, found `{}`", what, index)),
+    };
+    let resolved = if i < 0 { len as i64 + i } else { i - 1 };
+    if i == 0 || resolved < 0 || resolved >= len as i64 {
+        return lines.error(format!(
+            "{} index {} is out of range, there are {}",
+            what, i, len
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+// Material library paths are relative to `path`, faces without a material
+// get `default_material`
+pub fn parse_obj(
+    src: &str,
+    path: &Path,
+    default_material: Arc<dyn Material>,
+) -> Result<Vec<ObjGroup>, MeshError> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut lines = Lines { path, line: 0 };
+    let mut positions: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<(f32, f32)> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut library: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    let mut material = default_material;
+    let mut groups = vec![GroupBuilder::new("default".to_string())];
+
+    for (line, statement) in statements(src) {
+        lines.line = line;
+        let mut tokens = statement.split_whitespace();
+        match tokens.next() {
+            Some("v") => positions.push(lines.vec3(&mut tokens)?),
+            Some("vt") => {
+                let u = lines.float(tokens.next(), "u")?;
+                let v = match tokens.next() {
+                    Some(t) => lines.float(Some(t), "v")?,
+                    None => 0.0,
+                };
+                uvs.push((u, v));
+            }
+            Some("vn") => normals.push(lines.vec3(&mut tokens)?),
+            Some("f") => {
+                let mut corners = Vec::new();
+                for token in tokens {
+                    let mut parts = token.split('/');
+                    let v = resolve(&lines, parts.next().unwrap(), positions.len(), "vertex")?;
+                    let vt = match parts.next() {
+                        Some("") | None => None,
+                        Some(t) => Some(resolve(&lines, t, uvs.len(), "texture coordinate")?),
+                    };
+                    let vn = match parts.next() {
+                        Some("") | None => None,
+                        Some(n) => Some(resolve(&lines, n, normals.len(), "normal")?),
+                    };
+                    corners.push((v, vt, vn));
+                }
+                if corners.len() < 3 {
+                    return lines.error(format!(
+                        "a face needs at least 3 vertices, this one has {}",
+                        corners.len()
+                    ));
+                }
+                let group = groups.last_mut().unwrap();
+                for i in 1..corners.len() - 1 {
+                    group.corners.push([corners[0], corners[i], corners[i + 1]]);
+                    group.materials.push(material.clone());
+                }
+            }
+            Some("g") | Some("o") => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                groups.push(GroupBuilder::new(name));
+            }
+            Some("usemtl") => {
+                let name = lines.rest(tokens, "material name")?;
+                material = match library.get(&name) {
+                    Some(m) => m.clone(),
+                    None => return lines.error(format!("unknown material `{}`", name)),
+                };
+            }
+            Some("mtllib") => {
+                for file in tokens {
+                    library.extend(load_mtl(&dir.join(file))?);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let groups: Vec<ObjGroup> = groups
+        .into_iter()
+        .filter(|g| !g.corners.is_empty())
+        .map(|g| g.build(&positions, &uvs, &normals))
+        .collect();
+    if groups.is_empty() {
+        return Err(MeshError::Parse {
+            path: path.to_path_buf(),
+            line: lines.line,
+            message: "no faces".to_string(),
+        });
+    }
+    Ok(groups)
+}
+
+pub fn load_obj(
+    path: &Path,
+    default_material: Arc<dyn Material>,
+) -> Result<Vec<ObjGroup>, MeshError> {
+    parse_obj(&read(path)?, path, default_material)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::Hittable;
+    use crate::vec3::Ray;
+
+    fn grey() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
+            0.5, 0.5, 0.5,
+        )))))
+    }
+
+    #[test]
+    fn quad_with_groups() {
+        let src = "# a quad split in two groups\n\
+                   v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+                   vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+                   vn 0 0 1\n\
+                   g first\n\
+                   f 1/1/1 2/2/1 3/3/1 4/4/1\n\
+                   g second\n\
+                   f -4 -2 -1\n";
+        let groups = parse_obj(src, Path::new("quad.obj"), grey()).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].name, "first");
+        assert_eq!(groups[0].mesh.triangles.len(), 2);
+        assert_eq!(groups[1].mesh.triangles[0].mesh.normals.len(), 0);
+
+        let r = Ray::new(Vec3::new(0.75, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = groups[0].mesh.hit(r, 0.001, f32::MAX).unwrap();
+        assert!((hit.u - 0.75).abs() < 1e-5 && (hit.v - 0.25).abs() < 1e-5);
+        assert!((hit.normal - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-5);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let bad = [
+            ("v 0 0 0\nv 1 0 0\nv 1 1 nope\n", 3),
+            ("v 0 0 0\nv 1 0 0\nv 1 1 0\n\nf 1 2 4\n", 5),
+            ("v 0 0 0\nv 1 0 0\nf 1 2\n", 3),
+            ("v 0 0 0\nusemtl missing\n", 2),
+        ];
+        for (src, line) in bad.iter() {
+            match parse_obj(src, Path::new("bad.obj"), grey()) {
+                Err(MeshError::Parse { line: l, .. }) => assert_eq!(l, *line, "{}", src),
+                _ => panic!("expected an error for {:?}", src),
+            }
+        }
+    }
+}
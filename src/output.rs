@@ -0,0 +1,488 @@
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+
+use exr::prelude as openexr;
+use image::hdr::HDREncoder;
+use image::Rgb;
+use openexr::{
+    AnyChannel, AnyChannels, AttributeValue, Encoding, FlatSamples, LayerAttributes, SmallVec,
+    Text, WritableImage,
+};
+
+use crate::vec3::Vec3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Precision {
+    Half,
+    Full,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    Ppm,
+    Hdr,
+    // Precision of the color channels, depth is always full float
+    Exr(Precision),
+}
+
+// Buffers besides the beauty pass, from the first hit of each camera ray.
+// Only OpenEXR can store them.
+pub struct Aovs {
+    pub albedo: Vec<Vec3>,
+    pub normal: Vec<Vec3>,
+    pub depth: Vec<f32>,
+}
+
+// What each receptor of a `Sensor` saw, `values` holding one per receptor
+// for each pixel, and their false colors. Only OpenEXR can store the
+// values, as a layer per receptor.
+pub struct Receptors {
+    pub names: Vec<String>,
+    pub values: Vec<f32>,
+    pub preview: Vec<Vec3>,
+}
+
+// The polarization of each pixel per channel, from the mean Stokes vectors
+// whose S0 is the image itself. `aop` is the angle from the horizontal in
+// radians, from 0 to pi. Only OpenEXR can store the Stokes vectors.
+pub struct Polarization {
+    pub s1: Vec<Vec3>,
+    pub s2: Vec<Vec3>,
+    pub s3: Vec<Vec3>,
+    pub dop: Vec<Vec3>,
+    pub aop: Vec<Vec3>,
+}
+
+// A finished or partial render ready to be written. `pixels` holds linear
+// radiance in rows from top to bottom, `spp` is the number of samples each
+// pixel has received so far and goes into the file metadata.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3>,
+    pub aovs: Option<Aovs>,
+    pub receptors: Option<Receptors>,
+    pub polarization: Option<Polarization>,
+    pub spp: u32,
+}
+
+impl Image {
+    // Another image of the same size with just `pixels`
+    fn with_pixels(&self, pixels: Vec<Vec3>) -> Image {
+        Image {
+            width: self.width,
+            height: self.height,
+            pixels,
+            aovs: None,
+            receptors: None,
+            polarization: None,
+            spp: self.spp,
+        }
+    }
+
+    // The false color preview of the receptors as an image of its own
+    pub fn preview(&self) -> Option<Image> {
+        self.receptors
+            .as_ref()
+            .map(|receptors| self.with_pixels(receptors.preview.clone()))
+    }
+
+    // The degree of polarization as an image, from 0 to 1
+    pub fn dop(&self) -> Option<Image> {
+        self.polarization
+            .as_ref()
+            .map(|polarization| self.with_pixels(polarization.dop.clone()))
+    }
+
+    // The angle of polarization as an image, from 0 for horizontal to 1 for
+    // horizontal again after half a turn
+    pub fn aop(&self) -> Option<Image> {
+        self.polarization.as_ref().map(|polarization| {
+            let pixels = polarization
+                .aop
+                .iter()
+                .map(|a| *a / std::f32::consts::PI)
+                .collect();
+            self.with_pixels(pixels)
+        })
+    }
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Option<OutputFormat> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "png" => Some(OutputFormat::Png),
+            "ppm" => Some(OutputFormat::Ppm),
+            "hdr" => Some(OutputFormat::Hdr),
+            "exr" => Some(OutputFormat::Exr(Precision::Full)),
+            _ => None,
+        }
+    }
+}
+
+// Gamma 2 and clamp to 8 bits, for formats that can't hold radiance
+pub fn to_rgb8(col: Vec3) -> [u8; 3] {
+    let col = Vec3::new(col[0].sqrt(), col[1].sqrt(), col[2].sqrt());
+    [
+        (256.0 * num::clamp(col[0], 0.0, 0.999)) as u8,
+        (256.0 * num::clamp(col[1], 0.0, 0.999)) as u8,
+        (256.0 * num::clamp(col[2], 0.0, 0.999)) as u8,
+    ]
+}
+
+fn rgb8_buffer(pixels: &[Vec3]) -> Vec<u8> {
+    pixels.iter().flat_map(|p| to_rgb8(*p).to_vec()).collect()
+}
+
+fn exr_channel(name: &str, values: Vec<f32>, precision: Precision) -> AnyChannel<FlatSamples> {
+    let samples = match precision {
+        Precision::Half => {
+            FlatSamples::F16(values.into_iter().map(openexr::f16::from_f32).collect())
+        }
+        Precision::Full => FlatSamples::F32(values),
+    };
+    AnyChannel::new(name, samples)
+}
+
+fn exr_rgb(
+    channels: &mut Vec<AnyChannel<FlatSamples>>,
+    names: [&str; 3],
+    values: &[Vec3],
+    precision: Precision,
+) {
+    for (c, name) in names.iter().enumerate() {
+        let plane = values.iter().map(|p| p[c as u32]).collect();
+        channels.push(exr_channel(name, plane, precision));
+    }
+}
+
+// The beauty pass goes in the unnamed R, G, B channels and the AOVs in
+// `albedo.*`, `normal.*` and `depth.Z`, the layer naming compositors expect.
+// Receptors get a `<name>.Y` layer each and their preview `preview.*`,
+// polarization `S1.*`, `S2.*`, `S3.*`, `dop.*` and `aop.*`.
+fn write_exr<W: Write>(mut w: W, precision: Precision, image: &Image) -> std::io::Result<()> {
+    let mut channels = Vec::new();
+    exr_rgb(&mut channels, ["R", "G", "B"], &image.pixels, precision);
+    if let Some(aovs) = &image.aovs {
+        exr_rgb(
+            &mut channels,
+            ["albedo.R", "albedo.G", "albedo.B"],
+            &aovs.albedo,
+            precision,
+        );
+        exr_rgb(
+            &mut channels,
+            ["normal.X", "normal.Y", "normal.Z"],
+            &aovs.normal,
+            precision,
+        );
+        channels.push(exr_channel("depth.Z", aovs.depth.clone(), Precision::Full));
+    }
+    if let Some(receptors) = &image.receptors {
+        let n = receptors.names.len();
+        for (k, name) in receptors.names.iter().enumerate() {
+            let plane = receptors
+                .values
+                .iter()
+                .skip(k)
+                .step_by(n)
+                .cloned()
+                .collect();
+            channels.push(exr_channel(&format!("{}.Y", name), plane, precision));
+        }
+        exr_rgb(
+            &mut channels,
+            ["preview.R", "preview.G", "preview.B"],
+            &receptors.preview,
+            precision,
+        );
+    }
+    if let Some(polarization) = &image.polarization {
+        for (layer, values) in [
+            ("S1", &polarization.s1),
+            ("S2", &polarization.s2),
+            ("S3", &polarization.s3),
+            ("dop", &polarization.dop),
+            ("aop", &polarization.aop),
+        ]
+        .iter()
+        {
+            let names = ["R", "G", "B"].map(|c| format!("{}.{}", layer, c));
+            exr_rgb(
+                &mut channels,
+                [&names[0], &names[1], &names[2]],
+                values,
+                precision,
+            );
+        }
+    }
+
+    let mut attributes = LayerAttributes::named("shrimpray");
+    attributes
+        .other
+        .insert(Text::from("spp"), AttributeValue::I32(image.spp as i32));
+    let layer = openexr::Layer::new(
+        (image.width as usize, image.height as usize),
+        attributes,
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(SmallVec::from_vec(channels)),
+    );
+
+    // exr needs to seek while writing, so encode into memory first
+    let mut buf = Cursor::new(Vec::new());
+    openexr::Image::from_layer(layer)
+        .write()
+        .to_buffered(&mut buf)
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    w.write_all(&buf.into_inner())
+}
+
+fn write_png<W: Write>(w: W, image: &Image) -> std::io::Result<()> {
+    let mut encoder = png::Encoder::new(w, image.width, image.height);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    // tEXt is a keyword and a value separated by a null byte
+    writer.write_chunk(*b"tEXt", format!("spp\0{}", image.spp).as_bytes())?;
+    writer.write_image_data(&rgb8_buffer(&image.pixels))?;
+    Ok(())
+}
+
+fn write_ppm<W: Write>(mut w: W, image: &Image) -> std::io::Result<()> {
+    write!(
+        w,
+        "P6\n# spp {}\n{} {}\n255\n",
+        image.spp, image.width, image.height
+    )?;
+    w.write_all(&rgb8_buffer(&image.pixels))
+}
+
+fn write_hdr<W: Write>(mut w: W, image: &Image) -> std::io::Result<()> {
+    let data: Vec<Rgb<f32>> = image
+        .pixels
+        .iter()
+        .map(|p| Rgb([p[0], p[1], p[2]]))
+        .collect();
+    let mut buf = Vec::new();
+    HDREncoder::new(&mut buf).encode(&data, image.width as usize, image.height as usize)?;
+
+    // The encoder has no way to add header variables, so put ours right
+    // after the `#?RADIANCE` signature line
+    let signature = buf.iter().position(|b| *b == b'\n').unwrap() + 1;
+    w.write_all(&buf[..signature])?;
+    writeln!(w, "SPP={}", image.spp)?;
+    w.write_all(&buf[signature..])
+}
+
+pub fn write_image<W: Write>(w: W, format: OutputFormat, image: &Image) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Png => write_png(w, image),
+        OutputFormat::Ppm => write_ppm(w, image),
+        OutputFormat::Hdr => write_hdr(w, image),
+        OutputFormat::Exr(precision) => write_exr(w, precision, image),
+    }
+}
+
+// Writes to a temporary file next to `path` and renames it into place, so
+// nobody ever picks up a partial file, be it a viewer watching a
+// progressive render or a resume after a crash
+pub fn save_atomically<F>(path: &Path, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+{
+    let mut partial = PathBuf::from(path).into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let mut w = BufWriter::new(File::create(&partial)?);
+    write(&mut w)?;
+    w.flush()?;
+    drop(w);
+    std::fs::rename(&partial, path)
+}
+
+pub fn save_image(path: &Path, format: OutputFormat, image: &Image) -> std::io::Result<()> {
+    save_atomically(path, |w| write_image(w, format, image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openexr::{ReadChannels, ReadLayers};
+
+    fn read_channels(bytes: Vec<u8>) -> (Vec<(String, Vec<f32>)>, Option<AttributeValue>) {
+        let image = openexr::read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .all_channels()
+            .first_valid_layer()
+            .all_attributes()
+            .from_buffered(Cursor::new(bytes))
+            .unwrap();
+        assert_eq!(image.layer_data.size, openexr::Vec2(2, 1));
+        let channels = image
+            .layer_data
+            .channel_data
+            .list
+            .iter()
+            .map(|c| (c.name.to_string(), c.sample_data.values_as_f32().collect()))
+            .collect();
+        let spp = image.layer_data.attributes.other.get(&Text::from("spp"));
+        (channels, spp.cloned())
+    }
+
+    #[test]
+    fn exr_round_trip() {
+        let image = Image {
+            width: 2,
+            height: 1,
+            pixels: vec![Vec3::new(0.5, 2.0, 100.0), Vec3::new(0.0, 0.25, 1.5)],
+            aovs: Some(Aovs {
+                albedo: vec![Vec3::new(0.1, 0.2, 0.3), Vec3::new(0.4, 0.5, 0.6)],
+                normal: vec![Vec3::new(0.0, 1.0, 0.0), Vec3::new(-1.0, 0.0, 0.0)],
+                depth: vec![12.345678, f32::INFINITY],
+            }),
+            receptors: Some(Receptors {
+                names: vec!["uv".to_string(), "red".to_string()],
+                values: vec![0.5, 1.0, 2.0, 3.0],
+                preview: vec![Vec3::new(1.0, 0.5, 0.25), Vec3::new(0.0, 0.5, 1.0)],
+            }),
+            polarization: Some(Polarization {
+                s1: vec![Vec3::new(0.1, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0)],
+                s2: vec![Vec3::new(0.0, 0.2, 0.0), Vec3::new(0.0, 0.0, 0.0)],
+                s3: vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.5)],
+                dop: vec![Vec3::new(0.2, 0.1, 0.0), Vec3::new(0.0, 0.0, 0.5)],
+                aop: vec![Vec3::new(0.0, 0.785, 0.0), Vec3::new(0.0, 0.0, 0.0)],
+            }),
+            spp: 64,
+        };
+
+        for precision in [Precision::Full, Precision::Half].iter() {
+            let mut bytes = Vec::new();
+            write_image(&mut bytes, OutputFormat::Exr(*precision), &image).unwrap();
+            let (channels, spp) = read_channels(bytes);
+            assert_eq!(spp, Some(AttributeValue::I32(64)));
+
+            let tolerance = match precision {
+                Precision::Full => 0.0,
+                Precision::Half => 0.05,
+            };
+            let expect = |name: &str, values: [f32; 2]| {
+                let (_, read) = channels.iter().find(|(n, _)| n == name).unwrap();
+                for (a, b) in read.iter().zip(values.iter()) {
+                    assert!(
+                        (a - b).abs() <= tolerance * b.abs(),
+                        "{}: {} != {}",
+                        name,
+                        a,
+                        b
+                    );
+                }
+            };
+            expect("R", [0.5, 0.0]);
+            expect("G", [2.0, 0.25]);
+            expect("B", [100.0, 1.5]);
+            expect("albedo.B", [0.3, 0.6]);
+            expect("normal.X", [0.0, -1.0]);
+            expect("normal.Y", [1.0, 0.0]);
+
+            // Depth keeps full precision either way
+            let (_, depth) = channels.iter().find(|(n, _)| n == "depth.Z").unwrap();
+            assert_eq!(depth[0], 12.345678);
+            assert!(depth[1].is_infinite());
+            expect("uv.Y", [0.5, 2.0]);
+            expect("red.Y", [1.0, 3.0]);
+            expect("preview.B", [0.25, 1.0]);
+            expect("S3.B", [0.0, 0.5]);
+            expect("aop.G", [0.785, 0.0]);
+            assert_eq!(channels.len(), 30);
+        }
+    }
+
+    #[test]
+    fn spp_in_metadata() {
+        let image = Image {
+            width: 1,
+            height: 1,
+            pixels: vec![Vec3::new(0.5, 0.5, 0.5)],
+            aovs: None,
+            receptors: None,
+            polarization: None,
+            spp: 12,
+        };
+
+        let mut ppm = Vec::new();
+        write_image(&mut ppm, OutputFormat::Ppm, &image).unwrap();
+        assert!(ppm.starts_with(b"P6\n# spp 12\n1 1\n255\n"));
+
+        let mut png = Vec::new();
+        write_image(&mut png, OutputFormat::Png, &image).unwrap();
+        assert!(png.windows(8).any(|w| w == b"tEXtspp\0"));
+
+        let mut hdr = Vec::new();
+        write_image(&mut hdr, OutputFormat::Hdr, &image).unwrap();
+        let decoder = image::hdr::HDRDecoder::new(Cursor::new(hdr)).unwrap();
+        let attributes = decoder.metadata().custom_attributes;
+        assert!(attributes.contains(&("SPP".to_string(), "12".to_string())));
+    }
+
+    // Two pixels, one brighter than any 8 bit format can hold
+    fn bright() -> Image {
+        Image {
+            width: 2,
+            height: 1,
+            pixels: vec![Vec3::new(0.5, 2.0, 100.0), Vec3::new(0.0, 0.25, 1.0)],
+            aovs: None,
+            receptors: None,
+            polarization: None,
+            spp: 1,
+        }
+    }
+
+    #[test]
+    fn radiance_above_one_survives() {
+        let mut hdr = Vec::new();
+        write_image(&mut hdr, OutputFormat::Hdr, &bright()).unwrap();
+        let pixels = image::hdr::HDRDecoder::new(Cursor::new(hdr))
+            .unwrap()
+            .read_image_hdr()
+            .unwrap();
+        assert_eq!(pixels.len(), 2);
+        // RGBE shares one exponent between the channels of a pixel, so the
+        // dimmer ones are only kept to within a step of the brightest
+        let [_, g, b] = pixels[0].0;
+        assert!(
+            (b - 100.0).abs() < 1.0 && (g - 2.0).abs() < 0.5,
+            "{} {}",
+            g,
+            b
+        );
+
+        let mut exr = Vec::new();
+        write_image(&mut exr, OutputFormat::Exr(Precision::Full), &bright()).unwrap();
+        let (channels, _) = read_channels(exr);
+        let (_, blue) = channels.iter().find(|(n, _)| n == "B").unwrap();
+        assert_eq!(blue[0], 100.0);
+    }
+
+    #[test]
+    fn png_and_ppm_are_clamped_to_eight_bits() {
+        let mut ppm = Vec::new();
+        write_image(&mut ppm, OutputFormat::Ppm, &bright()).unwrap();
+        let header = b"P6\n# spp 1\n2 1\n255\n";
+        assert!(ppm.starts_with(header));
+        assert_eq!(&ppm[header.len()..], &[181, 255, 255, 0, 128, 255]);
+
+        let mut png = Vec::new();
+        write_image(&mut png, OutputFormat::Png, &bright()).unwrap();
+        let (info, mut reader) = png::Decoder::new(Cursor::new(png)).read_info().unwrap();
+        assert_eq!((info.width, info.height), (2, 1));
+        assert_eq!(info.color_type, png::ColorType::RGB);
+        let mut pixels = vec![0; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(pixels, ppm[header.len()..]);
+    }
+}
@@ -1,9 +1,12 @@
 use std::sync::Arc;
 
 use crate::hit::*;
+use crate::microfacet::*;
 use crate::util::*;
 use crate::vec3::*;
 
+// `generate` gives a zero vector when it fails to find a direction, which
+// counts as the path ending there
 pub trait Pdf {
     fn value(&self, direction: &Vec3) -> f32;
     fn generate(&self) -> Vec3;
@@ -34,6 +37,104 @@ impl Pdf for CosinePdf {
     }
 }
 
+// Uniform over all directions, the phase function of an isotropic medium
+pub struct SpherePdf;
+
+impl Pdf for SpherePdf {
+    fn value(&self, _direction: &Vec3) -> f32 {
+        1.0 / (4.0 * std::f32::consts::PI)
+    }
+    fn generate(&self) -> Vec3 {
+        random_unit_vector()
+    }
+}
+
+// Mirror reflections off the GGX microfacets seen from `wo`, given in the
+// shading frame `uvw`. Only the visible microfacets are sampled, so
+// directions the surface would shadow are seldom picked.
+pub struct GgxPdf {
+    uvw: Onb,
+    wo: Vec3,
+    ggx: Ggx,
+}
+
+impl GgxPdf {
+    pub fn new(uvw: Onb, wo: &Vec3, ggx: Ggx) -> Self {
+        let wo = uvw.coordinates(&wo.unit());
+        Self { uvw, wo, ggx }
+    }
+}
+
+impl Pdf for GgxPdf {
+    fn value(&self, direction: &Vec3) -> f32 {
+        let wi = self.uvw.coordinates(&direction.unit());
+        if wi.z() <= 0.0 || self.wo.z() <= 0.0 {
+            return 0.0;
+        }
+        // Picking the half vector h spreads over 4 (wo.h) as much solid
+        // angle once reflected
+        let h = (self.wo + wi).unit();
+        self.ggx.visible_pdf(&self.wo, &h) / (4.0 * dot(self.wo, h))
+    }
+    fn generate(&self) -> Vec3 {
+        let h = self.ggx.sample_visible(&self.wo);
+        let wi = reflect(-1.0 * self.wo, h);
+        if wi.z() <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        self.uvw.local_vector(&wi)
+    }
+}
+
+// Reflection and refraction through the GGX microfacets of a dielectric
+// with relative index of refraction `eta`, seen from `wo` on either side.
+// Reflection is picked with the Fresnel reflectance of the microfacet.
+pub struct RoughDielectricPdf {
+    uvw: Onb,
+    wo: Vec3,
+    ggx: Ggx,
+    eta: f32,
+}
+
+impl RoughDielectricPdf {
+    pub fn new(uvw: Onb, wo: &Vec3, ggx: Ggx, eta: f32) -> Self {
+        let wo = uvw.coordinates(&wo.unit());
+        Self { uvw, wo, ggx, eta }
+    }
+}
+
+impl Pdf for RoughDielectricPdf {
+    fn value(&self, direction: &Vec3) -> f32 {
+        let wi = self.uvw.coordinates(&direction.unit());
+        let (h, etap) = match dielectric_half(&self.wo, &wi, self.eta) {
+            Some(half) => half,
+            None => return 0.0,
+        };
+        let reflectance = fresnel_dielectric(dot(self.wo, h), self.eta);
+        let pdf_h = self.ggx.visible_pdf(&self.wo, &h);
+        if wi.z() * self.wo.z() > 0.0 {
+            reflectance * pdf_h / (4.0 * dot(self.wo, h).abs())
+        } else {
+            // How much solid angle a change of h sweeps once refracted
+            let denom = dot(wi, h) + dot(self.wo, h) / etap;
+            (1.0 - reflectance) * pdf_h * dot(wi, h).abs() / (denom * denom)
+        }
+    }
+    fn generate(&self) -> Vec3 {
+        let h = self.ggx.sample_visible(&self.wo);
+        let reflectance = fresnel_dielectric(dot(self.wo, h), self.eta);
+        let wi = if rand_float() < reflectance {
+            Some(reflect(-1.0 * self.wo, h)).filter(|wi| wi.z() * self.wo.z() > 0.0)
+        } else {
+            refract_through(&self.wo, &h, self.eta).filter(|wi| wi.z() * self.wo.z() < 0.0)
+        };
+        match wi {
+            Some(wi) => self.uvw.local_vector(&wi),
+            None => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
 pub struct HittablePdf {
     o: Vec3,
     obj_ref: Arc<dyn Hittable>,
@@ -57,25 +158,42 @@ impl Pdf for HittablePdf {
     }
 }
 
+// How much of what one sampling strategy finds to keep, when another with
+// pdf `g` could have found it too (Veach 1997)
+pub fn power_heuristic(f: f32, g: f32) -> f32 {
+    f * f / (f * f + g * g)
+}
+
+// Picks one of its pdfs with the chance given by its weight
 pub struct MixturePdf {
-    p: Vec<Box<dyn Pdf>>,
+    p: Vec<(f32, Box<dyn Pdf>)>,
 }
 
 impl MixturePdf {
     pub fn new(p0: Box<dyn Pdf>, p1: Box<dyn Pdf>) -> Self {
-        let p = vec![p0, p1];
+        Self::weighted(vec![(0.5, p0), (0.5, p1)])
+    }
+    // The weights need not add up to one
+    pub fn weighted(p: Vec<(f32, Box<dyn Pdf>)>) -> Self {
+        let total: f32 = p.iter().map(|(w, _)| w).sum();
+        let p = p.into_iter().map(|(w, pdf)| (w / total, pdf)).collect();
         MixturePdf { p: p }
     }
 }
 
 impl Pdf for MixturePdf {
     fn value(&self, direction: &Vec3) -> f32 {
-        0.5 * self.p[0].value(direction) + 0.5 * self.p[1].value(direction)
+        self.p.iter().map(|(w, pdf)| w * pdf.value(direction)).sum()
     }
     fn generate(&self) -> Vec3 {
-        if rand_float() < 0.5 {
-            return self.p[0].generate();
+        let mut pick = rand_float();
+        for (w, pdf) in &self.p {
+            if pick < *w {
+                return pdf.generate();
+            }
+            pick -= w;
         }
-        self.p[1].generate()
+        // Rounding can leave a sliver past the last weight
+        self.p[self.p.len() - 1].1.generate()
     }
 }
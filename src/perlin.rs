@@ -10,6 +10,12 @@ pub struct Perlin {
     perm_z: Vec<u32>,
 }
 
+impl Default for Perlin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Perlin {
     pub fn new() -> Self {
         Self {
@@ -57,7 +63,7 @@ impl Perlin {
 
     fn generate_perm() -> Vec<u32> {
         let mut p: Vec<u32> = (0..256).collect();
-        p.shuffle(&mut rand::thread_rng());
+        with_rng(|rng| p.shuffle(rng));
         p
     }
 
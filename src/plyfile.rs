@@ -0,0 +1,565 @@
+// Stanford PLY meshes, in ASCII or binary of either byte order.
+//
+// From the `vertex` element this reads x, y, z, the normal nx, ny, nz,
+// the color red, green, blue and texture coordinates as u, v or s, t.
+// From the `face` element it reads the vertex_indices (or vertex_index)
+// list, fan triangulating faces with more than three corners. Any other
+// elements and properties are skipped.
+
+use std::path::Path;
+
+use crate::obj::MeshData;
+use crate::objfile::MeshError;
+use crate::vec3::Vec3;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "char" | "int8" => Some(Scalar::I8),
+            "uchar" | "uint8" => Some(Scalar::U8),
+            "short" | "int16" => Some(Scalar::I16),
+            "ushort" | "uint16" => Some(Scalar::U16),
+            "int" | "int32" => Some(Scalar::I32),
+            "uint" | "uint32" => Some(Scalar::U32),
+            "float" | "float32" => Some(Scalar::F32),
+            "double" | "float64" => Some(Scalar::F64),
+            _ => None,
+        }
+    }
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+    // Integer colors run up to the largest value of their type, float
+    // colors up to one
+    fn color_scale(self) -> f32 {
+        match self {
+            Scalar::I8 => 127.0,
+            Scalar::U8 => 255.0,
+            Scalar::I16 => 32767.0,
+            Scalar::U16 => 65535.0,
+            Scalar::I32 => 2_147_483_647.0,
+            Scalar::U32 => 4_294_967_295.0,
+            Scalar::F32 | Scalar::F64 => 1.0,
+        }
+    }
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    List(String, Scalar, Scalar),
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar(name, _) | Property::List(name, _, _) => name,
+        }
+    }
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+    // Where it was declared, for errors about missing properties
+    line: usize,
+}
+
+impl Element {
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|p| names.contains(&p.name()))
+    }
+}
+
+struct Header {
+    format: Format,
+    elements: Vec<Element>,
+    // Number of lines, so ASCII data errors can count on from it
+    lines: usize,
+    // Offset of the first byte after `end_header`
+    body: usize,
+}
+
+fn parse_error(path: &Path, line: usize, message: String) -> MeshError {
+    MeshError::Parse {
+        path: path.to_path_buf(),
+        line,
+        message,
+    }
+}
+
+fn parse_header(data: &[u8], path: &Path) -> Result<Header, MeshError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut offset = 0;
+    let mut line = 0;
+    loop {
+        let end = match data[offset..].iter().position(|b| *b == b'\n') {
+            Some(end) => offset + end,
+            None => return Err(parse_error(path, line, "missing `end_header`".to_string())),
+        };
+        line += 1;
+        let text = String::from_utf8_lossy(&data[offset..end]);
+        offset = end + 1;
+        let error = |message: String| Err(parse_error(path, line, message));
+
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        if line == 1 {
+            if tokens != ["ply"] {
+                return error("not a PLY file".to_string());
+            }
+            continue;
+        }
+        match tokens.as_slice() {
+            ["format", name, _version] => {
+                format = match *name {
+                    "ascii" => Some(Format::Ascii),
+                    "binary_little_endian" => Some(Format::BinaryLittleEndian),
+                    "binary_big_endian" => Some(Format::BinaryBigEndian),
+                    other => return error(format!("unknown format `{}`", other)),
+                }
+            }
+            ["element", name, count] => match count.parse() {
+                Ok(count) => elements.push(Element {
+                    name: name.to_string(),
+                    count,
+                    properties: Vec::new(),
+                    line,
+                }),
+                Err(_) => return error(format!("`{}` is not an element count", count)),
+            },
+            ["property", rest @ ..] => {
+                let element = match elements.last_mut() {
+                    Some(element) => element,
+                    None => return error("property before any element".to_string()),
+                };
+                let scalar = |name: &str| match Scalar::from_name(name) {
+                    Some(s) => Ok(s),
+                    None => Err(parse_error(
+                        path,
+                        line,
+                        format!("unknown property type `{}`", name),
+                    )),
+                };
+                let property = match rest {
+                    ["list", count, item, name] => {
+                        Property::List(name.to_string(), scalar(count)?, scalar(item)?)
+                    }
+                    [ty, name] => Property::Scalar(name.to_string(), scalar(ty)?),
+                    _ => return error("malformed property".to_string()),
+                };
+                element.properties.push(property);
+            }
+            ["end_header"] => break,
+            ["comment", ..] | ["obj_info", ..] | [] => (),
+            _ => return error(format!("unexpected `{}` in the header", text.trim())),
+        }
+    }
+    match format {
+        Some(format) => Ok(Header {
+            format,
+            elements,
+            lines: line,
+            body: offset,
+        }),
+        None => Err(parse_error(path, line, "missing `format`".to_string())),
+    }
+}
+
+// Reads the values after the header one at a time
+struct Body<'a> {
+    path: &'a Path,
+    format: Format,
+    data: &'a [u8],
+    pos: usize,
+    // The current line and what is left of it, for ASCII
+    line: usize,
+    tokens: std::str::SplitWhitespace<'a>,
+    lines: std::str::Lines<'a>,
+}
+
+impl<'a> Body<'a> {
+    fn new(data: &'a [u8], header: &Header, path: &'a Path) -> Result<Self, MeshError> {
+        let text = if header.format == Format::Ascii {
+            match std::str::from_utf8(&data[header.body..]) {
+                Ok(text) => text,
+                Err(_) => {
+                    return Err(MeshError::Invalid(
+                        path.to_path_buf(),
+                        "ASCII data is not valid text".to_string(),
+                    ))
+                }
+            }
+        } else {
+            ""
+        };
+        Ok(Self {
+            path,
+            format: header.format,
+            data,
+            pos: header.body,
+            line: header.lines,
+            tokens: "".split_whitespace(),
+            lines: text.lines(),
+        })
+    }
+
+    // Points at the line for ASCII data, or names the element for binary
+    fn error(&self, element: &str, index: usize, message: String) -> MeshError {
+        match self.format {
+            Format::Ascii => parse_error(self.path, self.line, message),
+            _ => MeshError::Invalid(
+                self.path.to_path_buf(),
+                format!("{} {}: {}", element, index, message),
+            ),
+        }
+    }
+
+    fn read(&mut self, ty: Scalar) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            let token = loop {
+                if let Some(token) = self.tokens.next() {
+                    break token;
+                }
+                match self.lines.next() {
+                    Some(line) => {
+                        self.line += 1;
+                        self.tokens = line.split_whitespace();
+                    }
+                    None => return Err("file ends early".to_string()),
+                }
+            };
+            return token
+                .parse()
+                .map_err(|_| format!("expected a number, found `{}`", token));
+        }
+
+        let size = ty.size();
+        let bytes = match self.data.get(self.pos..self.pos + size) {
+            Some(bytes) => bytes,
+            None => return Err("file ends early".to_string()),
+        };
+        self.pos += size;
+        let mut buf = [0; 8];
+        buf[..size].copy_from_slice(bytes);
+        if self.format == Format::BinaryBigEndian {
+            buf[..size].reverse();
+        }
+        // Reversed into little endian above
+        let word = [buf[0], buf[1], buf[2], buf[3]];
+        Ok(match ty {
+            Scalar::I8 => buf[0] as i8 as f64,
+            Scalar::U8 => buf[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes(word) as f64,
+            Scalar::U32 => u32::from_le_bytes(word) as f64,
+            Scalar::F32 => f32::from_le_bytes(word) as f64,
+            Scalar::F64 => f64::from_le_bytes(buf),
+        })
+    }
+
+    // All properties of one element, with lists in `list` and the value of
+    // each scalar property in `values`
+    fn read_element(
+        &mut self,
+        element: &Element,
+        index: usize,
+        values: &mut Vec<f64>,
+        list: &mut Vec<f64>,
+    ) -> Result<(), MeshError> {
+        values.clear();
+        list.clear();
+        for property in element.properties.iter() {
+            let result = match property {
+                Property::Scalar(_, ty) => self.read(*ty).map(|x| values.push(x)),
+                Property::List(_, count, item) => {
+                    values.push(0.0);
+                    self.read(*count).and_then(|n| {
+                        (0..n as usize).try_for_each(|_| self.read(*item).map(|x| list.push(x)))
+                    })
+                }
+            };
+            result.map_err(|message| self.error(&element.name, index, message))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_ply(data: &[u8], path: &Path) -> Result<MeshData, MeshError> {
+    let header = parse_header(data, path)?;
+    let mut body = Body::new(data, &header, path)?;
+    let mut mesh = MeshData {
+        positions: Vec::new(),
+        normals: Vec::new(),
+        uvs: Vec::new(),
+        colors: Vec::new(),
+        indices: Vec::new(),
+        motion: Vec::new(),
+    };
+    let vertex_count = header
+        .elements
+        .iter()
+        .find(|e| e.name == "vertex")
+        .map_or(0, |e| e.count);
+    let mut values = Vec::new();
+    let mut list = Vec::new();
+
+    for element in header.elements.iter() {
+        let missing = |what: &str| {
+            Err(parse_error(
+                path,
+                element.line,
+                format!("`{}` has no {} property", element.name, what),
+            ))
+        };
+        // Indices of the properties this element is read for, if any
+        let (xyz, normal, color, uv, corners) = match element.name.as_str() {
+            "vertex" => {
+                let xyz = match (
+                    element.find(&["x"]),
+                    element.find(&["y"]),
+                    element.find(&["z"]),
+                ) {
+                    (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                    _ => return missing("x, y and z"),
+                };
+                let normal = match (
+                    element.find(&["nx"]),
+                    element.find(&["ny"]),
+                    element.find(&["nz"]),
+                ) {
+                    (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                    _ => None,
+                };
+                let color = match (
+                    element.find(&["red", "diffuse_red"]),
+                    element.find(&["green", "diffuse_green"]),
+                    element.find(&["blue", "diffuse_blue"]),
+                ) {
+                    (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+                    _ => None,
+                };
+                let uv = match (
+                    element.find(&["u", "s", "texture_u", "texture_s"]),
+                    element.find(&["v", "t", "texture_v", "texture_t"]),
+                ) {
+                    (Some(u), Some(v)) => Some([u, v]),
+                    _ => None,
+                };
+                (xyz, normal, color, uv, None)
+            }
+            "face" => match element.find(&["vertex_indices", "vertex_index"]) {
+                Some(i) => (None, None, None, None, Some(i)),
+                None => return missing("vertex_indices"),
+            },
+            _ => (None, None, None, None, None),
+        };
+        if let Some(i) = corners {
+            if let Property::Scalar(..) = element.properties[i] {
+                return Err(parse_error(
+                    path,
+                    element.line,
+                    "vertex_indices must be a list".to_string(),
+                ));
+            }
+        }
+        let color_scale = color.map(|c| match element.properties[c[0]] {
+            Property::Scalar(_, ty) => ty.color_scale(),
+            Property::List(..) => 1.0,
+        });
+
+        for index in 0..element.count {
+            body.read_element(element, index, &mut values, &mut list)?;
+            let vec3 = |i: [usize; 3]| {
+                Vec3::new(
+                    values[i[0]] as f32,
+                    values[i[1]] as f32,
+                    values[i[2]] as f32,
+                )
+            };
+            if let Some(xyz) = xyz {
+                mesh.positions.push(vec3(xyz));
+            }
+            if let Some(normal) = normal {
+                mesh.normals.push(vec3(normal));
+            }
+            if let (Some(color), Some(scale)) = (color, color_scale) {
+                mesh.colors.push(vec3(color) / scale);
+            }
+            if let Some([u, v]) = uv {
+                mesh.uvs.push((values[u] as f32, values[v] as f32));
+            }
+            if corners.is_some() {
+                if list.len() < 3 {
+                    return Err(body.error(
+                        "face",
+                        index,
+                        format!(
+                            "a face needs at least 3 vertices, this one has {}",
+                            list.len()
+                        ),
+                    ));
+                }
+                if let Some(bad) = list
+                    .iter()
+                    .find(|i| **i < 0.0 || **i >= vertex_count as f64 || i.fract() != 0.0)
+                {
+                    return Err(body.error(
+                        "face",
+                        index,
+                        format!(
+                            "vertex index {} is out of range, there are {}",
+                            bad, vertex_count
+                        ),
+                    ));
+                }
+                for k in 1..list.len() - 1 {
+                    mesh.indices
+                        .push([list[0] as u32, list[k] as u32, list[k + 1] as u32]);
+                }
+            }
+        }
+    }
+
+    if mesh.indices.is_empty() {
+        return Err(MeshError::Invalid(
+            path.to_path_buf(),
+            "no faces".to_string(),
+        ));
+    }
+    Ok(mesh)
+}
+
+pub fn load_ply(path: &Path) -> Result<MeshData, MeshError> {
+    match std::fs::read(path) {
+        Ok(data) => parse_ply(&data, path),
+        Err(e) => Err(MeshError::Io(path.to_path_buf(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII: &str = "ply\n\
+        format ascii 1.0\n\
+        comment a unit quad with a red and a blue edge\n\
+        element vertex 4\n\
+        property float x\n\
+        property float y\n\
+        property float z\n\
+        property uchar red\n\
+        property uchar green\n\
+        property uchar blue\n\
+        element face 1\n\
+        property list uchar int vertex_indices\n\
+        end_header\n\
+        0 0 0 255 0 0\n\
+        1 0 0 255 0 0\n\
+        1 1 0 0 0 255\n\
+        0 1 0 0 0 255\n\
+        4 0 1 2 3\n";
+
+    // The same quad in binary, with a double for y to check mixed sizes
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let format = if big_endian { "big" } else { "little" };
+        let mut data = format!(
+            "ply\nformat binary_{}_endian 1.0\n\
+             element vertex 4\nproperty float x\nproperty double y\nproperty float z\n\
+             property uchar red\nproperty uchar green\nproperty uchar blue\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n",
+            format
+        )
+        .into_bytes();
+        let f32s = |x: f32| {
+            if big_endian {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            }
+        };
+        let f64s = |x: f64| {
+            if big_endian {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            }
+        };
+        let i32s = |x: i32| {
+            if big_endian {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            }
+        };
+        for (x, y, color) in [
+            (0.0, 0.0, [255, 0, 0]),
+            (1.0, 0.0, [255, 0, 0]),
+            (1.0, 1.0, [0, 0, 255]),
+            (0.0, 1.0, [0, 0, 255]),
+        ]
+        .iter()
+        {
+            data.extend_from_slice(&f32s(*x));
+            data.extend_from_slice(&f64s(*y));
+            data.extend_from_slice(&f32s(0.0));
+            data.extend_from_slice(color);
+        }
+        data.push(4);
+        for i in 0..4 {
+            data.extend_from_slice(&i32s(i));
+        }
+        data
+    }
+
+    #[test]
+    fn ascii_and_binary_agree() {
+        let path = Path::new("quad.ply");
+        let meshes = [
+            parse_ply(ASCII.as_bytes(), path).unwrap(),
+            parse_ply(&binary(false), path).unwrap(),
+            parse_ply(&binary(true), path).unwrap(),
+        ];
+        for mesh in meshes.iter() {
+            assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+            assert!((mesh.positions[2] - Vec3::new(1.0, 1.0, 0.0)).mag() < 1e-6);
+            assert!((mesh.colors[3] - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-6);
+            assert!(mesh.normals.is_empty() && mesh.uvs.is_empty());
+        }
+
+        let bad = ASCII.replace("4 0 1 2 3", "4 0 1 2 7");
+        match parse_ply(bad.as_bytes(), path) {
+            Err(MeshError::Parse { line, .. }) => assert_eq!(line, 18),
+            _ => panic!("expected an error for an out of range index"),
+        }
+        let truncated = binary(false);
+        assert!(parse_ply(&truncated[..truncated.len() - 2], path).is_err());
+    }
+}
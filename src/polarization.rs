@@ -0,0 +1,259 @@
+// Polarized light as Stokes vectors, changed by the Mueller matrices of
+// the surfaces it meets (Wilkie and Weidlich 2012).
+//
+// A Stokes vector holds the intensity S0, how much more of it is polarized
+// along its frame's x axis than across it S1, the same for the diagonals
+// S2, and how much is circularly polarized S3, each with a value per
+// channel. What a path brings back along a ray is given in a frame fixed by
+// the ray's direction, with x perpendicular to it and to the world's up.
+//
+// Only the polarizing part of an interaction is a Mueller matrix here,
+// scaled so that unpolarized light keeps its intensity. Materials keep
+// their attenuation and sampling, so unpolarized light comes out exactly as
+// in the scalar mode.
+
+use std::ops::{Add, Div};
+
+use num::complex::Complex32;
+
+use crate::hit::HitRecord;
+use crate::material::Material;
+use crate::vec3::*;
+
+// What a path carries back to the camera, radiance or its Stokes vector
+pub trait Light: Copy + Add<Output = Self> + Div<f32, Output = Self> {
+    fn unpolarized(radiance: Vec3) -> Self;
+    // Times a color per channel
+    fn scaled(self, by: Vec3) -> Self;
+    // The light that came back along `next` after `material` sent `r` that
+    // way, on its way back along `r`
+    fn through(self, material: &dyn Material, r: &Ray, hit: &HitRecord, next: &Ray) -> Self;
+}
+
+impl Light for Vec3 {
+    fn unpolarized(radiance: Vec3) -> Vec3 {
+        radiance
+    }
+    fn scaled(self, by: Vec3) -> Vec3 {
+        by * self
+    }
+    fn through(self, _material: &dyn Material, _r: &Ray, _hit: &HitRecord, _next: &Ray) -> Vec3 {
+        self
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stokes {
+    pub s0: Vec3,
+    pub s1: Vec3,
+    pub s2: Vec3,
+    pub s3: Vec3,
+}
+
+impl Add for Stokes {
+    type Output = Stokes;
+    fn add(self, o: Stokes) -> Stokes {
+        Stokes {
+            s0: self.s0 + o.s0,
+            s1: self.s1 + o.s1,
+            s2: self.s2 + o.s2,
+            s3: self.s3 + o.s3,
+        }
+    }
+}
+
+impl Div<f32> for Stokes {
+    type Output = Stokes;
+    fn div(self, t: f32) -> Stokes {
+        Stokes {
+            s0: self.s0 / t,
+            s1: self.s1 / t,
+            s2: self.s2 / t,
+            s3: self.s3 / t,
+        }
+    }
+}
+
+impl Light for Stokes {
+    fn unpolarized(radiance: Vec3) -> Stokes {
+        Stokes {
+            s0: radiance,
+            ..Stokes::default()
+        }
+    }
+    fn scaled(self, by: Vec3) -> Stokes {
+        Stokes {
+            s0: by * self.s0,
+            s1: by * self.s1,
+            s2: by * self.s2,
+            s3: by * self.s3,
+        }
+    }
+    fn through(self, material: &dyn Material, r: &Ray, hit: &HitRecord, next: &Ray) -> Stokes {
+        let m = match material.mueller(r, hit, next) {
+            Some(m) => m,
+            None => return Stokes::unpolarized(self.s0),
+        };
+        // The light came in along -from and leaves along -to, s
+        // polarized across the plane of both
+        let from = next.direction().unit();
+        let to = r.direction().unit();
+        let across = cross(from, to);
+        let s = if across.mag() > 1e-6 {
+            across.unit()
+        } else {
+            frame_x(from)
+        };
+        self.rotated(frame_x(from), s, -1.0 * from)
+            .apply(&m)
+            .rotated(s, frame_x(to), -1.0 * to)
+    }
+}
+
+// The x axis of the frame for light along `dir`, horizontal unless `dir`
+// is straight up or down
+pub fn frame_x(dir: Vec3) -> Vec3 {
+    let x = cross(dir, Vec3::new(0.0, 1.0, 0.0));
+    if x.mag() > 1e-6 {
+        x.unit()
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+}
+
+impl Stokes {
+    // Every component through `f`
+    pub fn map<F: Fn(Vec3) -> Vec3>(self, f: F) -> Stokes {
+        Stokes {
+            s0: f(self.s0),
+            s1: f(self.s1),
+            s2: f(self.s2),
+            s3: f(self.s3),
+        }
+    }
+
+    // The fraction of the light that is polarized
+    pub fn degree(&self) -> Vec3 {
+        let mut dop = Vec3::default();
+        for c in 0..3 {
+            let polarized = (self.s1[c].powi(2) + self.s2[c].powi(2) + self.s3[c].powi(2)).sqrt();
+            if self.s0[c] > 0.0 {
+                dop[c] = (polarized / self.s0[c]).min(1.0);
+            }
+        }
+        dop
+    }
+
+    // The angle of linear polarization from the frame's x axis towards its
+    // y axis, from 0 to pi
+    pub fn angle(&self) -> Vec3 {
+        let mut aop = Vec3::default();
+        for c in 0..3 {
+            let a = 0.5 * self.s2[c].atan2(self.s1[c]);
+            aop[c] = if a < 0.0 { a + std::f32::consts::PI } else { a };
+        }
+        aop
+    }
+
+    // The same light in the frame with x axis `to` instead of `from`, both
+    // across the direction of travel `dir`
+    fn rotated(self, from: Vec3, to: Vec3, dir: Vec3) -> Stokes {
+        let phi = dot(cross(from, to), dir).atan2(dot(from, to));
+        let (sin, cos) = (2.0 * phi).sin_cos();
+        Stokes {
+            s1: cos * self.s1 + sin * self.s2,
+            s2: cos * self.s2 - sin * self.s1,
+            ..self
+        }
+    }
+
+    fn apply(self, m: &Mueller) -> Stokes {
+        Stokes {
+            s0: self.s0 + m.b * self.s1,
+            s1: m.b * self.s0 + self.s1,
+            s2: m.c * self.s2 + m.d * self.s3,
+            s3: m.c * self.s3 - m.d * self.s2,
+        }
+    }
+}
+
+// The Mueller matrix of a reflection or refraction in the frame with x
+// across the plane of incidence, over its first element:
+//
+//   | 1  b  0  0 |
+//   | b  1  0  0 |
+//   | 0  0  c  d |
+//   | 0  0 -d  c |
+#[derive(Copy, Clone, Debug)]
+pub struct Mueller {
+    pub b: Vec3,
+    pub c: Vec3,
+    pub d: Vec3,
+}
+
+impl Mueller {
+    // From the Fresnel equations, light meeting a surface with complex
+    // index of refraction `eta` + i `k` relative to where it came from at
+    // `cos_i` to the normal
+    pub fn fresnel(cos_i: f32, eta: Vec3, k: Vec3, reflected: bool) -> Mueller {
+        let mut m = Mueller {
+            b: Vec3::default(),
+            c: Vec3::default(),
+            d: Vec3::default(),
+        };
+        let cos_i = cos_i.clamp(0.0, 1.0);
+        let ci = Complex32::new(cos_i, 0.0);
+        for c in 0..3 {
+            let eta = Complex32::new(eta[c], k[c]);
+            let sin2_t = Complex32::new(1.0 - cos_i * cos_i, 0.0) / (eta * eta);
+            let ct = (Complex32::new(1.0, 0.0) - sin2_t).sqrt();
+            let (s, p) = if reflected {
+                (
+                    (ci - eta * ct) / (ci + eta * ct),
+                    (eta * ci - ct) / (eta * ci + ct),
+                )
+            } else {
+                (2.0 * ci / (ci + eta * ct), 2.0 * ci / (eta * ci + ct))
+            };
+            let a = 0.5 * (s.norm_sqr() + p.norm_sqr());
+            if a > 0.0 {
+                let sp = s * p.conj();
+                m.b[c] = 0.5 * (s.norm_sqr() - p.norm_sqr()) / a;
+                m.c[c] = sp.re / a;
+                m.d[c] = sp.im / a;
+            } else {
+                m.c[c] = 1.0;
+            }
+        }
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unpolarized light reflected off glass at Brewster's angle is all s
+    // polarized, and turning the frame a quarter turn makes it all p
+    #[test]
+    fn brewster_reflection_is_polarized() {
+        let cos_b = 1.5f32.atan().cos();
+        let eta = Vec3::new(1.5, 1.5, 1.5);
+        let m = Mueller::fresnel(cos_b, eta, Vec3::default(), true);
+        let l = Stokes::unpolarized(Vec3::new(1.0, 1.0, 1.0)).apply(&m);
+        assert!((l.degree()[0] - 1.0).abs() < 1e-4 && l.angle()[0].abs() < 1e-4);
+
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        let turned = l.rotated(x, y, Vec3::new(0.0, 0.0, 1.0));
+        assert!((turned.s1[0] + l.s1[0]).abs() < 1e-4);
+
+        let through = Stokes::unpolarized(Vec3::new(1.0, 1.0, 1.0)).apply(&Mueller::fresnel(
+            cos_b,
+            eta,
+            Vec3::default(),
+            false,
+        ));
+        assert!(through.degree()[0] > 0.0 && through.degree()[0] < 0.2);
+    }
+}
@@ -0,0 +1,629 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+
+use crate::camera::Camera;
+use crate::film::Film;
+use crate::hit::Hittable;
+use crate::output::Image;
+use crate::pdf::*;
+use crate::polarization::*;
+use crate::scene::Scene;
+use crate::sensor::Sensor;
+use crate::spectrum::*;
+use crate::util::*;
+use crate::vec3::*;
+
+// Radiance as a Vec3, or as a Stokes vector for polarization
+pub fn color<L: Light>(
+    r: Ray,
+    world: &Vec<Arc<dyn Hittable>>,
+    lights: &Option<Arc<dyn Hittable>>,
+    depth: u32,
+) -> L {
+    radiance(r, RGB_WAVELENGTHS, world, lights, depth, 1.0, &|c| c)
+}
+
+// Radiance at the three wavelengths in `lambdas`, see `spectrum`
+pub fn color_spectral<L: Light>(
+    r: Ray,
+    lambdas: Vec3,
+    world: &Vec<Arc<dyn Hittable>>,
+    lights: &Option<Arc<dyn Hittable>>,
+    depth: u32,
+) -> L {
+    radiance(r, lambdas, world, lights, depth, 1.0, &|c| {
+        upsample(c, lambdas)
+    })
+}
+
+// The path tracer behind both, carrying three channels at the wavelengths
+// in `lambdas`, with `spectrum` turning the RGB colors from materials into
+// what the channels carry.
+//
+// Every diffuse or rough bounce sends a shadow ray towards a point picked
+// on the lights, next event estimation, and carries on the way its material
+// would scatter. Light reached either way is weighted by the power heuristic
+// against the other way of finding it, so what a bounce's ray hits counts
+// for `emission_weight` of its emission.
+fn radiance<L: Light>(
+    r: Ray,
+    lambdas: Vec3,
+    world: &Vec<Arc<dyn Hittable>>,
+    lights: &Option<Arc<dyn Hittable>>,
+    depth: u32,
+    emission_weight: f32,
+    spectrum: &dyn Fn(Vec3) -> Vec3,
+) -> L {
+    if depth == 0 {
+        return L::unpolarized(Vec3::new(0.0, 0.0, 0.0));
+    }
+    if let Some(hit) = world.hit(r, 0.001, f32::MAX) {
+        let emitted = L::unpolarized(
+            emission_weight * spectrum(hit.material.emitted(&r, &hit, hit.u, hit.v, &hit.p)),
+        );
+        // Past a dispersive surface the channels go separate ways, so only
+        // one of them carries on, picked at random and counted three times
+        let (r, weight) = if r.wavelength.is_none() && hit.material.dispersive() {
+            let channel = ((3.0 * rand_float()) as u32).min(2);
+            let mut weight = Vec3::default();
+            weight[channel] = 3.0;
+            (r.with_wavelength(Some(lambdas[channel])), weight)
+        } else {
+            (r, Vec3::new(1.0, 1.0, 1.0))
+        };
+        let follow = |next: Ray, emission_weight: f32| {
+            let next = next.with_wavelength(r.wavelength);
+            let l: L = radiance(
+                next,
+                lambdas,
+                world,
+                lights,
+                depth - 1,
+                emission_weight,
+                spectrum,
+            );
+            l.scaled(weight)
+                .through(hit.material.as_ref(), &r, &hit, &next)
+        };
+        if let Some(s_rec) = hit.material.scatter(r, &hit) {
+            if s_rec.is_specular {
+                return follow(s_rec.specular_ray, 1.0).scaled(spectrum(s_rec.attenuation));
+            } else {
+                let bsdf = s_rec.pdf.unwrap();
+                let plight = lights
+                    .as_ref()
+                    .map(|lights| HittablePdf::new(lights.clone(), hit.p));
+
+                let direct = match &plight {
+                    Some(plight) => {
+                        let shadow = Ray::new(hit.p, plight.generate(), r.time());
+                        let light_pdf = plight.value(&shadow.direction());
+                        match (light_pdf > 0.0, world.hit(shadow, 0.001, f32::MAX)) {
+                            (true, Some(light)) => {
+                                let le = light
+                                    .material
+                                    .emitted(&shadow, &light, light.u, light.v, &light.p);
+                                let f =
+                                    hit.material
+                                        .scattering(&r, &hit, &shadow, s_rec.attenuation);
+                                let mis =
+                                    power_heuristic(light_pdf, bsdf.value(&shadow.direction()));
+                                let shadow = shadow.with_wavelength(r.wavelength);
+                                L::unpolarized(weight * spectrum(le))
+                                    .through(hit.material.as_ref(), &r, &hit, &shadow)
+                                    .scaled(mis / light_pdf * spectrum(f))
+                            }
+                            _ => L::unpolarized(Vec3::new(0.0, 0.0, 0.0)),
+                        }
+                    }
+                    None => L::unpolarized(Vec3::new(0.0, 0.0, 0.0)),
+                };
+
+                // The path goes on the way the material would send it
+                let scattered = Ray::new(hit.p, bsdf.generate(), r.time());
+                if scattered.direction().mag_sqrd() == 0.0 {
+                    return emitted + direct;
+                }
+                let pdf_val = bsdf.value(&scattered.direction());
+                if pdf_val <= 0.0 {
+                    return emitted + direct;
+                }
+                // Without lights there is no other way to find what it hits
+                let emission_weight = match &plight {
+                    Some(plight) => power_heuristic(pdf_val, plight.value(&scattered.direction())),
+                    None => 1.0,
+                };
+
+                let f = hit
+                    .material
+                    .scattering(&r, &hit, &scattered, s_rec.attenuation);
+                return emitted
+                    + direct
+                    + follow(scattered, emission_weight).scaled(spectrum(f)) / pdf_val;
+            }
+        } else {
+            return emitted;
+        }
+    }
+    L::unpolarized(Vec3::new(0.0, 0.0, 0.0))
+}
+
+// Albedo, normal and distance at the first hit of a camera ray
+fn first_hit(r: Ray, world: &Vec<Arc<dyn Hittable>>) -> (Vec3, Vec3, f32) {
+    match world.hit(r, 0.001, f32::MAX) {
+        Some(hit) => {
+            let albedo = match hit.material.scatter(r, &hit) {
+                Some(s_rec) => s_rec.attenuation,
+                None => hit.material.emitted(&r, &hit, hit.u, hit.v, &hit.p),
+            };
+            (albedo, hit.normal, hit.t * r.direction().mag())
+        }
+        None => (Vec3::default(), Vec3::default(), f32::INFINITY),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None,
+        }
+    }
+}
+
+// A rectangle of pixels, (x0, y0) inclusive to (x1, y1) exclusive with y
+// going down from the top row
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+// Tile (x, y) in the grid as pixels, clipped to the image
+fn tile_at(x: u32, y: u32, size: u32, width: u32, height: u32) -> Tile {
+    Tile {
+        x0: x * size,
+        y0: y * size,
+        x1: ((x + 1) * size).min(width),
+        y1: ((y + 1) * size).min(height),
+    }
+}
+
+// Converts a distance along the Hilbert curve filling an n by n grid to a
+// position, n must be a power of two
+fn hilbert_d2xy(n: u32, d: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+pub fn tiles(width: u32, height: u32, size: u32, order: TileOrder) -> Vec<Tile> {
+    let nx = width.div_ceil(size);
+    let ny = height.div_ceil(size);
+    let mut grid: Vec<(u32, u32)> = Vec::with_capacity((nx * ny) as usize);
+
+    match order {
+        TileOrder::Scanline => {
+            for y in 0..ny {
+                for x in 0..nx {
+                    grid.push((x, y));
+                }
+            }
+        }
+        TileOrder::Spiral => {
+            // Walk outwards from the center in legs of 1, 1, 2, 2, 3, 3, ...
+            // tiles, skipping the ones that fall outside the image
+            let (mut x, mut y) = ((nx as i64 - 1) / 2, (ny as i64 - 1) / 2);
+            let dirs = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+            let mut leg = 1;
+            let mut dir = 0;
+            while grid.len() < (nx * ny) as usize {
+                for _ in 0..2 {
+                    for _ in 0..leg {
+                        if x >= 0 && y >= 0 && x < nx as i64 && y < ny as i64 {
+                            grid.push((x as u32, y as u32));
+                        }
+                        x += dirs[dir].0;
+                        y += dirs[dir].1;
+                    }
+                    dir = (dir + 1) % 4;
+                }
+                leg += 1;
+            }
+        }
+        TileOrder::Hilbert => {
+            let n = nx.max(ny).next_power_of_two();
+            for d in 0..n * n {
+                let (x, y) = hilbert_d2xy(n, d);
+                if x < nx && y < ny {
+                    grid.push((x, y));
+                }
+            }
+        }
+    }
+
+    grid.into_iter()
+        .map(|(x, y)| tile_at(x, y, size, width, height))
+        .collect()
+}
+
+// How often to write snapshots or checkpoints between passes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interval {
+    Passes(u32),
+    Seconds(u64),
+}
+
+impl Interval {
+    // Parses `N` as every N passes and `Ns` as every N seconds
+    pub fn from_arg(arg: &str) -> Option<Interval> {
+        let (n, seconds) = match arg.strip_suffix('s') {
+            Some(n) => (n, true),
+            None => (arg, false),
+        };
+        match n.parse::<u32>() {
+            Ok(0) | Err(_) => None,
+            Ok(n) if seconds => Some(Interval::Seconds(n as u64)),
+            Ok(n) => Some(Interval::Passes(n)),
+        }
+    }
+}
+
+// Keeps track of when something done every `Interval` is next due
+pub struct Schedule {
+    interval: Interval,
+    passes: u32,
+    last: Instant,
+}
+
+impl Schedule {
+    pub fn new(interval: Interval) -> Self {
+        Self {
+            interval,
+            passes: 0,
+            last: Instant::now(),
+        }
+    }
+
+    // Called once after every pass
+    pub fn due(&mut self) -> bool {
+        self.passes += 1;
+        let due = match self.interval {
+            Interval::Passes(n) => self.passes.is_multiple_of(n),
+            Interval::Seconds(s) => self.last.elapsed() >= Duration::from_secs(s),
+        };
+        if due {
+            self.last = Instant::now();
+        }
+        due
+    }
+}
+
+// Seed for the samples of one pixel, a few rounds of splitmix64 so that
+// neighbouring pixels get unrelated sequences
+fn pixel_seed(seed: u64, x: u32, y: u32) -> u64 {
+    let mix = |mut z: u64| {
+        z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let pixel = (x as u64) << 32 | y as u64;
+    mix(mix(seed) ^ pixel)
+}
+
+pub struct Renderer {
+    pub width: u32,
+    pub height: u32,
+    // Total samples per pixel, rendered in passes of `pass_samples`
+    pub samples: u32,
+    pub pass_samples: u32,
+    pub max_depth: u32,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    // Also collect albedo, normal and depth
+    pub aovs: bool,
+    pub seed: u64,
+    // Trace wavelengths instead of red, green and blue
+    pub spectral: bool,
+    // Also see the spectra through these receptors, which implies spectral
+    pub sensor: Option<Arc<Sensor>>,
+    // Carry Stokes vectors for the polarization of the light
+    pub polarized: bool,
+}
+
+impl Renderer {
+    // Everything in one pass with the default depth, tiling and seed
+    pub fn new(width: u32, height: u32, samples: u32) -> Self {
+        Self {
+            width,
+            height,
+            samples,
+            pass_samples: samples,
+            max_depth: 50,
+            tile_size: 32,
+            tile_order: TileOrder::Hilbert,
+            aovs: false,
+            seed: 0,
+            spectral: false,
+            sensor: None,
+            polarized: false,
+        }
+    }
+
+    // A film for this renderer to start on
+    pub fn film(&self) -> Film {
+        let receptors = self.sensor.as_ref().map_or(0, |s| s.names.len());
+        Film::new(self.width, self.height, self.aovs)
+            .with_receptors(receptors)
+            .with_polarization(self.polarized)
+    }
+
+    // The image so far, with what the receptors saw if there is a sensor
+    pub fn image(&self, film: &Film) -> Image {
+        let mut image = film.image();
+        if let (Some(sensor), Some(values)) = (&self.sensor, film.responses()) {
+            image.receptors = Some(sensor.receptors(values));
+        }
+        image
+    }
+
+    // The light along a camera ray, at `lambdas` if spectral
+    fn trace<L: Light>(
+        &self,
+        r: Ray,
+        lambdas: Option<Vec3>,
+        world: &Vec<Arc<dyn Hittable>>,
+        lights: &Option<Arc<dyn Hittable>>,
+    ) -> L {
+        match lambdas {
+            Some(lambdas) => color_spectral(r, lambdas, world, lights, self.max_depth),
+            None => color(r, world, lights, self.max_depth),
+        }
+    }
+
+    fn render_tile(
+        &self,
+        tile: Tile,
+        samples: Range<u32>,
+        cam: &Camera,
+        world: &Vec<Arc<dyn Hittable>>,
+        lights: &Option<Arc<dyn Hittable>>,
+    ) -> Film {
+        let receptors = self.sensor.as_ref().map_or(0, |s| s.names.len());
+        let mut film = Film::new(tile.x1 - tile.x0, tile.y1 - tile.y0, self.aovs)
+            .with_receptors(receptors)
+            .with_polarization(self.polarized);
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                seed_rng(pixel_seed(self.seed, x, y));
+                for sample in samples.clone() {
+                    set_rng_stream(sample as u64);
+                    let u = (x as f32 + rand_float()) / self.width as f32;
+                    let v = ((self.height - 1 - y) as f32 + rand_float()) / self.height as f32;
+                    let r = cam.get_ray(u, v);
+                    let lambdas = if self.spectral || self.sensor.is_some() {
+                        Some(sample_wavelengths(rand_float()))
+                    } else {
+                        None
+                    };
+                    let light = if self.polarized {
+                        self.trace::<Stokes>(r, lambdas, world, lights)
+                    } else {
+                        Stokes::unpolarized(self.trace::<Vec3>(r, lambdas, world, lights))
+                    };
+                    let light = match lambdas {
+                        Some(lambdas) => {
+                            let light = light.map(|c| de_nan(&c));
+                            if let Some(sensor) = &self.sensor {
+                                let responses = sensor.responses(light.s0, lambdas);
+                                film.add_responses(x - tile.x0, y - tile.y0, &responses);
+                            }
+                            light.map(|c| to_rgb(c, lambdas))
+                        }
+                        None => light,
+                    };
+                    let light = light.map(|c| de_nan(&c));
+                    film.add_sample(x - tile.x0, y - tile.y0, light.s0);
+                    if self.polarized {
+                        film.add_stokes(x - tile.x0, y - tile.y0, &light);
+                    }
+                    // After the path, so that the random numbers the first
+                    // hit draws do not change the image
+                    if self.aovs {
+                        let (albedo, normal, depth) = first_hit(r, world);
+                        film.add_aovs(x - tile.x0, y - tile.y0, albedo, normal, depth);
+                    }
+                }
+            }
+        }
+        film
+    }
+
+    // Adds one pass of samples to every pixel of `film`. Each thread of the
+    // pool takes the next tile in traversal order whenever it is free and
+    // renders it into its own film before merging it.
+    fn render_pass(
+        &self,
+        film: &mut Film,
+        cam: &Camera,
+        world: &Vec<Arc<dyn Hittable>>,
+        lights: &Option<Arc<dyn Hittable>>,
+    ) {
+        let first = film.spp();
+        let samples = first..self.samples.min(first + self.pass_samples);
+        let film = Mutex::new(film);
+        let tiles = tiles(self.width, self.height, self.tile_size, self.tile_order);
+        let next = AtomicUsize::new(0);
+        (0..rayon::current_num_threads())
+            .into_par_iter()
+            .for_each(|_| {
+                while let Some(tile) = tiles.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let tile_film = self.render_tile(*tile, samples.clone(), cam, world, lights);
+                    film.lock().unwrap().merge(tile.x0, tile.y0, &tile_film);
+                }
+            });
+    }
+
+    // Renders passes into `film` until every pixel has `samples`, carrying
+    // on from whatever it already holds. `after_pass` gets to look at the
+    // film after each pass but the last.
+    pub fn render<F: FnMut(&Film)>(&self, film: &mut Film, scene: &Scene, mut after_pass: F) {
+        let lights: Option<Arc<dyn Hittable>> = if scene.lights.is_empty() {
+            None
+        } else {
+            Some(Arc::new(scene.lights.clone()))
+        };
+        while film.spp() < self.samples {
+            self.render_pass(film, &scene.camera, &scene.world, &lights);
+            if film.spp() < self.samples {
+                after_pass(film);
+            }
+        }
+    }
+}
+
+// Renders `scene` from start to finish and returns the image
+pub fn render(scene: &Scene, renderer: &Renderer) -> Image {
+    let mut film = renderer.film();
+    renderer.render(&mut film, scene, |_| {});
+    renderer.image(&film)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_image_once() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+            let (width, height) = (100, 37);
+            let mut covered = vec![0; (width * height) as usize];
+            for tile in tiles(width, height, 16, *order) {
+                for y in tile.y0..tile.y1 {
+                    for x in tile.x0..tile.x1 {
+                        covered[(y * width + x) as usize] += 1;
+                    }
+                }
+            }
+            assert!(covered.iter().all(|n| *n == 1), "{:?}", order);
+        }
+    }
+
+    // Light that never meets glass or metal is never polarized and comes
+    // out just as bright as without Stokes vectors
+    #[test]
+    fn unpolarized_scene_renders_the_same() {
+        let scene = crate::scene::cornell_box(1.0);
+        let scalar = render(&scene, &Renderer::new(12, 12, 4));
+        let polarized = render(
+            &scene,
+            &Renderer {
+                polarized: true,
+                ..Renderer::new(12, 12, 4)
+            },
+        );
+        for (a, b) in scalar.pixels.iter().zip(polarized.pixels.iter()) {
+            assert_eq!((a[0], a[1], a[2]), (b[0], b[1], b[2]));
+        }
+        let dop = polarized.polarization.unwrap().dop;
+        assert!(dop.iter().all(|d| d.mag() == 0.0));
+    }
+
+    // Glass and smoke draw random numbers when hit, and looking up the AOVs
+    // must not take them from the path
+    #[test]
+    fn aovs_leave_the_image_alone() {
+        for scene in [
+            crate::scene::cornell_mc(1.0),
+            crate::scene::cornell_smoke_scene(1.0),
+        ]
+        .iter()
+        {
+            let plain = render(scene, &Renderer::new(16, 16, 4));
+            let with_aovs = render(
+                scene,
+                &Renderer {
+                    aovs: true,
+                    ..Renderer::new(16, 16, 4)
+                },
+            );
+            for (a, b) in plain.pixels.iter().zip(with_aovs.pixels.iter()) {
+                assert_eq!((a[0], a[1], a[2]), (b[0], b[1], b[2]));
+            }
+        }
+    }
+
+    // A gray floor under a square light sees the same light whether it is
+    // found by shadow rays or by bounces, and none of it twice
+    #[test]
+    fn light_is_counted_once() {
+        use crate::material::*;
+        use crate::obj::*;
+        use crate::texture::ConstantTexture;
+        use crate::transf::FlipNormals;
+
+        let gray = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
+            0.5, 0.5, 0.5,
+        )))));
+        let white = Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
+            Vec3::new(1.0, 1.0, 1.0),
+        ))));
+        let light: Arc<dyn Hittable> = Arc::new(FlipNormals::new(Arc::new(XZRect::new(
+            -1.0, 1.0, -1.0, 1.0, 1.0, white,
+        ))));
+        let world: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(XZRect::new(-100.0, 100.0, -100.0, 100.0, 0.0, gray)),
+            light.clone(),
+        ];
+        let down = Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+
+        // The floor reflects half of the light's form factor from its center
+        let expected = 0.5 * 0.5541;
+        let n = 20000;
+        for lights in [
+            None,
+            Some(Arc::new(vec![light.clone()]) as Arc<dyn Hittable>),
+        ]
+        .iter()
+        {
+            let mut sum = 0.0;
+            for _ in 0..n {
+                sum += color::<Vec3>(down, &world, lights, 8)[0];
+            }
+            let mean = sum / n as f32;
+            assert!((mean - expected).abs() < 0.02 * expected, "{}", mean);
+        }
+    }
+}
@@ -7,13 +7,53 @@ use crate::camera::*;
 use crate::hit::*;
 use crate::material::*;
 use crate::obj::*;
-use crate::perlin::Perlin;
 use crate::texture::*;
 use crate::transf::*;
 use crate::util::*;
 use crate::vec3::*;
 
-pub fn regular_scene() -> Vec<Arc<dyn Hittable>> {
+pub struct Scene {
+    pub camera: Camera,
+    pub world: Vec<Arc<dyn Hittable>>,
+    pub lights: Vec<Arc<dyn Hittable>>,
+}
+
+// Scene constructors take the aspect ratio of the image
+pub type SceneFn = fn(f32) -> Scene;
+
+// Built-in scenes by name, as listed by --list-scenes
+pub const SCENES: [(&str, SceneFn); 10] = [
+    ("regular", regular_scene),
+    ("random", random_scene),
+    ("two_spheres", two_spheres_scene),
+    ("two_perlin_spheres", two_perlin_spheres_scene),
+    ("earth", earth_scene),
+    ("simple_light", simple_light),
+    ("cornell_box", cornell_box),
+    ("cornell_smoke", cornell_smoke_scene),
+    ("final", final_scene),
+    ("cornell_mc", cornell_mc),
+];
+
+pub fn find_scene(name: &str) -> Option<(&'static str, SceneFn)> {
+    SCENES.iter().find(|(n, _)| *n == name).cloned()
+}
+
+fn pinhole_camera(lookfrom: Vec3, lookat: Vec3, vfov: f32, aspect: f32) -> Camera {
+    Camera::new(
+        lookfrom,
+        lookat,
+        Vec3::new(0.0, 1.0, 0.0),
+        vfov,
+        aspect,
+        0.0,
+        10.0,
+        0.0,
+        1.0,
+    )
+}
+
+pub fn regular_scene(aspect: f32) -> Scene {
     let world: Vec<Arc<dyn Hittable>> = vec![
         Arc::new(Sphere::new(
             Vec3::new(0.0, 0.0, -1.0),
@@ -45,14 +85,23 @@ pub fn regular_scene() -> Vec<Arc<dyn Hittable>> {
             Arc::new(Dielectric::new(1.5)),
         )),
     ];
-    world
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(-2.0, 2.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            90.0,
+            aspect,
+        ),
+        world,
+        lights: Vec::new(),
+    }
 }
 
-pub fn random_scene() -> Vec<Arc<dyn Hittable>> {
+pub fn random_scene(aspect: f32) -> Scene {
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
     let checker = Arc::new(CheckerTexture::new(
-        Box::new(ConstantTexture::new(Vec3::new(0.2, 0.3, 0.1))),
-        Box::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9))),
+        Arc::new(ConstantTexture::new(Vec3::new(0.2, 0.3, 0.1))),
+        Arc::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9))),
     ));
     scene.push(Arc::new(Sphere::new(
         Vec3::new(0.0, -1000.0, -1.0),
@@ -125,18 +174,27 @@ pub fn random_scene() -> Vec<Arc<dyn Hittable>> {
         1.0,
         Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0)),
     )));
-    scene
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            20.0,
+            aspect,
+        ),
+        world: scene,
+        lights: Vec::new(),
+    }
 }
 
-pub fn two_spheres_scene() -> Vec<Arc<dyn Hittable>> {
+pub fn two_spheres_scene(aspect: f32) -> Scene {
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
     let checker = Arc::new(CheckerTexture::new(
-        Box::new(ConstantTexture::new(Vec3::new(0.2, 0.3, 0.1))),
-        Box::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9))),
+        Arc::new(ConstantTexture::new(Vec3::new(0.2, 0.3, 0.1))),
+        Arc::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9))),
     ));
     let checker2 = Arc::new(CheckerTexture::new(
-        Box::new(ConstantTexture::new(Vec3::new(0.1, 0.2, 0.3))),
-        Box::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9))),
+        Arc::new(ConstantTexture::new(Vec3::new(0.1, 0.2, 0.3))),
+        Arc::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9))),
     ));
     scene.push(Arc::new(Sphere::new(
         Vec3::new(0.0, -10.0, 0.0),
@@ -148,12 +206,21 @@ pub fn two_spheres_scene() -> Vec<Arc<dyn Hittable>> {
         10.0,
         Arc::new(Lambertian::new(checker2)),
     )));
-    scene
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            20.0,
+            aspect,
+        ),
+        world: scene,
+        lights: Vec::new(),
+    }
 }
 
-pub fn two_perlin_spheres_scene() -> Vec<Arc<dyn Hittable>> {
+pub fn two_perlin_spheres_scene(aspect: f32) -> Scene {
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
-    let perlin_texture = Arc::new(NoiseTexture::new(4.0, Perlin::new()));
+    let perlin_texture = Arc::new(NoiseTexture::new(4.0));
     scene.push(Arc::new(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
         1000.0,
@@ -164,12 +231,21 @@ pub fn two_perlin_spheres_scene() -> Vec<Arc<dyn Hittable>> {
         2.0,
         Arc::new(Lambertian::new(perlin_texture)),
     )));
-    scene
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            20.0,
+            aspect,
+        ),
+        world: scene,
+        lights: Vec::new(),
+    }
 }
 
-pub fn earth_scene() -> Vec<Arc<dyn Hittable>> {
+pub fn earth_scene(aspect: f32) -> Scene {
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
-    let perlin_texture = Arc::new(NoiseTexture::new(4.0, Perlin::new()));
+    let perlin_texture = Arc::new(NoiseTexture::new(4.0));
     scene.push(Arc::new(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
         1000.0,
@@ -185,11 +261,20 @@ pub fn earth_scene() -> Vec<Arc<dyn Hittable>> {
         2.0,
         Arc::new(Lambertian::new(image_texture)),
     )));
-    scene
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(13.0, 2.0, 3.0),
+            Vec3::new(0.0, 0.0, 0.0),
+            20.0,
+            aspect,
+        ),
+        world: scene,
+        lights: Vec::new(),
+    }
 }
 
-pub fn simple_light() -> Vec<Arc<dyn Hittable>> {
-    let perlin_texture = Arc::new(NoiseTexture::new(4.0, Perlin::new()));
+pub fn simple_light(aspect: f32) -> Scene {
+    let perlin_texture = Arc::new(NoiseTexture::new(4.0));
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
     scene.push(Arc::new(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
@@ -203,23 +288,35 @@ pub fn simple_light() -> Vec<Arc<dyn Hittable>> {
     )));
 
     let constant_texture = Arc::new(ConstantTexture::new(Vec3::new(4.0, 4.0, 4.0)));
-    scene.push(Arc::new(Sphere::new(
+    let sphere_light: Arc<dyn Hittable> = Arc::new(Sphere::new(
         Vec3::new(0.0, 7.0, 0.0),
         2.0,
         Arc::new(DiffuseLight::new(constant_texture.clone())),
-    )));
-    scene.push(Arc::new(XYRect::new(
+    ));
+    let rect_light: Arc<dyn Hittable> = Arc::new(XYRect::new(
         3.0,
         5.0,
         1.0,
         3.0,
         -2.0,
         Arc::new(DiffuseLight::new(constant_texture.clone())),
-    )));
-    scene
+    ));
+    scene.push(sphere_light.clone());
+    scene.push(rect_light.clone());
+
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(26.0, 3.0, 6.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            20.0,
+            aspect,
+        ),
+        world: scene,
+        lights: vec![sphere_light, rect_light],
+    }
 }
 
-pub fn cornell_box() -> Vec<Arc<dyn Hittable>> {
+pub fn cornell_box(aspect: f32) -> Scene {
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
 
     let red = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
@@ -239,9 +336,10 @@ pub fn cornell_box() -> Vec<Arc<dyn Hittable>> {
         0.0, 555.0, 0.0, 555.0, 555.0, green,
     )))));
     scene.push(Arc::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)));
-    scene.push(Arc::new(XZRect::new(
+    let light_shape: Arc<dyn Hittable> = Arc::new(FlipNormals::new(Arc::new(XZRect::new(
         213.0, 343.0, 227.0, 332.0, 554.0, light,
-    )));
+    ))));
+    scene.push(light_shape.clone());
     scene.push(Arc::new(FlipNormals::new(Arc::new(XZRect::new(
         0.0,
         555.0,
@@ -286,10 +384,19 @@ pub fn cornell_box() -> Vec<Arc<dyn Hittable>> {
         Vec3::new(265.0, 0.0, 295.0),
     )));
 
-    scene
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(278.0, 278.0, -800.0),
+            Vec3::new(278.0, 278.0, 0.0),
+            40.0,
+            aspect,
+        ),
+        world: scene,
+        lights: vec![light_shape],
+    }
 }
 
-pub fn cornell_smoke_scene() -> Vec<Arc<dyn Hittable>> {
+pub fn cornell_smoke_scene(aspect: f32) -> Scene {
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
     let red = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
         0.65, 0.05, 0.05,
@@ -308,9 +415,10 @@ pub fn cornell_smoke_scene() -> Vec<Arc<dyn Hittable>> {
         0.0, 555.0, 0.0, 555.0, 555.0, green,
     )))));
     scene.push(Arc::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)));
-    scene.push(Arc::new(XZRect::new(
+    let light_shape: Arc<dyn Hittable> = Arc::new(FlipNormals::new(Arc::new(XZRect::new(
         113.0, 443.0, 127.0, 432.0, 554.0, light,
-    )));
+    ))));
+    scene.push(light_shape.clone());
     scene.push(Arc::new(FlipNormals::new(Arc::new(XZRect::new(
         0.0,
         555.0,
@@ -359,10 +467,19 @@ pub fn cornell_smoke_scene() -> Vec<Arc<dyn Hittable>> {
         0.01,
         Arc::new(ConstantTexture::new(Vec3::new(1.0, 1.0, 1.0))),
     )));
-    scene
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(278.0, 278.0, -800.0),
+            Vec3::new(278.0, 278.0, 0.0),
+            40.0,
+            aspect,
+        ),
+        world: scene,
+        lights: vec![light_shape],
+    }
 }
 
-pub fn final_scene() -> Vec<Arc<dyn Hittable>> {
+pub fn final_scene(aspect: f32) -> Scene {
     // Create scene vector
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
 
@@ -388,15 +505,16 @@ pub fn final_scene() -> Vec<Arc<dyn Hittable>> {
             )));
         }
     }
-    scene.push(Arc::new(BvhNode::new(&mut boxes1, 0.0, 1.0)));
+    scene.push(Arc::new(FlatBvh::new(&boxes1, 0.0, 1.0)));
 
     // Create and add lighting to scene
     let light = Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
         Vec3::new(7.0, 7.0, 7.0),
     ))));
-    scene.push(Arc::new(XZRect::new(
+    let light_shape: Arc<dyn Hittable> = Arc::new(FlipNormals::new(Arc::new(XZRect::new(
         123.0, 423.0, 147.0, 412.0, 554.0, light,
-    )));
+    ))));
+    scene.push(light_shape.clone());
 
     // Add moving sphere
     let center = Vec3::new(400.0, 400.0, 200.0);
@@ -463,42 +581,49 @@ pub fn final_scene() -> Vec<Arc<dyn Hittable>> {
     )));
 
     // Add perlin textured sphere
-    let perlin_texture = Arc::new(NoiseTexture::new(0.1, Perlin::new()));
+    let perlin_texture = Arc::new(NoiseTexture::new(0.1));
     scene.push(Arc::new(Sphere::new(
         Vec3::new(220.0, 280.0, 300.0),
         80.0,
         Arc::new(Lambertian::new(perlin_texture.clone())),
     )));
 
-    // Add rotated "box" of spheres
-    let mut box_of_spheres: Vec<Arc<dyn Hittable>> = Vec::new();
+    // Add rotated "box" of spheres, all instances of the same sphere
     let white = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
         0.73, 0.73, 0.73,
     )))));
+    let sphere: Arc<dyn Hittable> = Arc::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 10.0, white));
+    let place = Transform::rotate(Vec3::new(0.0, 1.0, 0.0), 15.0)
+        .then(&Transform::translate(Vec3::new(-100.0, 270.0, 395.0)));
+    let mut box_of_spheres: Vec<Arc<dyn Hittable>> = Vec::new();
     for _ in 0..1000 {
-        box_of_spheres.push(Arc::new(Sphere::new(
-            Vec3::new(
-                rand_float() * 165.0,
-                rand_float() * 165.0,
-                rand_float() * 165.0,
-            ),
-            10.0,
-            white.clone(),
+        let offset = Vec3::new(
+            rand_float() * 165.0,
+            rand_float() * 165.0,
+            rand_float() * 165.0,
+        );
+        box_of_spheres.push(Arc::new(Instance::new(
+            sphere.clone(),
+            Transform::translate(offset).then(&place),
+            None,
         )));
     }
-    scene.push(Arc::new(Translate::new(
-        Arc::new(RotateY::new(
-            Arc::new(BvhNode::new(&mut box_of_spheres, 0.0, 1.0)),
-            15.0,
-        )),
-        Vec3::new(-100.0, 270.0, 395.0),
-    )));
+    scene.push(Arc::new(FlatBvh::new(&box_of_spheres, 0.0, 1.0)));
 
     // All done, return the scene!
-    scene
+    Scene {
+        camera: pinhole_camera(
+            Vec3::new(478.0, 278.0, -600.0),
+            Vec3::new(278.0, 278.0, 0.0),
+            40.0,
+            aspect,
+        ),
+        world: scene,
+        lights: vec![light_shape],
+    }
 }
 
-pub fn cornell_mc(aspect: f32) -> (Camera, Vec<Arc<dyn Hittable>>) {
+pub fn cornell_mc(aspect: f32) -> Scene {
     let mut scene: Vec<Arc<dyn Hittable>> = Vec::new();
     let red = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
         0.65, 0.05, 0.05,
@@ -517,9 +642,10 @@ pub fn cornell_mc(aspect: f32) -> (Camera, Vec<Arc<dyn Hittable>>) {
         0.0, 555.0, 0.0, 555.0, 555.0, green,
     )))));
     scene.push(Arc::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, red)));
-    scene.push(Arc::new(FlipNormals::new(Arc::new(XZRect::new(
+    let light_shape: Arc<dyn Hittable> = Arc::new(FlipNormals::new(Arc::new(XZRect::new(
         213.0, 343.0, 227.0, 332.0, 554.0, light,
-    )))));
+    ))));
+    scene.push(light_shape.clone());
     scene.push(Arc::new(FlipNormals::new(Arc::new(XZRect::new(
         0.0,
         555.0,
@@ -556,11 +682,9 @@ pub fn cornell_mc(aspect: f32) -> (Camera, Vec<Arc<dyn Hittable>>) {
     )));
 
     let glass = Arc::new(Dielectric::new(1.5));
-    scene.push(Arc::new(Sphere::new(
-        Vec3::new(190.0, 90.0, 190.0),
-        90.0,
-        glass,
-    )));
+    let glass_sphere: Arc<dyn Hittable> =
+        Arc::new(Sphere::new(Vec3::new(190.0, 90.0, 190.0), 90.0, glass));
+    scene.push(glass_sphere.clone());
 
     let lookfrom = Vec3::new(278.0, 278.0, -800.0);
     let lookat = Vec3::new(278.0, 278.0, 0.0);
@@ -583,5 +707,9 @@ pub fn cornell_mc(aspect: f32) -> (Camera, Vec<Arc<dyn Hittable>>) {
         t1,
     );
 
-    (cam, scene)
+    Scene {
+        camera: cam,
+        world: scene,
+        lights: vec![light_shape, glass_sphere],
+    }
 }
@@ -0,0 +1,1028 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::bvh::FlatBvh;
+use crate::camera::Camera;
+use crate::gltffile::load_gltf;
+use crate::hit::Hittable;
+use crate::material::*;
+use crate::obj::*;
+use crate::objfile::{load_obj, MeshError};
+use crate::plyfile::load_ply;
+use crate::scene::Scene;
+use crate::stlfile::load_stl;
+use crate::texture::*;
+use crate::transf::*;
+use crate::vec3::Vec3;
+
+// A scene file is TOML with five parts:
+//
+//   [camera]            lookfrom, lookat, vup, vfov, aperture, focus_dist, time0, time1
+//   [textures.<name>]   type = "constant" | "checker" | "noise" | "image"
+//   [materials.<name>]  type = "lambertian" | "metal" | "conductor" | "dielectric"
+//                              | "principled" | "diffuse_light" | "isotropic"
+//                       a dielectric's index is a `ref_idx`, a `glass` of "bk7" |
+//                       "fused_silica" | "diamond", `cauchy` or `sellmeier_b/c`
+//   [prototypes.<name>] an object to be instanced
+//   [[objects]]         type = "sphere" | "moving_sphere" | "xy_rect" | "xz_rect" | "yz_rect"
+//                              | "box" | "triangle" | "mesh" | "instance" | "constant_medium"
+//
+// Objects refer to materials and textures by name so they share the same
+// Arc. Objects marked `light = true` also go into the light list used for
+// importance sampling, and a `motion` list of timed transforms blurs them
+// between keys. A mesh's `keys` list of timed files deforms it the same
+// way. See scenes/ for examples.
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    // A glTF scene that could not be imported
+    Import(MeshError),
+    Invalid {
+        line: Option<usize>,
+        field: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            SceneError::Parse(e) => write!(f, "{}", e),
+            SceneError::Import(e) => write!(f, "{}", e),
+            SceneError::Invalid {
+                line: Some(line),
+                field,
+                message,
+            } => write!(f, "{} for key `{}` at line {}", message, field, line),
+            SceneError::Invalid {
+                line: None,
+                field,
+                message,
+            } => write!(f, "{} for key `{}`", message, field),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    camera: CameraDesc,
+    #[serde(default)]
+    textures: HashMap<String, TextureDesc>,
+    #[serde(default)]
+    materials: HashMap<String, MaterialDesc>,
+    #[serde(default)]
+    prototypes: HashMap<String, ObjectDesc>,
+    #[serde(default)]
+    objects: Vec<ObjectDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    lookfrom: [f32; 3],
+    lookat: [f32; 3],
+    #[serde(default = "default_vup")]
+    vup: [f32; 3],
+    vfov: f32,
+    #[serde(default)]
+    aperture: f32,
+    #[serde(default = "default_focus_dist")]
+    focus_dist: f32,
+    #[serde(default)]
+    time0: f32,
+    #[serde(default = "default_time1")]
+    time1: f32,
+}
+
+fn default_vup() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_focus_dist() -> f32 {
+    10.0
+}
+
+fn default_time1() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextureDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    color: Option<[f32; 3]>,
+    odd: Option<Spanned<String>>,
+    even: Option<Spanned<String>>,
+    scale: Option<f32>,
+    path: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    texture: Option<Spanned<String>>,
+    color: Option<[f32; 3]>,
+    albedo: Option<[f32; 3]>,
+    fuzz: Option<f32>,
+    ref_idx: Option<f32>,
+    glass: Option<Spanned<String>>,
+    cauchy: Option<[f32; 2]>,
+    sellmeier_b: Option<[f32; 3]>,
+    sellmeier_c: Option<[f32; 3]>,
+    metal: Option<Spanned<String>>,
+    eta: Option<[f32; 3]>,
+    k: Option<[f32; 3]>,
+    roughness: Option<f32>,
+    roughness_v: Option<f32>,
+    tint: Option<[f32; 3]>,
+    tint_distance: Option<f32>,
+    metallic: Option<f32>,
+    specular: Option<f32>,
+    specular_tint: Option<f32>,
+    sheen: Option<f32>,
+    clearcoat: Option<f32>,
+    transmission: Option<f32>,
+    #[serde(default)]
+    maps: MapsDesc,
+}
+
+// Textures standing in for the numbers of a principled material
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct MapsDesc {
+    metallic: Option<Spanned<String>>,
+    roughness: Option<Spanned<String>>,
+    specular: Option<Spanned<String>>,
+    specular_tint: Option<Spanned<String>>,
+    sheen: Option<Spanned<String>>,
+    clearcoat: Option<Spanned<String>>,
+    transmission: Option<Spanned<String>>,
+    ref_idx: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    material: Option<Spanned<String>>,
+    texture: Option<Spanned<String>>,
+    center: Option<[f32; 3]>,
+    center0: Option<[f32; 3]>,
+    center1: Option<[f32; 3]>,
+    time0: Option<f32>,
+    time1: Option<f32>,
+    radius: Option<f32>,
+    x0: Option<f32>,
+    x1: Option<f32>,
+    y0: Option<f32>,
+    y1: Option<f32>,
+    z0: Option<f32>,
+    z1: Option<f32>,
+    k: Option<f32>,
+    p0: Option<[f32; 3]>,
+    p1: Option<[f32; 3]>,
+    p2: Option<[f32; 3]>,
+    file: Option<Spanned<String>>,
+    prototype: Option<Spanned<String>>,
+    density: Option<f32>,
+    boundary: Option<Box<ObjectDesc>>,
+    #[serde(default)]
+    transforms: Vec<TransformDesc>,
+    #[serde(default)]
+    motion: Vec<KeyDesc>,
+    #[serde(default)]
+    keys: Vec<VertexKeyDesc>,
+    #[serde(default)]
+    light: bool,
+}
+
+// Where an object is at `time`, after its own `transforms`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyDesc {
+    time: f32,
+    #[serde(default)]
+    transforms: Vec<TransformDesc>,
+}
+
+// Where the vertices of a mesh are at `time`, from another file with the
+// same faces
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VertexKeyDesc {
+    time: f32,
+    file: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TransformDesc {
+    Translate { offset: [f32; 3] },
+    RotateY { angle: f32 },
+    // Degrees about any axis through the origin
+    Rotate { axis: [f32; 3], angle: f32 },
+    Scale { factor: [f32; 3] },
+    // A row-major affine matrix, the last row is usually 0 0 0 1
+    Matrix { rows: [[f32; 4]; 4] },
+    FlipNormals,
+}
+
+fn vec3(a: [f32; 3]) -> Vec3 {
+    Vec3::new(a[0], a[1], a[2])
+}
+
+// Resolves names and byte offsets in the source back to something a human
+// can find in their editor.
+struct Loader<'a> {
+    src: &'a str,
+    dir: &'a Path,
+    desc: &'a SceneDesc,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    prototypes: HashMap<String, Arc<dyn Hittable>>,
+    resolving: Vec<String>,
+    // Prototypes being built, to catch ones that instance themselves
+    instancing: Vec<String>,
+}
+
+impl<'a> Loader<'a> {
+    fn line(&self, offset: usize) -> usize {
+        self.src[..offset].matches('\n').count() + 1
+    }
+
+    fn invalid<T>(&self, offset: usize, field: &str, message: String) -> Result<T, SceneError> {
+        Err(SceneError::Invalid {
+            line: Some(self.line(offset)),
+            field: field.to_string(),
+            message,
+        })
+    }
+
+    fn require<T>(
+        &self,
+        value: Option<T>,
+        kind: &Spanned<String>,
+        field: &str,
+    ) -> Result<T, SceneError> {
+        match value {
+            Some(v) => Ok(v),
+            None => self.invalid(
+                kind.start(),
+                field,
+                format!("`{}` is missing field `{}`", kind.get_ref(), field),
+            ),
+        }
+    }
+
+    fn texture(
+        &mut self,
+        name: &Spanned<String>,
+        field: &str,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        if let Some(tex) = self.textures.get(name.get_ref()) {
+            return Ok(tex.clone());
+        }
+        let desc = match self.desc.textures.get(name.get_ref()) {
+            Some(desc) => desc,
+            None => {
+                return self.invalid(
+                    name.start(),
+                    field,
+                    format!("unknown texture `{}`", name.get_ref()),
+                )
+            }
+        };
+        if self.resolving.contains(name.get_ref()) {
+            return self.invalid(
+                name.start(),
+                field,
+                format!("texture `{}` refers to itself", name.get_ref()),
+            );
+        }
+
+        self.resolving.push(name.get_ref().clone());
+        let tex = self.build_texture(desc);
+        self.resolving.pop();
+
+        let tex = tex?;
+        self.textures.insert(name.get_ref().clone(), tex.clone());
+        Ok(tex)
+    }
+
+    fn build_texture(&mut self, desc: &TextureDesc) -> Result<Arc<dyn Texture>, SceneError> {
+        let kind = &desc.kind;
+        let tex: Arc<dyn Texture> = match kind.get_ref().as_str() {
+            "constant" => Arc::new(ConstantTexture::new(vec3(
+                self.require(desc.color, kind, "color")?,
+            ))),
+            "checker" => {
+                let odd = self.require(desc.odd.as_ref(), kind, "odd")?;
+                let even = self.require(desc.even.as_ref(), kind, "even")?;
+                Arc::new(CheckerTexture::new(
+                    self.texture(odd, "odd")?,
+                    self.texture(even, "even")?,
+                ))
+            }
+            "noise" => Arc::new(NoiseTexture::new(self.require(desc.scale, kind, "scale")?)),
+            "image" => {
+                let path = self.require(desc.path.as_ref(), kind, "path")?;
+                match ImageTexture::open(&self.dir.join(path.get_ref())) {
+                    Ok(tex) => Arc::new(tex),
+                    Err(e) => {
+                        return self.invalid(
+                            path.start(),
+                            "path",
+                            format!("could not load `{}`: {}", path.get_ref(), e),
+                        )
+                    }
+                }
+            }
+            other => {
+                return self.invalid(
+                    kind.start(),
+                    "type",
+                    format!("unknown texture type `{}`", other),
+                )
+            }
+        };
+        Ok(tex)
+    }
+
+    // Materials take either a named `texture` or an inline `color`.
+    fn albedo(&mut self, desc: &MaterialDesc) -> Result<Arc<dyn Texture>, SceneError> {
+        match (&desc.texture, desc.color) {
+            (Some(name), None) => self.texture(name, "texture"),
+            (None, Some(color)) => Ok(Arc::new(ConstantTexture::new(vec3(color)))),
+            (Some(name), Some(_)) => self.invalid(
+                name.start(),
+                "texture",
+                "only one of `texture` and `color` may be given".to_string(),
+            ),
+            (None, None) => self.invalid(
+                desc.kind.start(),
+                "texture",
+                format!("`{}` needs a `texture` or a `color`", desc.kind.get_ref()),
+            ),
+        }
+    }
+
+    // A number or a texture from `maps` for one parameter of a principled
+    // material, or its default
+    fn parameter(
+        &mut self,
+        value: Option<f32>,
+        map: &Option<Spanned<String>>,
+        field: &str,
+        default: &Arc<dyn Texture>,
+    ) -> Result<Arc<dyn Texture>, SceneError> {
+        match (value, map) {
+            (None, None) => Ok(default.clone()),
+            (Some(x), None) => Ok(Arc::new(ConstantTexture::new(Vec3::new(x, x, x)))),
+            (None, Some(name)) => self.texture(name, field),
+            (Some(_), Some(name)) => self.invalid(
+                name.start(),
+                field,
+                format!("only one of `{}` and `maps.{}` may be given", field, field),
+            ),
+        }
+    }
+
+    fn principled(&mut self, desc: &MaterialDesc) -> Result<Principled, SceneError> {
+        let mut mat = Principled::new(self.albedo(desc)?);
+        let maps = &desc.maps;
+        mat.metallic = self.parameter(desc.metallic, &maps.metallic, "metallic", &mat.metallic)?;
+        mat.roughness =
+            self.parameter(desc.roughness, &maps.roughness, "roughness", &mat.roughness)?;
+        mat.specular = self.parameter(desc.specular, &maps.specular, "specular", &mat.specular)?;
+        mat.specular_tint = self.parameter(
+            desc.specular_tint,
+            &maps.specular_tint,
+            "specular_tint",
+            &mat.specular_tint,
+        )?;
+        mat.sheen = self.parameter(desc.sheen, &maps.sheen, "sheen", &mat.sheen)?;
+        mat.clearcoat =
+            self.parameter(desc.clearcoat, &maps.clearcoat, "clearcoat", &mat.clearcoat)?;
+        mat.transmission = self.parameter(
+            desc.transmission,
+            &maps.transmission,
+            "transmission",
+            &mat.transmission,
+        )?;
+        mat.ior = self.parameter(desc.ref_idx, &maps.ref_idx, "ref_idx", &mat.ior)?;
+        Ok(mat)
+    }
+
+    // Conductors take either a named `metal` or its `eta` and `k`.
+    fn conductor_ior(&self, desc: &MaterialDesc) -> Result<(Vec3, Vec3), SceneError> {
+        match (&desc.metal, desc.eta, desc.k) {
+            (Some(name), None, None) => match name.get_ref().as_str() {
+                "gold" => Ok(GOLD),
+                "silver" => Ok(SILVER),
+                "copper" => Ok(COPPER),
+                "aluminium" => Ok(ALUMINIUM),
+                other => self.invalid(
+                    name.start(),
+                    "metal",
+                    format!(
+                        "unknown metal `{}`, expected gold, silver, copper or aluminium",
+                        other
+                    ),
+                ),
+            },
+            (None, Some(eta), Some(k)) => Ok((vec3(eta), vec3(k))),
+            (Some(name), _, _) => self.invalid(
+                name.start(),
+                "metal",
+                "only one of `metal` and `eta` with `k` may be given".to_string(),
+            ),
+            (None, _, _) => self.invalid(
+                desc.kind.start(),
+                "metal",
+                "`conductor` needs a `metal` or both `eta` and `k`".to_string(),
+            ),
+        }
+    }
+
+    // Dielectrics take one of a `ref_idx`, a named `glass`, the `cauchy`
+    // coefficients or `sellmeier_b` with `sellmeier_c`.
+    fn dielectric_ior(&self, desc: &MaterialDesc) -> Result<Ior, SceneError> {
+        let given = [
+            desc.ref_idx.is_some(),
+            desc.glass.is_some(),
+            desc.cauchy.is_some(),
+            desc.sellmeier_b.is_some() || desc.sellmeier_c.is_some(),
+        ];
+        if given.iter().filter(|g| **g).count() > 1 {
+            return self.invalid(
+                desc.kind.start(),
+                "ref_idx",
+                "only one of `ref_idx`, `glass`, `cauchy` and `sellmeier_b` with `sellmeier_c` may be given"
+                    .to_string(),
+            );
+        }
+        match (&desc.glass, desc.sellmeier_b, desc.sellmeier_c) {
+            (Some(name), _, _) => match name.get_ref().as_str() {
+                "bk7" => Ok(Ior::BK7),
+                "fused_silica" => Ok(Ior::FUSED_SILICA),
+                "diamond" => Ok(Ior::DIAMOND),
+                other => self.invalid(
+                    name.start(),
+                    "glass",
+                    format!(
+                        "unknown glass `{}`, expected bk7, fused_silica or diamond",
+                        other
+                    ),
+                ),
+            },
+            (None, Some(b), Some(c)) => Ok(Ior::Sellmeier(b, c)),
+            (None, Some(_), None) | (None, None, Some(_)) => self.invalid(
+                desc.kind.start(),
+                "sellmeier_b",
+                "`sellmeier_b` and `sellmeier_c` go together".to_string(),
+            ),
+            (None, None, None) => match desc.cauchy {
+                Some([a, b]) => Ok(Ior::Cauchy(a, b)),
+                None => Ok(Ior::Constant(self.require(
+                    desc.ref_idx,
+                    &desc.kind,
+                    "ref_idx",
+                )?)),
+            },
+        }
+    }
+
+    fn material(&mut self, name: &Spanned<String>) -> Result<Arc<dyn Material>, SceneError> {
+        if let Some(mat) = self.materials.get(name.get_ref()) {
+            return Ok(mat.clone());
+        }
+        let desc = match self.desc.materials.get(name.get_ref()) {
+            Some(desc) => desc,
+            None => {
+                return self.invalid(
+                    name.start(),
+                    "material",
+                    format!("unknown material `{}`", name.get_ref()),
+                )
+            }
+        };
+
+        let kind = &desc.kind;
+        let mat: Arc<dyn Material> = match kind.get_ref().as_str() {
+            "lambertian" => Arc::new(Lambertian::new(self.albedo(desc)?)),
+            "metal" => Arc::new(Metal::new(
+                vec3(self.require(desc.albedo, kind, "albedo")?),
+                desc.fuzz.unwrap_or(0.0),
+            )),
+            "conductor" => {
+                let (eta, k) = self.conductor_ior(desc)?;
+                let roughness = self.require(desc.roughness, kind, "roughness")?;
+                Arc::new(Conductor::anisotropic(
+                    eta,
+                    k,
+                    roughness,
+                    desc.roughness_v.unwrap_or(roughness),
+                ))
+            }
+            "dielectric" => {
+                let ior = self.dielectric_ior(desc)?;
+                // Rough or tinted glass, smooth and clear otherwise. Only
+                // smooth glass disperses.
+                match (ior, desc.roughness, desc.tint) {
+                    (ior, None, None) => Arc::new(Dielectric::with_ior(ior)),
+                    (Ior::Constant(ref_idx), roughness, None) => {
+                        Arc::new(RoughDielectric::new(ref_idx, roughness.unwrap_or(0.0)))
+                    }
+                    (Ior::Constant(ref_idx), roughness, Some(tint)) => {
+                        Arc::new(RoughDielectric::tinted(
+                            ref_idx,
+                            roughness.unwrap_or(0.0),
+                            vec3(tint),
+                            self.require(desc.tint_distance, kind, "tint_distance")?,
+                        ))
+                    }
+                    _ => {
+                        return self.invalid(
+                            kind.start(),
+                            "roughness",
+                            "rough or tinted glass needs a constant `ref_idx`".to_string(),
+                        )
+                    }
+                }
+            }
+            "principled" => Arc::new(self.principled(desc)?),
+            "diffuse_light" => Arc::new(DiffuseLight::new(self.albedo(desc)?)),
+            "isotropic" => Arc::new(Isotropic::new(self.albedo(desc)?)),
+            other => {
+                return self.invalid(
+                    kind.start(),
+                    "type",
+                    format!("unknown material type `{}`", other),
+                )
+            }
+        };
+        self.materials.insert(name.get_ref().clone(), mat.clone());
+        Ok(mat)
+    }
+
+    fn object(&mut self, desc: &ObjectDesc) -> Result<Arc<dyn Hittable>, SceneError> {
+        let obj = self.still_object(desc)?;
+        if desc.motion.is_empty() {
+            return Ok(obj);
+        }
+        let mut keys = Vec::new();
+        for key in &desc.motion {
+            keys.push((key.time, self.compose(&key.transforms, &desc.kind)?));
+        }
+        Ok(Arc::new(Keyframed::new(obj, keys)))
+    }
+
+    fn still_object(&mut self, desc: &ObjectDesc) -> Result<Arc<dyn Hittable>, SceneError> {
+        let kind = &desc.kind;
+        let obj: Arc<dyn Hittable> = match kind.get_ref().as_str() {
+            "sphere" => Arc::new(Sphere::new(
+                vec3(self.require(desc.center, kind, "center")?),
+                self.require(desc.radius, kind, "radius")?,
+                self.object_material(desc)?,
+            )),
+            "moving_sphere" => Arc::new(MovingSphere::new(
+                vec3(self.require(desc.center0, kind, "center0")?),
+                vec3(self.require(desc.center1, kind, "center1")?),
+                desc.time0.unwrap_or(0.0),
+                desc.time1.unwrap_or(1.0),
+                self.require(desc.radius, kind, "radius")?,
+                self.object_material(desc)?,
+            )),
+            "xy_rect" => Arc::new(XYRect::new(
+                self.require(desc.x0, kind, "x0")?,
+                self.require(desc.x1, kind, "x1")?,
+                self.require(desc.y0, kind, "y0")?,
+                self.require(desc.y1, kind, "y1")?,
+                self.require(desc.k, kind, "k")?,
+                self.object_material(desc)?,
+            )),
+            "xz_rect" => Arc::new(XZRect::new(
+                self.require(desc.x0, kind, "x0")?,
+                self.require(desc.x1, kind, "x1")?,
+                self.require(desc.z0, kind, "z0")?,
+                self.require(desc.z1, kind, "z1")?,
+                self.require(desc.k, kind, "k")?,
+                self.object_material(desc)?,
+            )),
+            "yz_rect" => Arc::new(YZRect::new(
+                self.require(desc.y0, kind, "y0")?,
+                self.require(desc.y1, kind, "y1")?,
+                self.require(desc.z0, kind, "z0")?,
+                self.require(desc.z1, kind, "z1")?,
+                self.require(desc.k, kind, "k")?,
+                self.object_material(desc)?,
+            )),
+            "box" => Arc::new(BoxShape::new(
+                vec3(self.require(desc.p0, kind, "p0")?),
+                vec3(self.require(desc.p1, kind, "p1")?),
+                self.object_material(desc)?,
+            )),
+            "triangle" => Arc::new(Triangle::new(
+                vec3(self.require(desc.p0, kind, "p0")?),
+                vec3(self.require(desc.p1, kind, "p1")?),
+                vec3(self.require(desc.p2, kind, "p2")?),
+                self.object_material(desc)?,
+            )),
+            "mesh" => self.mesh(desc)?,
+            "instance" => return self.instance(desc),
+            "constant_medium" => {
+                let boundary = self.require(desc.boundary.as_ref(), kind, "boundary")?;
+                let texture = self.require(desc.texture.as_ref(), kind, "texture")?;
+                Arc::new(ConstantMedium::new(
+                    self.object(boundary)?,
+                    self.require(desc.density, kind, "density")?,
+                    self.texture(texture, "texture")?,
+                ))
+            }
+            other => {
+                return self.invalid(
+                    kind.start(),
+                    "type",
+                    format!("unknown object type `{}`", other),
+                )
+            }
+        };
+
+        let mut obj = obj;
+        for t in &desc.transforms {
+            obj = match t {
+                TransformDesc::Translate { offset } => Arc::new(Translate::new(obj, vec3(*offset))),
+                TransformDesc::RotateY { angle } => Arc::new(RotateY::new(obj, *angle)),
+                TransformDesc::FlipNormals => Arc::new(FlipNormals::new(obj)),
+                _ => Arc::new(Transformed::new(obj, self.affine(t, kind)?)),
+            };
+        }
+        Ok(obj)
+    }
+
+    fn affine(&self, t: &TransformDesc, kind: &Spanned<String>) -> Result<Transform, SceneError> {
+        let invalid = |message: &str| self.invalid(kind.start(), "transforms", message.to_string());
+        match t {
+            TransformDesc::Translate { offset } => Ok(Transform::translate(vec3(*offset))),
+            TransformDesc::RotateY { angle } => {
+                Ok(Transform::rotate(Vec3::new(0.0, 1.0, 0.0), *angle))
+            }
+            TransformDesc::Rotate { axis, .. } if vec3(*axis).mag() == 0.0 => {
+                invalid("rotation axis must not be zero")
+            }
+            TransformDesc::Rotate { axis, angle } => Ok(Transform::rotate(vec3(*axis), *angle)),
+            TransformDesc::Scale { factor } if factor.contains(&0.0) => {
+                invalid("scale factors must not be zero")
+            }
+            TransformDesc::Scale { factor } => Ok(Transform::scale(vec3(*factor))),
+            TransformDesc::Matrix { rows } => match Transform::from_matrix(*rows) {
+                Some(transform) => Ok(transform),
+                None => invalid("matrix is not invertible"),
+            },
+            TransformDesc::FlipNormals => {
+                invalid("normals can only be flipped in the transforms of an object")
+            }
+        }
+    }
+
+    // The transforms in one matrix, for instances and motion keys
+    fn compose(
+        &self,
+        transforms: &[TransformDesc],
+        kind: &Spanned<String>,
+    ) -> Result<Transform, SceneError> {
+        let mut transform = Transform::default();
+        for t in transforms {
+            transform = transform.then(&self.affine(t, kind)?);
+        }
+        Ok(transform)
+    }
+
+    // A copy of a named prototype. Its transforms are folded into a single
+    // matrix instead of a chain of wrappers, and `material` replaces the
+    // prototype's materials if given.
+    fn instance(&mut self, desc: &ObjectDesc) -> Result<Arc<dyn Hittable>, SceneError> {
+        let kind = &desc.kind;
+        let name = self.require(desc.prototype.as_ref(), kind, "prototype")?;
+        let prototype = self.prototype(name)?;
+        let transform = self.compose(&desc.transforms, kind)?;
+        let material = match &desc.material {
+            Some(name) => Some(self.material(name)?),
+            None => None,
+        };
+        Ok(Arc::new(Instance::new(prototype, transform, material)))
+    }
+
+    fn prototype(&mut self, name: &Spanned<String>) -> Result<Arc<dyn Hittable>, SceneError> {
+        if let Some(obj) = self.prototypes.get(name.get_ref()) {
+            return Ok(obj.clone());
+        }
+        let desc = match self.desc.prototypes.get(name.get_ref()) {
+            Some(desc) => desc,
+            None => {
+                return self.invalid(
+                    name.start(),
+                    "prototype",
+                    format!("unknown prototype `{}`", name.get_ref()),
+                )
+            }
+        };
+        if self.instancing.contains(name.get_ref()) {
+            return self.invalid(
+                name.start(),
+                "prototype",
+                format!("prototype `{}` refers to itself", name.get_ref()),
+            );
+        }
+
+        self.instancing.push(name.get_ref().clone());
+        let obj = self.object(desc);
+        self.instancing.pop();
+
+        let obj = obj?;
+        self.prototypes.insert(name.get_ref().clone(), obj.clone());
+        Ok(obj)
+    }
+
+    // An OBJ, PLY or STL file. `material` is for faces without one from an
+    // MTL library, PLY vertex colors are used if it is not given. Each of
+    // the `keys` is a file with the same faces and the vertices where they
+    // are at its time.
+    fn mesh(&mut self, desc: &ObjectDesc) -> Result<Arc<dyn Hittable>, SceneError> {
+        let file = self.require(desc.file.as_ref(), &desc.kind, "file")?;
+        let material = match &desc.material {
+            Some(name) => Some(self.material(name)?),
+            None => None,
+        };
+        let mut meshes = self.meshes(file, material)?;
+        if !desc.keys.is_empty() {
+            let mut motion = vec![Vec::new(); meshes.len()];
+            for key in &desc.keys {
+                let moved = self.meshes(&key.file, None)?;
+                let vertices = |m: &TriangleMesh| m.triangles[0].mesh.positions.len();
+                if moved.len() != meshes.len()
+                    || moved
+                        .iter()
+                        .zip(&meshes)
+                        .any(|(a, b)| vertices(a) != vertices(b))
+                {
+                    return self.invalid(
+                        key.file.start(),
+                        "keys",
+                        format!(
+                            "`{}` does not have the same vertices as `{}`",
+                            key.file.get_ref(),
+                            file.get_ref()
+                        ),
+                    );
+                }
+                for (keys, m) in motion.iter_mut().zip(moved) {
+                    keys.push((key.time, m.triangles[0].mesh.positions.clone()));
+                }
+            }
+            meshes = meshes
+                .iter()
+                .zip(motion)
+                .map(|(mesh, motion)| mesh.with_motion(motion))
+                .collect();
+        }
+        let mut meshes: Vec<Arc<dyn Hittable>> = meshes
+            .into_iter()
+            .map(|m| Arc::new(m) as Arc<dyn Hittable>)
+            .collect();
+        if meshes.len() == 1 {
+            return Ok(meshes.pop().unwrap());
+        }
+        Ok(Arc::new(meshes))
+    }
+
+    // The meshes in an OBJ, PLY or STL file, one per OBJ group
+    fn meshes(
+        &self,
+        file: &Spanned<String>,
+        material: Option<Arc<dyn Material>>,
+    ) -> Result<Vec<TriangleMesh>, SceneError> {
+        let path = self.dir.join(file.get_ref());
+        let grey = || -> Arc<dyn Material> {
+            Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(
+                0.8, 0.8, 0.8,
+            )))))
+        };
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        // PLY and STL hold a single mesh without materials
+        let single = |mesh: MeshData| -> Vec<TriangleMesh> {
+            let mesh = Arc::new(mesh);
+            vec![match material.clone() {
+                Some(material) => TriangleMesh::new(mesh, material),
+                None if !mesh.colors.is_empty() => TriangleMesh::with_vertex_colors(mesh),
+                None => TriangleMesh::new(mesh, grey()),
+            }]
+        };
+        let loaded = match extension.as_deref() {
+            Some("obj") => load_obj(&path, material.clone().unwrap_or_else(grey))
+                .map(|groups| groups.into_iter().map(|g| g.mesh).collect()),
+            Some("ply") => load_ply(&path).map(single),
+            Some("stl") => load_stl(&path).map(single),
+            _ => {
+                return self.invalid(
+                    file.start(),
+                    "file",
+                    "meshes must be .obj, .ply or .stl files".to_string(),
+                )
+            }
+        };
+        match loaded {
+            Ok(meshes) => Ok(meshes),
+            Err(e) => self.invalid(file.start(), "file", e.to_string()),
+        }
+    }
+
+    fn object_material(&mut self, desc: &ObjectDesc) -> Result<Arc<dyn Material>, SceneError> {
+        let name = self.require(desc.material.as_ref(), &desc.kind, "material")?;
+        self.material(name)
+    }
+}
+
+// Relative texture paths are resolved against `dir`, the directory of the
+// scene file.
+pub fn parse_scene(src: &str, dir: &Path, aspect: f32) -> Result<Scene, SceneError> {
+    let desc: SceneDesc = toml::from_str(src).map_err(SceneError::Parse)?;
+    let mut loader = Loader {
+        src,
+        dir,
+        desc: &desc,
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        prototypes: HashMap::new(),
+        resolving: Vec::new(),
+        instancing: Vec::new(),
+    };
+
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    let mut lights: Vec<Arc<dyn Hittable>> = Vec::new();
+    // Instances go into one BVH of their own, on top of the prototypes'
+    let mut instances: Vec<Arc<dyn Hittable>> = Vec::new();
+    for obj_desc in desc.objects.iter() {
+        let obj = loader.object(obj_desc)?;
+        if obj_desc.light {
+            lights.push(obj.clone());
+        }
+        if obj_desc.kind.get_ref() == "instance" {
+            instances.push(obj);
+        } else {
+            world.push(obj);
+        }
+    }
+    if !instances.is_empty() {
+        world.push(Arc::new(FlatBvh::new(
+            &instances,
+            desc.camera.time0,
+            desc.camera.time1,
+        )));
+    }
+    if lights.is_empty() {
+        return Err(SceneError::Invalid {
+            line: None,
+            field: "light".to_string(),
+            message: "at least one object must be marked as a light".to_string(),
+        });
+    }
+
+    let cam = &desc.camera;
+    let camera = Camera::new(
+        vec3(cam.lookfrom),
+        vec3(cam.lookat),
+        vec3(cam.vup),
+        cam.vfov,
+        aspect,
+        cam.aperture,
+        cam.focus_dist,
+        cam.time0,
+        cam.time1,
+    );
+
+    Ok(Scene {
+        camera,
+        world,
+        lights,
+    })
+}
+
+// TOML scene files, or glTF scenes by their .gltf or .glb extension
+pub fn load_scene(path: &Path, aspect: f32) -> Result<Scene, SceneError> {
+    let extension = path.extension().and_then(|e| e.to_str());
+    if let Some("gltf") | Some("glb") = extension.map(|e| e.to_ascii_lowercase()).as_deref() {
+        return load_gltf(path, aspect).map_err(SceneError::Import);
+    }
+    let src = std::fs::read_to_string(path).map_err(|e| SceneError::Io(path.to_path_buf(), e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    parse_scene(&src, dir, aspect)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::Ray;
+
+    const CAMERA: &str = "[camera]\nlookfrom = [0, 0, -5]\nlookat = [0, 0, 0]\nvfov = 40\n";
+
+    fn parse(body: &str) -> Result<Scene, SceneError> {
+        parse_scene(&format!("{}{}", CAMERA, body), Path::new("."), 1.0)
+    }
+
+    #[test]
+    fn shared_material() {
+        let scene = parse(
+            "[materials.light]\ntype = \"diffuse_light\"\ncolor = [4, 4, 4]\n\
+             [[objects]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"light\"\nlight = true\n\
+             [[objects]]\ntype = \"xy_rect\"\nx0 = 0\nx1 = 1\ny0 = 0\ny1 = 1\nk = 2\nmaterial = \"light\"\n\
+             transforms = [{ type = \"flip_normals\" }]\n",
+        )
+        .unwrap();
+        assert_eq!(scene.world.len(), 2);
+        assert_eq!(scene.lights.len(), 1);
+
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let sphere = scene.world[0]
+            .hit(Ray::new(Vec3::new(0.0, 0.0, -5.0), z, 0.0), 0.001, f32::MAX)
+            .unwrap();
+        let rect = scene.world[1]
+            .hit(
+                Ray::new(Vec3::new(0.5, 0.5, 5.0), -1.0 * z, 0.0),
+                0.001,
+                f32::MAX,
+            )
+            .unwrap();
+        assert!(Arc::ptr_eq(&sphere.material, &rect.material));
+    }
+
+    #[test]
+    fn unknown_material_reports_line() {
+        let err = parse(
+            "[[objects]]\ntype = \"sphere\"\ncenter = [0, 0, 0]\nradius = 1\nmaterial = \"nope\"\n",
+        )
+        .err()
+        .unwrap();
+        match err {
+            SceneError::Invalid { line, field, .. } => {
+                assert_eq!(line, Some(9));
+                assert_eq!(field, "material");
+            }
+            _ => panic!("expected an invalid scene error, got {}", err),
+        }
+    }
+
+    #[test]
+    fn bad_value_reports_line() {
+        let err = parse("[[objects]]\ntype = \"sphere\"\nradius = \"big\"\n")
+            .err()
+            .unwrap();
+        match err {
+            SceneError::Parse(e) => assert_eq!(e.line_col().map(|(l, _)| l + 1), Some(7)),
+            _ => panic!("expected a parse error, got {}", err),
+        }
+    }
+
+    // The pyramid's apex bends down from 160 to 130 between its two keys
+    #[test]
+    fn mesh_keys_deform_it() {
+        let scene = parse_scene(
+            &format!(
+                "{}[[objects]]\ntype = \"mesh\"\nfile = \"pyramid.obj\"\n\
+                 keys = [{{ time = 0, file = \"pyramid.obj\" }}, {{ time = 1, file = \"pyramid_bent.obj\" }}]\nlight = true\n",
+                CAMERA
+            ),
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes"),
+            1.0,
+        )
+        .unwrap();
+        let at = |time| {
+            let r = Ray::new(
+                Vec3::new(0.0, 150.0, -500.0),
+                Vec3::new(0.0, 0.0, 1.0),
+                time,
+            );
+            scene.world[0].hit(r, 0.001, f32::MAX)
+        };
+        assert!(at(0.0).is_some());
+        assert!(at(1.0).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+// Receptors with their own spectral sensitivities, for images as seen by
+// something other than a human eye, like the 12 photoreceptors of a mantis
+// shrimp or the color filters of a camera.
+//
+// Curves come from CSV files with a header naming the receptors and a row
+// per wavelength in nanometers, in increasing order:
+//
+//   wavelength,uv,blue,green
+//   300,0.8,0,0
+//   310,1.0,0.01,0
+//   ...
+//
+// Sensitivities are linear between rows and zero outside them. Empty lines
+// and lines starting with `#` are skipped.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::output::Receptors;
+use crate::spectrum::{LAMBDA_MAX, LAMBDA_MIN};
+use crate::vec3::Vec3;
+
+#[derive(Debug)]
+pub enum SensorError {
+    Io(PathBuf, std::io::Error),
+    Invalid(PathBuf, String),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SensorError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            SensorError::Invalid(path, message) => write!(f, "{}: {}", path.display(), message),
+            SensorError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
+
+pub struct Sensor {
+    pub names: Vec<String>,
+    wavelengths: Vec<f32>,
+    // One row of sensitivities per wavelength
+    rows: Vec<Vec<f32>>,
+    // What each receptor gives for a flat spectrum of one, so that it can
+    // be made to give one instead
+    flat: Vec<f32>,
+    // Where each receptor shows up in the false color preview
+    colors: Vec<Vec3>,
+}
+
+// Violet for the shortest wavelengths round to red for the longest, with
+// `t` from 0 to 1
+fn rainbow(t: f32) -> Vec3 {
+    // Hue in sixths of a turn, 4.5 is violet
+    let hue = 4.5 * (1.0 - t.clamp(0.0, 1.0));
+    let channel = |n: f32| {
+        let k = (n + hue) % 6.0;
+        1.0 - (k.min(4.0 - k)).clamp(0.0, 1.0)
+    };
+    Vec3::new(channel(5.0), channel(3.0), channel(1.0))
+}
+
+impl Sensor {
+    pub fn parse(src: &str, path: &Path) -> Result<Sensor, SensorError> {
+        let mut names = Vec::new();
+        let mut wavelengths: Vec<f32> = Vec::new();
+        let mut rows = Vec::new();
+        for (i, line) in src.lines().enumerate() {
+            let error = |message: String| SensorError::Parse {
+                path: path.to_path_buf(),
+                line: i + 1,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            if names.is_empty() {
+                if fields.len() < 2 {
+                    return Err(error(
+                        "the header needs a wavelength column and at least one receptor"
+                            .to_string(),
+                    ));
+                }
+                names = fields[1..].iter().map(|f| f.to_string()).collect();
+                continue;
+            }
+            if fields.len() != names.len() + 1 {
+                return Err(error(format!(
+                    "expected {} values, found {}",
+                    names.len() + 1,
+                    fields.len()
+                )));
+            }
+            let mut values = Vec::new();
+            for field in fields {
+                values.push(
+                    field
+                        .parse::<f32>()
+                        .map_err(|_| error(format!("expected a number, found `{}`", field)))?,
+                );
+            }
+            if let Some(last) = wavelengths.last() {
+                if values[0] <= *last {
+                    return Err(error(format!(
+                        "wavelengths must increase, {} comes after {}",
+                        values[0], last
+                    )));
+                }
+            }
+            wavelengths.push(values[0]);
+            rows.push(values[1..].to_vec());
+        }
+        if rows.is_empty() {
+            return Err(SensorError::Invalid(
+                path.to_path_buf(),
+                "no sensitivities".to_string(),
+            ));
+        }
+
+        let mut sensor = Sensor {
+            names,
+            wavelengths,
+            rows,
+            flat: Vec::new(),
+            colors: Vec::new(),
+        };
+        // Sums over the range rendered, a nanometer at a time
+        let n = sensor.names.len();
+        let mut flat = vec![0.0; n];
+        let mut centroid = vec![0.0; n];
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        for step in 0..steps {
+            let lambda = LAMBDA_MIN + step as f32 + 0.5;
+            for (k, s) in sensor.sensitivities(lambda).iter().enumerate() {
+                flat[k] += s;
+                centroid[k] += s * lambda;
+            }
+        }
+        for k in 0..n {
+            if flat[k] <= 0.0 {
+                return Err(SensorError::Invalid(
+                    path.to_path_buf(),
+                    format!(
+                        "receptor `{}` sees nothing between {} and {} nm",
+                        sensor.names[k], LAMBDA_MIN, LAMBDA_MAX
+                    ),
+                ));
+            }
+            let t = (centroid[k] / flat[k] - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+            sensor.colors.push(rainbow(t));
+        }
+        sensor.flat = flat;
+        Ok(sensor)
+    }
+
+    pub fn load(path: &Path) -> Result<Sensor, SensorError> {
+        match std::fs::read_to_string(path) {
+            Ok(src) => Sensor::parse(&src, path),
+            Err(e) => Err(SensorError::Io(path.to_path_buf(), e)),
+        }
+    }
+
+    // Every receptor's sensitivity at `lambda`
+    fn sensitivities(&self, lambda: f32) -> Vec<f32> {
+        let n = self.names.len();
+        let w = &self.wavelengths;
+        if lambda < w[0] || lambda > w[w.len() - 1] {
+            return vec![0.0; n];
+        }
+        if w.len() == 1 {
+            return self.rows[0].clone();
+        }
+        let i = w.partition_point(|x| *x <= lambda).clamp(1, w.len() - 1);
+        let f = (lambda - w[i - 1]) / (w[i] - w[i - 1]);
+        (0..n)
+            .map(|k| (1.0 - f) * self.rows[i - 1][k] + f * self.rows[i][k])
+            .collect()
+    }
+
+    // One path's estimate of what each receptor sees, from the radiance at
+    // the wavelengths in `lambdas`
+    pub fn responses(&self, radiance: Vec3, lambdas: Vec3) -> Vec<f32> {
+        let mut responses = vec![0.0; self.names.len()];
+        for i in 0..3 {
+            for (k, s) in self.sensitivities(lambdas[i]).iter().enumerate() {
+                responses[k] += radiance[i] * s;
+            }
+        }
+        // The flat sums are per nanometer and each wavelength stands for a
+        // third of the range
+        let scale = (LAMBDA_MAX - LAMBDA_MIN) / 3.0;
+        for (r, flat) in responses.iter_mut().zip(self.flat.iter()) {
+            *r *= scale / flat;
+        }
+        responses
+    }
+
+    // The mean responses of a film, `values` holding one per receptor for
+    // each pixel, with a preview that gives each receptor a color by where
+    // it sits in the spectrum. A flat spectrum comes out white.
+    pub fn receptors(&self, values: Vec<f32>) -> Receptors {
+        let white = self.colors.iter().fold(Vec3::default(), |sum, c| sum + *c);
+        let preview = values
+            .chunks(self.names.len())
+            .map(|pixel| {
+                let mut col = Vec3::default();
+                for (v, c) in pixel.iter().zip(self.colors.iter()) {
+                    col += *v * *c;
+                }
+                for c in 0..3 {
+                    if white[c] > 0.0 {
+                        col[c] /= white[c];
+                    }
+                }
+                col
+            })
+            .collect();
+        Receptors {
+            names: self.names.clone(),
+            values,
+            preview,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spectrum::sample_wavelengths;
+
+    #[test]
+    fn receptors_see_white_as_one() {
+        let csv = "# Two made up receptors\n\
+                   wavelength, short, long\n\
+                   300, 1, 0\n\
+                   500, 0.5, 0.5\n\
+                   \n\
+                   720, 0, 2\n";
+        let sensor = Sensor::parse(csv, Path::new("eye.csv")).unwrap();
+        assert_eq!(sensor.names, vec!["short", "long"]);
+
+        let n = 3000;
+        let mut sum = vec![0.0; 2];
+        for i in 0..n {
+            let lambdas = sample_wavelengths((i as f32 + 0.5) / n as f32);
+            let responses = sensor.responses(Vec3::new(1.0, 1.0, 1.0), lambdas);
+            for k in 0..2 {
+                sum[k] += responses[k] / n as f32;
+            }
+        }
+        assert!((sum[0] - 1.0).abs() < 0.01 && (sum[1] - 1.0).abs() < 0.01);
+        let receptors = sensor.receptors(vec![1.0, 1.0]);
+        assert!((receptors.preview[0] - Vec3::new(1.0, 1.0, 1.0)).mag() < 1e-5);
+
+        match Sensor::parse(
+            &csv.replace("500, 0.5, 0.5", "500, 0.5"),
+            Path::new("eye.csv"),
+        ) {
+            Err(SensorError::Parse { line, .. }) => assert_eq!(line, 4),
+            _ => panic!("expected an error for a short row"),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Spectral rendering with hero wavelengths (Wilkie et al. 2014).
+//
+// A path carries three wavelengths spread evenly over the range, one
+// picked at random and the others a third and two thirds of the way round
+// from it, and its Vec3 of radiance holds one value per wavelength instead
+// of red, green and blue. Textures and materials stay in RGB and their
+// colors are turned into smooth spectra where a path meets them. Finished
+// paths go through the CIE 1931 observer to XYZ and on to linear sRGB.
+
+use crate::vec3::*;
+
+// What the mantis shrimp sees, in nanometers
+pub const LAMBDA_MIN: f32 = 300.0;
+pub const LAMBDA_MAX: f32 = 720.0;
+
+// Where red, green and blue sit in the spectrum, for RGB renders of
+// materials that need a wavelength
+pub const RGB_WAVELENGTHS: Vec3 = Vec3 {
+    e0: 612.0,
+    e1: 549.0,
+    e2: 465.0,
+};
+
+// The hero wavelength at `u` along the range and its two companions
+pub fn sample_wavelengths(u: f32) -> Vec3 {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let hero = u * range;
+    let at = |i: f32| LAMBDA_MIN + (hero + i * range / 3.0) % range;
+    Vec3::new(at(0.0), at(1.0), at(2.0))
+}
+
+// A Gaussian with a different width on each side of its peak
+fn lobe(lambda: f32, peak: f32, below: f32, above: f32) -> f32 {
+    let t = (lambda - peak) / if lambda < peak { below } else { above };
+    (-0.5 * t * t).exp()
+}
+
+// The CIE 1931 color matching functions, from the multi-lobe fit of
+// Wyman, Sloan and Shirley (2013)
+pub fn cie_xyz(lambda: f32) -> Vec3 {
+    Vec3::new(
+        1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(lambda, 501.1, 20.4, 26.2),
+        0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1),
+        1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8),
+    )
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Red, green and blue bands with smooth edges that add up to one at every
+// wavelength, so white is a flat spectrum
+fn basis(lambda: f32) -> Vec3 {
+    let blue = 1.0 - smoothstep(450.0, 520.0, lambda);
+    let red = smoothstep(560.0, 620.0, lambda);
+    Vec3::new(red, 1.0 - red - blue, blue)
+}
+
+// How much of each band makes a color, the inverse of what the bands look
+// like through `to_rgb`
+const RGB_TO_BASIS: [[f32; 3]; 3] = [
+    [1.04197, -0.03084, -0.01113],
+    [-0.00906, 0.97273, 0.03634],
+    [0.02831, -0.02487, 0.99656],
+];
+
+// XYZ to linear sRGB, white balanced so that a flat spectrum is white
+const XYZ_TO_RGB: [[f32; 3]; 3] = [
+    [2.69976, -1.28065, -0.41539],
+    [-1.02014, 1.975, 0.04369],
+    [0.06133, -0.22461, 1.16376],
+];
+
+// The range over three wavelengths, divided by the integral of y over it
+// so that a flat spectrum of one has a Y of one
+const XYZ_SCALE: f32 = 1.30946;
+
+fn mul(m: &[[f32; 3]; 3], v: Vec3) -> Vec3 {
+    let row = |r: &[f32; 3]| Vec3::new(r[0], r[1], r[2]);
+    Vec3::new(dot(row(&m[0]), v), dot(row(&m[1]), v), dot(row(&m[2]), v))
+}
+
+// The values at `lambdas` of a smooth spectrum that looks like `rgb`.
+// Saturated colors can dip below zero between bands and are clipped.
+pub fn upsample(rgb: Vec3, lambdas: Vec3) -> Vec3 {
+    let bands = mul(&RGB_TO_BASIS, rgb);
+    let mut s = Vec3::default();
+    for i in 0..3 {
+        s[i] = dot(bands, basis(lambdas[i])).max(0.0);
+    }
+    s
+}
+
+// Linear sRGB from radiance sampled at `lambdas`, one path's estimate
+pub fn to_rgb(radiance: Vec3, lambdas: Vec3) -> Vec3 {
+    let mut xyz = Vec3::default();
+    for i in 0..3 {
+        xyz += radiance[i] * cie_xyz(lambdas[i]);
+    }
+    mul(&XYZ_TO_RGB, XYZ_SCALE * xyz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Averaged over evenly spread hero wavelengths, colors turned into
+    // spectra look like they did
+    #[test]
+    fn upsampled_colors_come_back() {
+        let n = 4000;
+        for rgb in [
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(0.8, 0.5, 0.3),
+            Vec3::new(0.12, 0.45, 0.15),
+            Vec3::new(15.0, 15.0, 15.0),
+        ]
+        .iter()
+        {
+            let mut sum = Vec3::default();
+            for i in 0..n {
+                let lambdas = sample_wavelengths((i as f32 + 0.5) / n as f32);
+                sum += to_rgb(upsample(*rgb, lambdas), lambdas);
+            }
+            let back = sum / n as f32;
+            assert!((back - *rgb).mag() < 0.01 * rgb.mag(), "{:?}", back);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+// STL meshes, in ASCII or binary.
+//
+// STL stores every triangle with its own three corners and a facet
+// normal. The normals are ignored since the winding gives the same one,
+// and corners are not merged, so the mesh is flat shaded.
+
+use std::path::Path;
+
+use crate::obj::MeshData;
+use crate::objfile::MeshError;
+use crate::vec3::Vec3;
+
+fn new_mesh() -> MeshData {
+    MeshData {
+        positions: Vec::new(),
+        normals: Vec::new(),
+        uvs: Vec::new(),
+        colors: Vec::new(),
+        indices: Vec::new(),
+        motion: Vec::new(),
+    }
+}
+
+fn push_triangle(mesh: &mut MeshData, corners: [Vec3; 3]) {
+    let first = mesh.positions.len() as u32;
+    mesh.positions.extend_from_slice(&corners);
+    mesh.indices.push([first, first + 1, first + 2]);
+}
+
+// An 80 byte header, the triangle count and 50 bytes per triangle
+fn parse_binary(data: &[u8], path: &Path) -> Result<MeshData, MeshError> {
+    let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+    if data.len() < 84 + 50 * count {
+        return Err(MeshError::Invalid(
+            path.to_path_buf(),
+            format!(
+                "file ends early, it should have {} triangles but has room for {}",
+                count,
+                (data.len() - 84) / 50
+            ),
+        ));
+    }
+    let float =
+        |at: usize| f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+    let vertex = |at: usize| Vec3::new(float(at), float(at + 4), float(at + 8));
+    let mut mesh = new_mesh();
+    for i in 0..count {
+        // The facet normal comes first and the attribute count last
+        let at = 84 + 50 * i + 12;
+        push_triangle(&mut mesh, [vertex(at), vertex(at + 12), vertex(at + 24)]);
+    }
+    Ok(mesh)
+}
+
+fn parse_ascii(src: &str, path: &Path) -> Result<MeshData, MeshError> {
+    let mut mesh = new_mesh();
+    let mut corners = Vec::new();
+    for (i, line) in src.lines().enumerate() {
+        let error = |message: String| MeshError::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            message,
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["vertex", x, y, z] => {
+                let mut xyz = [0.0; 3];
+                for (value, token) in xyz.iter_mut().zip([x, y, z].iter()) {
+                    *value = token
+                        .parse()
+                        .map_err(|_| error(format!("expected a number, found `{}`", token)))?;
+                }
+                corners.push(Vec3::new(xyz[0], xyz[1], xyz[2]));
+            }
+            ["vertex", ..] => return Err(error("a vertex needs x, y and z".to_string())),
+            ["endloop"] => {
+                if corners.len() != 3 {
+                    return Err(error(format!(
+                        "a facet needs 3 vertices, this one has {}",
+                        corners.len()
+                    )));
+                }
+                push_triangle(&mut mesh, [corners[0], corners[1], corners[2]]);
+                corners.clear();
+            }
+            _ => (),
+        }
+    }
+    Ok(mesh)
+}
+
+pub fn parse_stl(data: &[u8], path: &Path) -> Result<MeshData, MeshError> {
+    // Binary files may also start with `solid`, so the size decides
+    let binary = data.len() >= 84 && {
+        let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+        !data.starts_with(b"solid") || data.len() == 84 + 50 * count
+    };
+    let mesh = if binary {
+        parse_binary(data, path)?
+    } else {
+        match std::str::from_utf8(data) {
+            Ok(src) if src.trim_start().starts_with("solid") => parse_ascii(src, path)?,
+            _ => {
+                return Err(MeshError::Invalid(
+                    path.to_path_buf(),
+                    "not an STL file".to_string(),
+                ))
+            }
+        }
+    };
+    if mesh.indices.is_empty() {
+        return Err(MeshError::Invalid(
+            path.to_path_buf(),
+            "no facets".to_string(),
+        ));
+    }
+    Ok(mesh)
+}
+
+pub fn load_stl(path: &Path) -> Result<MeshData, MeshError> {
+    match std::fs::read(path) {
+        Ok(data) => parse_stl(&data, path),
+        Err(e) => Err(MeshError::Io(path.to_path_buf(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_and_binary_agree() {
+        let ascii = "solid tri\n\
+                     facet normal 0 0 1\n\
+                     outer loop\n\
+                     vertex 0 0 0\n\
+                     vertex 1 0 0\n\
+                     vertex 0 2 0\n\
+                     endloop\n\
+                     endfacet\n\
+                     endsolid tri\n";
+        // Binary with a header that starts with `solid` like some exporters
+        let mut binary = b"solid exported".to_vec();
+        binary.resize(80, 0);
+        binary.extend_from_slice(&1u32.to_le_bytes());
+        for x in [
+            0.0f32, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 0.0,
+        ]
+        .iter()
+        {
+            binary.extend_from_slice(&x.to_le_bytes());
+        }
+        binary.extend_from_slice(&[0, 0]);
+
+        let path = Path::new("tri.stl");
+        for mesh in [
+            parse_stl(ascii.as_bytes(), path).unwrap(),
+            parse_stl(&binary, path).unwrap(),
+        ]
+        .iter()
+        {
+            assert_eq!(mesh.indices, vec![[0, 1, 2]]);
+            assert!((mesh.positions[2] - Vec3::new(0.0, 2.0, 0.0)).mag() < 1e-6);
+        }
+
+        let bad = ascii.replace("vertex 1 0 0", "vertex 1 0");
+        match parse_stl(bad.as_bytes(), path) {
+            Err(MeshError::Parse { line, .. }) => assert_eq!(line, 5),
+            _ => panic!("expected an error for a short vertex"),
+        }
+    }
+}
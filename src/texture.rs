@@ -1,8 +1,20 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use image::GenericImageView;
+
+use crate::hit::HitRecord;
+use crate::obj::MeshData;
 use crate::perlin::Perlin;
 use crate::vec3::Vec3;
 
 pub trait Texture: Sync + Send {
     fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3;
+    // The value where `hit` landed, for textures that need more of it than
+    // the surface coordinates and the point
+    fn value_at(&self, hit: &HitRecord) -> Vec3 {
+        self.value(hit.u, hit.v, &hit.p)
+    }
 }
 
 pub struct ConstantTexture {
@@ -22,37 +34,43 @@ impl Texture for ConstantTexture {
 }
 
 pub struct CheckerTexture {
-    odd: Box<dyn Texture>,
-    even: Box<dyn Texture>,
+    odd: Arc<dyn Texture>,
+    even: Arc<dyn Texture>,
 }
 
 impl CheckerTexture {
-    pub fn new(t0: Box<dyn Texture>, t1: Box<dyn Texture>) -> Self {
+    pub fn new(t0: Arc<dyn Texture>, t1: Arc<dyn Texture>) -> Self {
         Self { odd: t0, even: t1 }
     }
-}
-
-impl Texture for CheckerTexture {
-    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+    fn square(&self, p: &Vec3) -> &Arc<dyn Texture> {
         let sines = (10.0 * p.x()).sin() * (10.0 * p.y()).sin() * (10.0 * p.z()).sin();
         if sines < 0.0 {
-            self.odd.value(u, v, p)
+            &self.odd
         } else {
-            self.even.value(u, v, p)
+            &self.even
         }
     }
 }
 
+impl Texture for CheckerTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        self.square(p).value(u, v, p)
+    }
+    fn value_at(&self, hit: &HitRecord) -> Vec3 {
+        self.square(&hit.p).value_at(hit)
+    }
+}
+
 pub struct NoiseTexture {
     scale: f32,
     noise: Perlin,
 }
 
 impl NoiseTexture {
-    pub fn new(scale: f32, noise: Perlin) -> Self {
+    pub fn new(scale: f32) -> Self {
         Self {
             scale: scale,
-            noise: noise,
+            noise: Perlin::new(),
         }
     }
 }
@@ -83,6 +101,12 @@ impl ImageTexture {
             ny: b,
         }
     }
+    // Any format the image crate can decode
+    pub fn open(path: &Path) -> image::ImageResult<Self> {
+        let img = image::open(path)?;
+        let (nx, ny) = img.dimensions();
+        Ok(Self::new(img.to_rgb().into_raw(), nx as i32, ny as i32))
+    }
 }
 
 impl Texture for ImageTexture {
@@ -107,3 +131,77 @@ impl Texture for ImageTexture {
         Vec3::new(r, g, b)
     }
 }
+
+// Colors stored per vertex of a mesh, blended across whichever face a hit
+// lands on, so one serves the whole mesh
+pub struct VertexColorTexture {
+    mesh: Arc<MeshData>,
+    mean: Vec3,
+}
+
+impl VertexColorTexture {
+    pub fn new(mesh: Arc<MeshData>) -> Self {
+        let sum = mesh.colors.iter().fold(Vec3::default(), |sum, c| sum + *c);
+        let mean = sum / mesh.colors.len().max(1) as f32;
+        Self { mesh, mean }
+    }
+}
+
+impl Texture for VertexColorTexture {
+    // Without a hit there is no face to blend across
+    fn value(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
+        self.mean
+    }
+    fn value_at(&self, hit: &HitRecord) -> Vec3 {
+        let [a, b, c] = self.mesh.indices[hit.face].map(|i| i as usize);
+        let (b1, b2) = hit.barycentric;
+        let colors = &self.mesh.colors;
+        (1.0 - b1 - b2) * colors[a] + b1 * colors[b] + b2 * colors[c]
+    }
+}
+
+// Another texture times a color, for formats that give a factor to
+// multiply into each map
+pub struct ScaledTexture {
+    texture: Arc<dyn Texture>,
+    scale: Vec3,
+}
+
+impl ScaledTexture {
+    pub fn new(texture: Arc<dyn Texture>, scale: Vec3) -> Self {
+        Self { texture, scale }
+    }
+}
+
+impl Texture for ScaledTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        self.texture.value(u, v, p) * self.scale
+    }
+    fn value_at(&self, hit: &HitRecord) -> Vec3 {
+        self.texture.value_at(hit) * self.scale
+    }
+}
+
+// One channel of another texture in all three, for images that pack
+// several parameters together
+pub struct ChannelTexture {
+    texture: Arc<dyn Texture>,
+    channel: u32,
+}
+
+impl ChannelTexture {
+    pub fn new(texture: Arc<dyn Texture>, channel: u32) -> Self {
+        Self { texture, channel }
+    }
+}
+
+impl Texture for ChannelTexture {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        let x = self.texture.value(u, v, p)[self.channel];
+        Vec3::new(x, x, x)
+    }
+    fn value_at(&self, hit: &HitRecord) -> Vec3 {
+        let x = self.texture.value_at(hit)[self.channel];
+        Vec3::new(x, x, x)
+    }
+}
@@ -1,7 +1,9 @@
 use std::sync::Arc;
 
 use crate::hit::Hittable;
-use crate::vec3::Vec3;
+use crate::material::Material;
+use crate::util::key_span;
+use crate::vec3::{cross, dot, Vec3};
 
 pub struct FlipNormals {
     pub obj_ref: Arc<dyn Hittable>,
@@ -45,3 +47,603 @@ impl RotateY {
         }
     }
 }
+
+type Mat4 = [[f32; 4]; 4];
+
+const IDENTITY: Mat4 = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+fn mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn transpose(a: &Mat4) -> Mat4 {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = a[j][i];
+        }
+    }
+    m
+}
+
+// Gauss-Jordan elimination with partial pivoting, in f64 so that
+// composed transforms stay accurate
+fn invert(a: &Mat4) -> Option<Mat4> {
+    let mut m = [[0.0f64; 8]; 4];
+    for i in 0..4 {
+        for j in 0..4 {
+            m[i][j] = a[i][j] as f64;
+        }
+        m[i][4 + i] = 1.0;
+    }
+    for col in 0..4 {
+        let pivot = (col..4)
+            .max_by(|x, y| m[*x][col].abs().partial_cmp(&m[*y][col].abs()).unwrap())
+            .unwrap();
+        if m[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        let p = m[col][col];
+        m[col].iter_mut().for_each(|x| *x /= p);
+        for row in 0..4 {
+            if row != col {
+                let f = m[row][col];
+                let pivot_row = m[col];
+                m[row]
+                    .iter_mut()
+                    .zip(pivot_row.iter())
+                    .for_each(|(x, p)| *x -= f * p);
+            }
+        }
+    }
+    let mut inv = [[0.0; 4]; 4];
+    for i in 0..4 {
+        for j in 0..4 {
+            inv[i][j] = m[i][4 + j] as f32;
+        }
+    }
+    Some(inv)
+}
+
+// An affine transform as a row major 4x4 matrix acting on column vectors,
+// kept together with its inverse
+#[derive(Clone, Copy)]
+pub struct Transform {
+    pub m: Mat4,
+    pub inv: Mat4,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            m: IDENTITY,
+            inv: IDENTITY,
+        }
+    }
+}
+
+impl Transform {
+    // None if the matrix cannot be inverted
+    pub fn from_matrix(m: Mat4) -> Option<Self> {
+        invert(&m).map(|inv| Self { m, inv })
+    }
+    pub fn translate(offset: Vec3) -> Self {
+        let mut t = Self::default();
+        for a in 0..3 {
+            t.m[a as usize][3] = offset[a];
+            t.inv[a as usize][3] = -offset[a];
+        }
+        t
+    }
+    // Scale factors must not be zero
+    pub fn scale(factor: Vec3) -> Self {
+        let mut t = Self::default();
+        for a in 0..3 {
+            t.m[a as usize][a as usize] = factor[a];
+            t.inv[a as usize][a as usize] = 1.0 / factor[a];
+        }
+        t
+    }
+    // Counterclockwise looking down `axis` towards the origin
+    pub fn rotate(axis: Vec3, angle: f32) -> Self {
+        let a = axis.unit();
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (x, y, z) = (a.x(), a.y(), a.z());
+        let m = [
+            [
+                cos + x * x * (1.0 - cos),
+                x * y * (1.0 - cos) - z * sin,
+                x * z * (1.0 - cos) + y * sin,
+                0.0,
+            ],
+            [
+                y * x * (1.0 - cos) + z * sin,
+                cos + y * y * (1.0 - cos),
+                y * z * (1.0 - cos) - x * sin,
+                0.0,
+            ],
+            [
+                z * x * (1.0 - cos) - y * sin,
+                z * y * (1.0 - cos) + x * sin,
+                cos + z * z * (1.0 - cos),
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        // The inverse of a rotation is its transpose
+        Self {
+            m,
+            inv: transpose(&m),
+        }
+    }
+    // Moves the origin to `from` and turns +z to face `to`, with +y as
+    // close to `up` as it can be
+    pub fn look_at(from: Vec3, to: Vec3, up: Vec3) -> Self {
+        let z = (to - from).unit();
+        let x = cross(up, z).unit();
+        let y = cross(z, x);
+        // The axes are orthonormal, so the inverse rotation is the transpose
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        for (c, axis) in [x, y, z].iter().enumerate() {
+            for r in 0..3 {
+                m[r][c] = axis[r as u32];
+                inv[c][r] = axis[r as u32];
+            }
+            m[c][3] = from[c as u32];
+            inv[c][3] = -dot(*axis, from);
+        }
+        Self { m, inv }
+    }
+    // `self` first and then `next`
+    pub fn then(&self, next: &Transform) -> Transform {
+        Self {
+            m: mul(&next.m, &self.m),
+            inv: mul(&self.inv, &next.inv),
+        }
+    }
+    pub fn inverse(&self) -> Transform {
+        Self {
+            m: self.inv,
+            inv: self.m,
+        }
+    }
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        apply(&self.m, p, 1.0)
+    }
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        apply(&self.m, v, 0.0)
+    }
+    // Normals go through the inverse transpose to stay perpendicular to
+    // the surface. Not normalized.
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        apply(&transpose(&self.inv), n, 0.0)
+    }
+    // Determinant of the 3x3 part, how much volumes are scaled by
+    pub fn determinant(&self) -> f32 {
+        determinant(&self.m)
+    }
+}
+
+fn determinant(m: &Mat4) -> f32 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+fn apply(m: &Mat4, v: Vec3, w: f32) -> Vec3 {
+    let row = |r: usize| m[r][0] * v.x() + m[r][1] * v.y() + m[r][2] * v.z() + m[r][3] * w;
+    Vec3::new(row(0), row(1), row(2))
+}
+
+// An object placed by an arbitrary affine transform
+pub struct Transformed {
+    pub obj_ref: Arc<dyn Hittable>,
+    pub transform: Transform,
+}
+
+impl Transformed {
+    pub fn new(obj_ref: Arc<dyn Hittable>, transform: Transform) -> Self {
+        Self { obj_ref, transform }
+    }
+}
+
+// A copy of a shared prototype, usually a mesh with its own BVH, placed by
+// a transform. The material, if given, replaces the prototype's own so the
+// same geometry can be reused with different looks. Instances are small,
+// so thousands of them in a FlatBvh cost little more than the prototype.
+pub struct Instance {
+    pub placed: Transformed,
+    pub material: Option<Arc<dyn Material>>,
+}
+
+impl Instance {
+    pub fn new(
+        prototype: Arc<dyn Hittable>,
+        transform: Transform,
+        material: Option<Arc<dyn Material>>,
+    ) -> Self {
+        Self {
+            placed: Transformed::new(prototype, transform),
+            material,
+        }
+    }
+}
+
+// A transform split into M = T R S, a translation, a rotation as a unit
+// quaternion (w, x, y, z) and a symmetric stretch, so that keys can be
+// blended without the shearing that blending matrices gives
+#[derive(Clone, Copy)]
+struct Decomposed {
+    translate: Vec3,
+    rotate: [f32; 4],
+    stretch: Mat4,
+}
+
+fn decompose(t: &Transform) -> Decomposed {
+    let mut linear = t.m;
+    for row in linear.iter_mut().take(3) {
+        row[3] = 0.0;
+    }
+    // Polar decomposition, averaging with the inverse transpose until only
+    // the rotation is left
+    let mut r = linear;
+    for _ in 0..100 {
+        let inv_t = match invert(&r) {
+            Some(inv) => transpose(&inv),
+            None => break,
+        };
+        let mut change: f32 = 0.0;
+        for i in 0..3 {
+            for j in 0..3 {
+                let next = 0.5 * (r[i][j] + inv_t[i][j]);
+                change = change.max((next - r[i][j]).abs());
+                r[i][j] = next;
+            }
+        }
+        if change < 1e-6 {
+            break;
+        }
+    }
+    // Mirroring is left in the stretch so that the rotation is proper
+    if determinant(&r) < 0.0 {
+        for row in r.iter_mut().take(3) {
+            for x in row.iter_mut().take(3) {
+                *x = -*x;
+            }
+        }
+    }
+    Decomposed {
+        translate: Vec3::new(t.m[0][3], t.m[1][3], t.m[2][3]),
+        rotate: quaternion(&r),
+        stretch: mul(&transpose(&r), &linear),
+    }
+}
+
+fn quaternion(m: &Mat4) -> [f32; 4] {
+    let trace = m[0][0] + m[1][1] + m[2][2];
+    if trace > 0.0 {
+        let s = 2.0 * (1.0 + trace).sqrt();
+        [
+            0.25 * s,
+            (m[2][1] - m[1][2]) / s,
+            (m[0][2] - m[2][0]) / s,
+            (m[1][0] - m[0][1]) / s,
+        ]
+    } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+        let s = 2.0 * (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt();
+        [
+            (m[2][1] - m[1][2]) / s,
+            0.25 * s,
+            (m[0][1] + m[1][0]) / s,
+            (m[0][2] + m[2][0]) / s,
+        ]
+    } else if m[1][1] > m[2][2] {
+        let s = 2.0 * (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt();
+        [
+            (m[0][2] - m[2][0]) / s,
+            (m[0][1] + m[1][0]) / s,
+            0.25 * s,
+            (m[1][2] + m[2][1]) / s,
+        ]
+    } else {
+        let s = 2.0 * (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt();
+        [
+            (m[1][0] - m[0][1]) / s,
+            (m[0][2] + m[2][0]) / s,
+            (m[1][2] + m[2][1]) / s,
+            0.25 * s,
+        ]
+    }
+}
+
+fn rotation_matrix(q: [f32; 4]) -> Mat4 {
+    let [w, x, y, z] = q;
+    [
+        [
+            1.0 - 2.0 * (y * y + z * z),
+            2.0 * (x * y - w * z),
+            2.0 * (x * z + w * y),
+            0.0,
+        ],
+        [
+            2.0 * (x * y + w * z),
+            1.0 - 2.0 * (x * x + z * z),
+            2.0 * (y * z - w * x),
+            0.0,
+        ],
+        [
+            2.0 * (x * z - w * y),
+            2.0 * (y * z + w * x),
+            1.0 - 2.0 * (x * x + y * y),
+            0.0,
+        ],
+        [0.0, 0.0, 0.0, 1.0],
+    ]
+}
+
+// The angle between two rotations, in radians
+fn turn(a: [f32; 4], b: [f32; 4]) -> f32 {
+    let cos: f32 = (0..4).map(|i| a[i] * b[i]).sum();
+    2.0 * cos.abs().min(1.0).acos()
+}
+
+// Spherical interpolation along the shorter way around
+fn slerp(a: [f32; 4], b: [f32; 4], f: f32) -> [f32; 4] {
+    let mut cos: f32 = (0..4).map(|i| a[i] * b[i]).sum();
+    let mut b = b;
+    if cos < 0.0 {
+        b.iter_mut().for_each(|x| *x = -*x);
+        cos = -cos;
+    }
+    let (wa, wb) = if cos > 0.9995 {
+        (1.0 - f, f)
+    } else {
+        let theta = cos.acos();
+        let sin = theta.sin();
+        (((1.0 - f) * theta).sin() / sin, (f * theta).sin() / sin)
+    };
+    let mut q = [0.0; 4];
+    for i in 0..4 {
+        q[i] = wa * a[i] + wb * b[i];
+    }
+    let norm = q.iter().map(|x| x * x).sum::<f32>().sqrt();
+    q.iter_mut().for_each(|x| *x /= norm);
+    q
+}
+
+// None when the stretch passes through zero on its way between the keys
+fn blend(a: &Decomposed, b: &Decomposed, f: f32) -> Option<Transform> {
+    let mut stretch = IDENTITY;
+    for (i, row) in stretch.iter_mut().enumerate().take(3) {
+        for (j, x) in row.iter_mut().enumerate().take(3) {
+            *x = (1.0 - f) * a.stretch[i][j] + f * b.stretch[i][j];
+        }
+    }
+    let mut m = mul(&rotation_matrix(slerp(a.rotate, b.rotate, f)), &stretch);
+    let translate = (1.0 - f) * a.translate + f * b.translate;
+    for (r, row) in m.iter_mut().take(3).enumerate() {
+        row[3] = translate[r as u32];
+    }
+    Transform::from_matrix(m)
+}
+
+// The most an object turns between the times used to bound it
+pub const MAX_TURN: f32 = std::f32::consts::PI / 16.0;
+
+// An object moved by transforms keyed in time, blended at the time of each
+// ray for motion blur. Translation and stretch are blended linearly and
+// rotation along the shortest arc. Outside the keys it holds still.
+pub struct Keyframed {
+    pub obj_ref: Arc<dyn Hittable>,
+    keys: Vec<(f32, Transform)>,
+    parts: Vec<Decomposed>,
+}
+
+impl Keyframed {
+    // Keys are (time, transform) pairs in any order
+    pub fn new(obj_ref: Arc<dyn Hittable>, mut keys: Vec<(f32, Transform)>) -> Self {
+        assert!(!keys.is_empty(), "keyframed motion needs at least one key");
+        keys.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let parts = keys.iter().map(|(_, t)| decompose(t)).collect();
+        Self {
+            obj_ref,
+            keys,
+            parts,
+        }
+    }
+    pub fn keys(&self) -> &[(f32, Transform)] {
+        &self.keys
+    }
+    pub fn at(&self, time: f32) -> Transform {
+        let (k0, k1, f) = key_span(&self.keys, time);
+        if k0 == k1 || f == 0.0 {
+            return self.keys[k0].1;
+        }
+        let nearest = if f < 0.5 { k0 } else { k1 };
+        blend(&self.parts[k0], &self.parts[k1], f).unwrap_or(self.keys[nearest].1)
+    }
+    pub fn halfway(&self) -> Transform {
+        let (first, last) = (self.keys[0].0, self.keys[self.keys.len() - 1].0);
+        self.at(0.5 * (first + last))
+    }
+    // Times from t0 to t1, including the keys between them, close enough
+    // together that the object turns by at most MAX_TURN from one to the
+    // next
+    pub fn sample_times(&self, t0: f32, t1: f32) -> Vec<f32> {
+        let mut times = vec![t0];
+        for k in 1..self.keys.len() {
+            let (start, end) = (self.keys[k - 1].0.max(t0), self.keys[k].0.min(t1));
+            if start >= end {
+                continue;
+            }
+            let angle = turn(self.parts[k - 1].rotate, self.parts[k].rotate);
+            let steps = (angle / MAX_TURN).ceil().max(1.0) as usize;
+            // Steps are even in the blend factor, which slerp keeps even in
+            // angle, so each is at most MAX_TURN wherever the span is cut
+            let (key0, key1) = (self.keys[k - 1].0, self.keys[k].0);
+            for i in 0..=steps {
+                let time = key0 + (key1 - key0) * i as f32 / steps as f32;
+                if time > start && time < end {
+                    times.push(time);
+                }
+            }
+            times.push(end);
+        }
+        times.push(t1);
+        times
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::FlatBvh;
+    use crate::material::Lambertian;
+    use crate::obj::{Sphere, XYRect, XZRect};
+    use crate::texture::ConstantTexture;
+    use crate::util::*;
+    use crate::vec3::Ray;
+
+    #[test]
+    fn transformed_rect_matches_placed_rect() {
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            Vec3::new(0.5, 0.5, 0.5),
+        ))));
+        // A unit square stretched to 2 by 3, stood up from z = 0 onto the
+        // y = 0 plane and lifted to y = 1
+        let transform = Transform::scale(Vec3::new(2.0, 3.0, 1.0))
+            .then(&Transform::rotate(Vec3::new(1.0, 0.0, 0.0), 90.0))
+            .then(&Transform::translate(Vec3::new(0.0, 1.0, 0.0)));
+        let unit = Arc::new(XYRect::new(0.0, 1.0, 0.0, 1.0, 0.0, mat.clone()));
+        let moved = Transformed::new(unit, transform);
+        let placed = XZRect::new(0.0, 2.0, 0.0, 3.0, 1.0, mat);
+
+        let bbox = moved.bounding_box(0.0, 1.0).unwrap();
+        // Rects pad their boxes by a little in the flat direction
+        assert!((bbox.min() - Vec3::new(0.0, 1.0, 0.0)).mag() < 1e-3);
+        assert!((bbox.max() - Vec3::new(2.0, 1.0, 3.0)).mag() < 1e-3);
+
+        let r = Ray::new(Vec3::new(1.5, 3.0, 2.5), Vec3::new(0.0, -2.0, 0.0), 0.0);
+        let hit = moved.hit(r, 0.001, f32::MAX).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-5);
+        assert!((hit.p - Vec3::new(1.5, 1.0, 2.5)).mag() < 1e-5);
+        assert!((hit.normal.y().abs() - 1.0).abs() < 1e-5);
+
+        // Same densities towards points sampled through the transform
+        seed_rng(5);
+        let o = Vec3::new(0.5, 4.0, -1.0);
+        for _ in 0..100 {
+            let v = moved.random(&o);
+            let p = o + v;
+            assert!((p.y() - 1.0).abs() < 1e-4 && (0.0..=2.0).contains(&p.x()));
+            let expected = placed.pdf_value(&o, &v);
+            assert!((moved.pdf_value(&o, &v) - expected).abs() < 1e-3 * expected);
+        }
+
+        let round_trip = transform.then(&transform.inverse());
+        assert!((round_trip.point(o) - o).mag() < 1e-5);
+        let m = Transform::from_matrix(transform.m).unwrap();
+        assert!((m.inverse().point(o) - transform.inverse().point(o)).mag() < 1e-5);
+        let eye = Transform::look_at(o, Vec3::new(0.5, 4.0, 1.0), Vec3::new(0.0, 1.0, 0.0));
+        assert!((eye.vector(Vec3::new(0.0, 0.0, 1.0)) - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-6);
+        assert!((eye.inverse().point(o)).mag() < 1e-6);
+    }
+
+    #[test]
+    fn instances_share_a_prototype() {
+        let grey: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            Vec3::new(0.5, 0.5, 0.5),
+        ))));
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            Vec3::new(0.8, 0.1, 0.1),
+        ))));
+        let prototype: Arc<dyn Hittable> =
+            Arc::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, grey.clone()));
+        let instances: Vec<Arc<dyn Hittable>> = (0..3)
+            .map(|i| {
+                let transform = Transform::scale(Vec3::new(0.5, 0.5, 0.5))
+                    .then(&Transform::translate(Vec3::new(i as f32 * 2.0, 0.0, 0.0)));
+                let material = if i == 1 { Some(red.clone()) } else { None };
+                Arc::new(Instance::new(prototype.clone(), transform, material)) as Arc<dyn Hittable>
+            })
+            .collect();
+        let tlas = FlatBvh::new(&instances, 0.0, 1.0);
+        assert_eq!(Arc::strong_count(&prototype), 4);
+
+        for i in 0..3 {
+            let x = i as f32 * 2.0;
+            let r = Ray::new(Vec3::new(x, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let hit = tlas.hit(r, 0.001, f32::MAX).unwrap();
+            assert!((hit.p - Vec3::new(x, 0.0, 0.5)).mag() < 1e-4);
+            assert!((hit.normal - Vec3::new(0.0, 0.0, 1.0)).mag() < 1e-4);
+            let expected = if i == 1 { &red } else { &grey };
+            assert!(Arc::ptr_eq(&hit.material, expected));
+        }
+        let between = Ray::new(Vec3::new(1.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(tlas.hit(between, 0.001, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn keyframed_motion_turns_and_sweeps() {
+        let mat: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            Vec3::new(0.5, 0.5, 0.5),
+        ))));
+        let ball = Arc::new(Sphere::new(Vec3::new(2.0, 0.0, 0.0), 0.5, mat));
+        let end = Transform::rotate(Vec3::new(0.0, 0.0, 1.0), 90.0)
+            .then(&Transform::translate(Vec3::new(4.0, 0.0, 0.0)));
+        let moving = Keyframed::new(ball, vec![(1.0, end), (0.0, Transform::default())]);
+
+        // Halfway the ball has turned 45 degrees and moved 2 along x, where
+        // blending the matrices would pull it in towards the axis
+        let center = |time: f32| moving.at(time).point(Vec3::new(2.0, 0.0, 0.0));
+        let half = 2.0f32.sqrt();
+        assert!((center(0.5) - Vec3::new(2.0 + half, half, 0.0)).mag() < 1e-4);
+        let down = Ray::new(
+            Vec3::new(2.0 + half, 10.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            0.5,
+        );
+        let hit = moving.hit(down, 0.001, f32::MAX).unwrap();
+        assert!((hit.p.y() - (half + 0.5)).abs() < 1e-4);
+        assert!(moving
+            .hit(
+                Ray::new(down.origin(), down.direction(), 0.0),
+                0.001,
+                f32::MAX
+            )
+            .is_none());
+
+        let bbox = moving.bounding_box(0.0, 1.0).unwrap();
+        for i in 0..=100 {
+            let c = center(i as f32 / 100.0);
+            for a in 0..3 {
+                assert!(bbox.min()[a] <= c[a] - 0.5 && c[a] + 0.5 <= bbox.max()[a]);
+            }
+        }
+
+        // Mirroring and stretching survive being split up and put back
+        let skew = Transform::from_matrix([
+            [-1.0, 0.5, 0.0, 1.0],
+            [0.0, 2.0, 0.3, -2.0],
+            [0.2, 0.0, 1.0, 0.5],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+        .unwrap();
+        let still = Keyframed::new(moving.obj_ref.clone(), vec![(0.0, skew), (1.0, skew)]);
+        let p = Vec3::new(0.3, -0.7, 1.1);
+        assert!((still.at(0.25).point(p) - skew.point(p)).mag() < 1e-4);
+    }
+}
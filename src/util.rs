@@ -1,16 +1,55 @@
+use std::cell::RefCell;
+
 use crate::vec3::Vec3;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+// Every thread draws from its own generator. The renderer reseeds it for
+// each pixel and switches streams for each sample, so an image comes out
+// the same no matter how the work was scheduled or split into passes.
+thread_local! {
+    static RNG: RefCell<ChaCha8Rng> = RefCell::new(ChaCha8Rng::seed_from_u64(0));
+}
+
+pub fn seed_rng(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = ChaCha8Rng::seed_from_u64(seed));
+}
+
+// Jumps to the start of one of the 2^64 independent streams of the seed
+pub fn set_rng_stream(stream: u64) {
+    RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        rng.set_stream(stream);
+        rng.set_word_pos(0);
+    });
+}
 
 #[inline]
-pub fn rand_float() -> f32 {
-    let mut rng = rand::thread_rng();
-    rng.gen()
+pub fn with_rng<T, F: FnOnce(&mut ChaCha8Rng) -> T>(f: F) -> T {
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
+}
+
+// The keys on either side of `time` in a list sorted by time and how far
+// along from the first to the second it is. Times outside the keys hold
+// the first or last one.
+pub fn key_span<T>(keys: &[(f32, T)], time: f32) -> (usize, usize, f32) {
+    let next = keys
+        .iter()
+        .position(|(t, _)| *t > time)
+        .unwrap_or(keys.len());
+    if next == 0 {
+        return (0, 0, 0.0);
+    }
+    if next == keys.len() {
+        return (next - 1, next - 1, 0.0);
+    }
+    let (t0, t1) = (keys[next - 1].0, keys[next].0);
+    (next - 1, next, (time - t0) / (t1 - t0))
 }
 
 #[inline]
-pub fn rand_float_range(min: f32, max: f32) -> f32 {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(min, max)
+pub fn rand_float() -> f32 {
+    with_rng(|rng| rng.gen())
 }
 
 #[inline]
@@ -196,6 +196,8 @@ pub struct Ray {
     pub a: Vec3,
     pub b: Vec3,
     pub time: f32,
+    // In nanometers, once the path has been narrowed down to one wavelength
+    pub wavelength: Option<f32>,
 }
 
 impl Ray {
@@ -216,14 +218,25 @@ impl Ray {
             a: a,
             b: b,
             time: t,
+            wavelength: None,
         }
     }
+    pub fn with_wavelength(self, wavelength: Option<f32>) -> Ray {
+        Ray { wavelength, ..self }
+    }
 }
 
+#[derive(Clone)]
 pub struct Onb {
     pub axis: Vec<Vec3>,
 }
 
+impl Default for Onb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Onb {
     pub fn new() -> Self {
         let uvw = vec![
@@ -259,6 +272,23 @@ impl Onb {
         self.axis[1] = cross(self.w(), a).unit();
         self.axis[0] = cross(self.w(), self.v());
     }
+    // With u as close to `u` as it can be while square to w, or any
+    // direction if `u` is along w
+    pub fn build_from_w_u(&mut self, n: &Vec3, u: &Vec3) {
+        let w = n.unit();
+        let across = *u - dot(*u, w) * w;
+        if across.mag_sqrd() < 1e-12 {
+            self.build_from_w(n);
+            return;
+        }
+        self.axis[2] = w;
+        self.axis[0] = across.unit();
+        self.axis[1] = cross(w, self.u());
+    }
+    // The coordinates of `a` along u, v and w
+    pub fn coordinates(&self, a: &Vec3) -> Vec3 {
+        Vec3::new(dot(*a, self.u()), dot(*a, self.v()), dot(*a, self.w()))
+    }
 }
 
 impl Index<u32> for Onb {